@@ -1,7 +1,10 @@
 use jsonrpc_proto::formatter::OutputFormat;
+use jsonrpc_proto::{Balancing, Limits};
 use structopt::StructOpt;
 use tracing_subscriber::prelude::*;
 
+// parsed once, the size of the variants does not matter
+#[allow(clippy::large_enum_variant)]
 #[derive(StructOpt, Debug, Clone)]
 pub enum Command {
     Add {
@@ -15,11 +18,23 @@ pub enum Command {
         url: String,
         #[structopt(long)]
         strip: bool,
+        #[structopt(long)]
+        host: Vec<String>,
+        /// Seconds to connect to the upstream
+        #[structopt(long, parse(try_from_str = parse_timeout))]
+        timeout_connect: Option<u64>,
+        /// Seconds the upstream may stall receiving the request
+        #[structopt(long, parse(try_from_str = parse_timeout))]
+        timeout_write: Option<u64>,
+        /// Seconds the upstream may stall sending the response
+        #[structopt(long, parse(try_from_str = parse_timeout))]
+        timeout_read: Option<u64>,
     },
     Get {
         #[structopt(short, long)]
         app: String,
     },
+    /// Changes the given settings of the application
     Update {
         #[structopt(short, long)]
         app: String,
@@ -31,6 +46,70 @@ pub enum Command {
         url: Option<String>,
         #[structopt(long)]
         strip: Option<bool>,
+        #[structopt(long)]
+        host: Vec<String>,
+        /// Seconds to connect to the upstream
+        #[structopt(long, parse(try_from_str = parse_timeout))]
+        timeout_connect: Option<u64>,
+        /// Seconds the upstream may stall receiving the request
+        #[structopt(long, parse(try_from_str = parse_timeout))]
+        timeout_write: Option<u64>,
+        /// Seconds the upstream may stall sending the response
+        #[structopt(long, parse(try_from_str = parse_timeout))]
+        timeout_read: Option<u64>,
+        #[structopt(long)]
+        traceparent: Option<bool>,
+        #[structopt(
+            long,
+            possible_values = &Balancing::variants(),
+            case_insensitive = true,
+        )]
+        balancing: Option<Balancing>,
+        #[structopt(long)]
+        max_batch: Option<usize>,
+        #[structopt(name = "allow-method", long)]
+        allow_method: Vec<String>,
+        #[structopt(name = "deny-method", long)]
+        deny_method: Vec<String>,
+        /// Compute units of the method as `method=units`, `-method` removes it
+        #[structopt(long)]
+        cost: Vec<String>,
+        /// Upstream WebSocket URL, empty value removes it
+        #[structopt(long)]
+        ws_url: Option<String>,
+        /// Pin `latest` block of the calls to the head agreed by the nodes
+        #[structopt(long)]
+        pin_head: Option<bool>,
+        #[structopt(flatten)]
+        limits: Limits,
+    },
+    UpstreamAdd {
+        #[structopt(short, long)]
+        app: String,
+        #[structopt(short, long)]
+        url: String,
+        #[structopt(long, default_value = "1")]
+        weight: u32,
+        /// Nodes with lower priority value are used first
+        #[structopt(long, default_value = "0")]
+        priority: u32,
+    },
+    UpstreamRemove {
+        #[structopt(short, long)]
+        app: String,
+        #[structopt(short, long)]
+        url: String,
+    },
+    UpstreamWeight {
+        #[structopt(short, long)]
+        app: String,
+        #[structopt(short, long)]
+        url: String,
+        #[structopt(long)]
+        weight: Option<u32>,
+        /// Nodes with lower priority value are used first
+        #[structopt(long)]
+        priority: Option<u32>,
     },
     List,
 }
@@ -61,6 +140,15 @@ pub struct Args {
     pub cmd: Command,
 }
 
+// zero would fail every upstream call at once
+fn parse_timeout(s: &str) -> Result<u64, String> {
+    match s.parse::<u64>() {
+        Ok(0) => Err("timeout must be at least 1 second".to_owned()),
+        Ok(x) => Ok(x),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
 pub fn parse() -> anyhow::Result<Args> {
     dotenv::dotenv().ok();
     let log_level: String = std::env::var("LOG_LEVEL").unwrap_or("info".to_owned());
@@ -82,3 +170,16 @@ pub fn parse() -> anyhow::Result<Args> {
     tracing::debug!("{:?}", res);
     Ok(res)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timeout_must_be_positive() {
+        assert_eq!(parse_timeout("5"), Ok(5));
+        assert!(parse_timeout("0").is_err());
+        assert!(parse_timeout("-1").is_err());
+        assert!(parse_timeout("soon").is_err());
+    }
+}
@@ -1,7 +1,7 @@
 pub mod args;
 use jsonrpc_proto::formatter::Formatter;
 use jsonrpc_proto::redis::{AppStorage, RedisConnection};
-use jsonrpc_proto::Application;
+use jsonrpc_proto::{Application, Upstream};
 
 fn main() -> anyhow::Result<()> {
     let args = match args::parse() {
@@ -29,25 +29,33 @@ fn main() -> anyhow::Result<()> {
             path,
             url,
             strip,
+            host,
+            timeout_connect,
+            timeout_write,
+            timeout_read,
         } => {
-            let doc = Application::new(&name, slug, path, url, strip);
+            let mut doc = Application::new(&name, slug, path, url, strip, host);
+            let timeouts = &mut doc.proxy.timeouts;
+            timeouts.connect = timeout_connect.unwrap_or(timeouts.connect);
+            timeouts.write = timeout_write.unwrap_or(timeouts.write);
+            timeouts.read = timeout_read.unwrap_or(timeouts.read);
             let key = doc.slug.to_owned();
-            match storage.get(&key) {
-                Some(_) => return fmt.fail("application already exists"),
+            match storage.get(&key)? {
+                Some(_) => fmt.fail("application already exists"),
                 None => {
                     if let Err(e) = storage.set(&key, &doc) {
                         return fmt.wrap_error(e);
                     }
-                    let updated = storage.get(&key).map(|x| x).unwrap();
+                    let updated = storage.get(&key)?.unwrap();
                     fmt.out(&updated)
                 }
             }
         }
         args::Command::Get { app } => {
             let key = app.clone();
-            match storage.get(&key) {
+            match storage.get(&key)? {
                 Some(doc) => fmt.out(&doc),
-                None => return fmt.fail("application not found"),
+                None => fmt.fail("application not found"),
             }
         }
         args::Command::Update {
@@ -56,9 +64,22 @@ fn main() -> anyhow::Result<()> {
             path,
             url,
             strip,
+            host,
+            timeout_connect,
+            timeout_write,
+            timeout_read,
+            traceparent,
+            balancing,
+            max_batch,
+            allow_method,
+            deny_method,
+            cost,
+            ws_url,
+            pin_head,
+            limits,
         } => {
             let key = app.clone();
-            match storage.get(&key) {
+            match storage.get(&key)? {
                 Some(orig) => {
                     let mut doc = orig.clone();
                     if let Some(active) = active {
@@ -68,20 +89,144 @@ fn main() -> anyhow::Result<()> {
                         doc.proxy.path = path.to_owned();
                     }
                     if let Some(url) = url {
-                        doc.proxy.path = url.to_owned();
+                        for u in doc.upstreams.iter_mut() {
+                            if u.url == orig.proxy.url {
+                                u.url = url.clone();
+                            }
+                        }
+                        doc.proxy.url = url.to_owned();
                     }
                     if let Some(strip) = strip {
                         doc.proxy.strip = strip
                     }
+                    if let Some(timeout_connect) = timeout_connect {
+                        doc.proxy.timeouts.connect = timeout_connect
+                    }
+                    if let Some(timeout_write) = timeout_write {
+                        doc.proxy.timeouts.write = timeout_write
+                    }
+                    if let Some(timeout_read) = timeout_read {
+                        doc.proxy.timeouts.read = timeout_read
+                    }
+                    if let Some(traceparent) = traceparent {
+                        doc.proxy.traceparent = traceparent
+                    }
+                    if let Some(balancing) = balancing {
+                        doc.balancing = balancing
+                    }
+                    if let Some(max_batch) = max_batch {
+                        doc.max_batch = Some(max_batch)
+                    }
+                    if let Some(ws_url) = ws_url {
+                        doc.ws_url = Some(ws_url).filter(|x| !x.is_empty())
+                    }
+                    if let Some(pin_head) = pin_head {
+                        doc.pin_head = pin_head
+                    }
+                    doc.limits.update(limits);
+                    doc.methods.update(allow_method, deny_method);
+                    for c in cost {
+                        if let Some(excluded) = c.strip_prefix('-') {
+                            doc.costs.remove(excluded);
+                            continue;
+                        }
+                        let units = c.split_once('=').and_then(|(method, units)| {
+                            units.parse::<u64>().ok().map(|x| (method.to_owned(), x))
+                        });
+                        match units {
+                            Some((method, units)) => doc.costs.insert(method, units),
+                            None => return fmt.fail("cost should be method=units"),
+                        };
+                    }
+                    for h in host {
+                        match h.get(..1) {
+                            Some("-") => {
+                                let excluded: String = h.chars().skip(1).collect();
+                                doc.proxy.hosts.retain(|x| *x != excluded);
+                            }
+                            _ => doc.proxy.hosts.push(h),
+                        };
+                    }
                     if let Err(e) = storage.set(&key, &doc) {
                         return fmt.wrap_error(e);
                     }
-                    let updated = storage.get(&key).map(|x| x).unwrap();
+                    let updated = storage.get(&key)?.unwrap();
                     fmt.out(&updated)
                 }
+                None => fmt.fail("application not found"),
+            }
+        }
+        args::Command::UpstreamAdd {
+            app,
+            url,
+            weight,
+            priority,
+        } => {
+            let mut doc = match storage.get(&app)? {
+                Some(x) => x,
+                None => return fmt.fail("application not found"),
+            };
+            // the list replaces proxy url, so it has to be kept as the first upstream
+            doc.upstreams = doc.upstreams();
+            if doc.upstreams.iter().any(|x| x.url == url) {
+                return fmt.fail("upstream already exists");
+            }
+            doc.upstreams.push(Upstream {
+                url,
+                weight,
+                priority,
+            });
+            if let Err(e) = storage.set(&app, &doc) {
+                return fmt.wrap_error(e);
+            }
+            fmt.out(&storage.get(&app)?.unwrap())
+        }
+        args::Command::UpstreamRemove { app, url } => {
+            let mut doc = match storage.get(&app)? {
+                Some(x) => x,
                 None => return fmt.fail("application not found"),
+            };
+            let mut upstreams = doc.upstreams();
+            if !upstreams.iter().any(|x| x.url == url) {
+                return fmt.fail("upstream not found");
+            }
+            upstreams.retain(|x| x.url != url);
+            if upstreams.is_empty() {
+                return fmt.fail("cannot remove the last upstream");
+            }
+            doc.proxy.url = upstreams[0].url.clone();
+            doc.upstreams = upstreams;
+            if let Err(e) = storage.set(&app, &doc) {
+                return fmt.wrap_error(e);
+            }
+            fmt.out(&storage.get(&app)?.unwrap())
+        }
+        args::Command::UpstreamWeight {
+            app,
+            url,
+            weight,
+            priority,
+        } => {
+            let mut doc = match storage.get(&app)? {
+                Some(x) => x,
+                None => return fmt.fail("application not found"),
+            };
+            doc.upstreams = doc.upstreams();
+            let upstream = match doc.upstreams.iter_mut().find(|x| x.url == url) {
+                Some(x) => x,
+                None => return fmt.fail("upstream not found"),
+            };
+            if let Some(weight) = weight {
+                upstream.weight = weight
+            }
+            if let Some(priority) = priority {
+                upstream.priority = priority
+            }
+            if let Err(e) = storage.set(&app, &doc) {
+                return fmt.wrap_error(e);
             }
+            fmt.out(&storage.get(&app)?.unwrap())
         }
-        args::Command::List => fmt.out(&storage.scan()),
+        args::Command::List => fmt.out(&storage.scan()?),
     }
 }
@@ -0,0 +1,26 @@
+use crate::health::Health;
+use crate::metrics::Metrics;
+use std::sync::Arc;
+use tide::{Body, Request, Response, Result, StatusCode};
+
+/// State of the internal admin server, not exposed to the clients
+#[derive(Clone)]
+pub struct AdminState {
+    pub health: Arc<Health>,
+    pub metrics: Arc<Metrics>,
+}
+
+// "/health"
+pub async fn health(req: Request<AdminState>) -> Result {
+    let mut res = Response::new(StatusCode::Ok);
+    res.set_body(Body::from_json(&req.state().health.snapshot())?);
+    Ok(res)
+}
+
+// "/metrics"
+pub async fn metrics(req: Request<AdminState>) -> Result {
+    let mut res = Response::new(StatusCode::Ok);
+    res.set_body(req.state().metrics.render());
+    res.set_content_type("text/plain; version=0.0.4");
+    Ok(res)
+}
@@ -1,44 +1,897 @@
+use crate::balancer::is_idempotent;
+use crate::batch::{self, Item};
+use crate::cache::Lookup;
+use crate::coalesce::{Outcome, Role};
+use crate::filters;
+use crate::head;
+use crate::limits::{self, BodyError};
+use crate::metrics::{self, RequestLabels};
+use crate::otlp::{self, Span, SpanContext};
+use crate::redact;
+use crate::router::Route;
+use crate::upstream::ConnectError;
+use crate::usage;
 use crate::State;
-use std::time::Duration;
-use tide::{Error, Request, Response, Result};
-use tracing::info;
-use ureq::{Agent, AgentBuilder};
+use async_std::io::BufReader;
+use jsonrpc_proto::jsonrpc::{self, ErrorObject, Id, Payload};
+use jsonrpc_proto::redis::QuotaUsage;
+use jsonrpc_proto::{Application, RpcKey, UsageRecord};
+use serde_json::Value;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tide::{Body, Request, Response, Result, StatusCode};
+use tracing::{debug, error, info, warn};
 
-// "/rpc/{key}"
-pub async fn proxy_rpc(mut req: Request<State>) -> Result {
-    let path = req.url().path().chars().skip(1).collect::<String>();
-    let used_key = match req.header("X-Key") {
-        Some(x) => x.to_string(),
-        None => path,
+// "/{prefix}/{key}"
+pub async fn proxy_rpc(req: Request<State>) -> Result {
+    let metrics = req.state().metrics.clone();
+    let _in_flight = metrics.in_flight();
+    let started = Instant::now();
+    let parent = req
+        .header("traceparent")
+        .and_then(|x| SpanContext::from_traceparent(x.as_str()));
+    let mut span = req
+        .state()
+        .tracer
+        .start(parent.as_ref(), "jsonrpc.request", otlp::KIND_SERVER);
+    span.set_str("http.method", req.method().as_ref());
+    span.set_str("http.target", &redact::path(req.url().path()));
+    let mut labels = RequestLabels::default();
+    let res = serve(req, &mut labels, &span).await;
+    let status = match &res {
+        Ok(x) => x.status(),
+        Err(e) => e.status(),
+    };
+    metrics.observe(&labels, status, started.elapsed());
+    span.set_str("jsonrpc.app", &labels.app);
+    span.set_str("rpc.method", &labels.method);
+    span.set_int("http.status_code", status as i64);
+    if status.is_server_error() {
+        span.set_error(status.canonical_reason());
+    }
+    res
+}
+
+async fn serve(mut req: Request<State>, labels: &mut RequestLabels, span: &Span) -> Result {
+    let body = match req.body_string().await {
+        Ok(x) => x,
+        Err(e) => {
+            info!("payload read error: {}", e);
+            let error = ErrorObject::new(jsonrpc::PARSE_ERROR, "payload expected");
+            return reply(
+                StatusCode::BadRequest,
+                &jsonrpc::Response::error(Id::Null, error),
+            );
+        }
+    };
+    let payload = match Payload::parse(&body) {
+        Ok(x) => x,
+        Err(e) => {
+            info!("invalid payload: {}", e.error.message);
+            return reply(StatusCode::BadRequest, &jsonrpc::Response::from(e));
+        }
+    };
+    labels.method = match &payload {
+        Payload::Single(x) => metrics::method_label(None, &x.method).to_owned(),
+        Payload::Batch(_) => "batch".to_owned(),
     };
 
-    let body = req.body_string().await.expect("payload expected");
+    let Caller {
+        route,
+        path,
+        key_hash,
+        rpc_key,
+    } = match authorize(&req, labels, span).await {
+        Ok(x) => x,
+        Err(denied) => {
+            let error = denied.error;
+            return error_response(denied.status, &payload, error.code, &error.message);
+        }
+    };
+    let app = &route.app;
+    if let Payload::Single(x) = &payload {
+        labels.method = metrics::method_label(Some(app), &x.method).to_owned();
+    }
     let state = req.state();
-    let mut guard = state.rpckeys.lock().expect("mutex lock error");
-    let rpc_key = match guard.get(&state.default_app.name, &used_key) {
-        Some(x) => x,
-        None => {
-            info!("request key = {}", used_key);
-            return Err(Error::from_str(403, "access denied"));
-        },
+    let key = redact::key_hash(&key_hash);
+    let max_batch = app.max_batch.unwrap_or(state.max_batch);
+    if payload.size() > max_batch {
+        info!(
+            "key = {} batch of {} calls exceeds {}",
+            key,
+            payload.size(),
+            max_batch
+        );
+        let error = ErrorObject::new(
+            jsonrpc::LIMIT_EXCEEDED,
+            &format!("batch size exceeds {}", max_batch),
+        );
+        let mut res = reply(
+            StatusCode::PayloadTooLarge,
+            &jsonrpc::Response::error(Id::Null, error),
+        )?;
+        peek_rate_limit(state, app, &key_hash, &rpc_key, &mut res).await;
+        return Ok(res);
+    }
+    let mut records = vec![];
+    let items = deny_methods(state, app, &rpc_key, &payload, &mut records);
+    let items = check_limits(state, app, &rpc_key, items, &mut records);
+    let permitted: Vec<&jsonrpc::Request> = items
+        .iter()
+        .filter_map(|x| match x {
+            Item::Forward(req) => Some(req),
+            _ => None,
+        })
+        .collect();
+    let record = |records| usage::spawn_record(state.usage.clone(), app, key_hash.clone(), records);
+    if permitted.is_empty() {
+        record(records);
+        let mut res = if payload.is_batch() {
+            reply_batch(batch::merge(items, vec![]))?
+        } else {
+            // the only call of the payload was rejected
+            match items.into_iter().next() {
+                Some(Item::Rejected(Some(x))) => reply(rejected_status(&x), &x)?,
+                _ => Response::new(StatusCode::NoContent),
+            }
+        };
+        peek_rate_limit(state, app, &key_hash, &rpc_key, &mut res).await;
+        return Ok(res);
+    }
+    // calls that did not reach the upstream are all counted as failed
+    let failed = |mut records: Vec<UsageRecord>, calls: &[&jsonrpc::Request]| {
+        for req in calls {
+            records.push(UsageRecord::call(&req.method, true, 0, 0));
+        }
+        record(records)
     };
+
+    // compute units of every permitted call of the batch are counted against the quota,
+    // whether it is answered from the cache or not
+    let cost: u64 = permitted.iter().map(|r| app.method_cost(&r.method)).sum();
+    let quota = match state
+        .quotas
+        .consume(&app.slug, &key_hash, &rpc_key.quotas(), cost)
+        .await
+    {
+        Ok(x) => x,
+        Err(e) => {
+            error!("quota storage error: {}", e);
+            return error_response(
+                StatusCode::InternalServerError,
+                &payload,
+                jsonrpc::INTERNAL_ERROR,
+                "internal error",
+            );
+        }
+    };
+    if let Some(exhausted) = quota.as_ref().filter(|x| x.exhausted) {
+        let window = exhausted.window.name();
+        info!("key = {} quota exceeded per {}", key, window);
+        state.metrics.quota_rejection(&app.slug, window);
+        failed(records, &permitted);
+        // the payload never fits the window, waiting for its reset does not help
+        if cost > exhausted.limit {
+            let mut res = error_response(
+                StatusCode::PayloadTooLarge,
+                &payload,
+                jsonrpc::LIMIT_EXCEEDED,
+                &quota_overflow(cost, exhausted),
+            )?;
+            set_rate_limit(&mut res, exhausted);
+            return Ok(res);
+        }
+        let mut res = error_response(
+            StatusCode::TooManyRequests,
+            &payload,
+            jsonrpc::LIMIT_EXCEEDED,
+            &format!("quota exceeded per {}", window),
+        )?;
+        set_rate_limit(&mut res, exhausted);
+        res.insert_header("Retry-After", exhausted.reset.to_string());
+        return Ok(res);
+    }
+
+    let calls = permitted.len();
+    let head = state.health.best_height(&app.slug);
+    let mut cacheable = false;
+    let mut hits = 0;
+    let (items, rewritten) = pin_head(state, app, items, &mut records);
+    let mut looked_up = Vec::with_capacity(items.len());
+    for item in items {
+        let req = match item {
+            Item::Forward(req) => req,
+            x => {
+                looked_up.push(x);
+                continue;
+            }
+        };
+        match state.cache.lookup(&app.slug, &path, &req, head).await {
+            Lookup::Hit(result) => {
+                state.metrics.cache_lookup(app, &req.method, true);
+                records.push(UsageRecord::call(&req.method, false, 0, 0));
+                hits += 1;
+                looked_up.push(Item::Answered(jsonrpc::Response::result(req.id(), result)));
+            }
+            Lookup::Miss => {
+                state.metrics.cache_lookup(app, &req.method, false);
+                cacheable = true;
+                looked_up.push(Item::Forward(req));
+            }
+            Lookup::Bypass => looked_up.push(Item::Forward(req)),
+        }
+    }
+    let (items, pinned) = pin_filters(state, app, looked_up, &mut records).await;
+    let forwarded: Vec<&jsonrpc::Request> = items
+        .iter()
+        .filter_map(|x| match x {
+            Item::Forward(req) => Some(req),
+            _ => None,
+        })
+        .collect();
     info!(
-        "used_key = {} details = {:?} proxy = {:?} payload = {}",
-        used_key, rpc_key, state.default_app.proxy, body
+        "key = {} app = {} calls = {} cached = {} cost = {}",
+        key, app.slug, calls, hits, cost
     );
+    // payloads may carry private data, so they are logged in full only for debugging
+    if rpc_key.tags.iter().any(|t| t == redact::DEBUG_TAG) {
+        info!("key = {} payload = {}", key, body);
+    } else {
+        debug!("key = {} payload = {}", key, body);
+    }
 
-    let rpc_url = state.default_app.proxy.url.clone();
-    let agent: Agent = AgentBuilder::new()
-        .timeout_read(Duration::from_secs(30))
-        .timeout_write(Duration::from_secs(5))
-        .build();
-    let resp = agent
-        .post(&rpc_url)
-        .set("Content-Type", "application/json")
-        .send_string(&body)?;
-
-    let mut res = Response::new(resp.status());
-    let body = resp.into_string().unwrap();
-    res.set_body(body);
+    // identical single calls of the key in flight share one upstream call
+    let max_response = rpc_key.limits(app).max_response_size;
+    let mut flight = None;
+    let mut shared = None;
+    if let (Payload::Single(_), [req]) = (&payload, forwarded.as_slice()) {
+        if req.id.is_some() && is_idempotent(&req.method) && pinned.is_none() {
+            match state.coalescer.join(&app.slug, &path, &key_hash, req) {
+                Role::Leader(x) => flight = Some(x),
+                Role::Waiter(rx) => shared = rx.recv().await.ok(),
+            }
+        }
+    }
+
+    let mut res = if forwarded.is_empty() {
+        record(records);
+        if payload.is_batch() {
+            reply_batch(batch::merge(items, vec![]))?
+        } else {
+            match items.into_iter().next() {
+                Some(Item::Answered(x)) => reply(StatusCode::Ok, &x)?,
+                Some(Item::Rejected(Some(x))) => reply(rejected_status(&x), &x)?,
+                _ => Response::new(StatusCode::NoContent),
+            }
+        }
+    } else if let Some(outcome) = shared {
+        debug!("key = {} coalesced {}", key, forwarded[0].method);
+        state.metrics.coalesced(app, &forwarded[0].method);
+        if let Some(url) = outcome.upstream {
+            labels.upstream = url;
+        }
+        let response = jsonrpc::Response {
+            id: payload.id(),
+            ..outcome.response
+        };
+        records.extend(usage::batch_records(
+            &forwarded,
+            std::slice::from_ref(&response),
+        ));
+        record(records);
+        reply(outcome.status, &response)?
+    } else {
+        let upstream_body = if forwarded.len() < payload.size() || rewritten {
+            serde_json::to_string(&forwarded)?
+        } else {
+            body
+        };
+        let upstream = forward(
+            state,
+            &route,
+            &path,
+            &upstream_body,
+            &forwarded,
+            pinned.as_deref(),
+            span,
+        )
+        .await;
+        if let Some((url, _)) = &upstream {
+            labels.upstream = url.clone();
+        }
+        let tracked = forwarded
+            .iter()
+            .any(|r| filters::changes_filters(&r.method));
+        match upstream.map(|(_, x)| x) {
+            None if pinned.is_some() => {
+                failed(records, &forwarded);
+                let node = pinned.unwrap_or_default();
+                info!("key = {} filter node {} is not available", key, node);
+                state.filters.forget(&app.slug, &forwarded).await;
+                error_response(
+                    StatusCode::BadGateway,
+                    &payload,
+                    jsonrpc::FILTER_NOT_FOUND,
+                    "filter not found: its upstream node is not available",
+                )?
+            }
+            None => {
+                failed(records, &forwarded);
+                let error =
+                    ErrorObject::new(jsonrpc::UPSTREAM_UNAVAILABLE, "upstream is not available");
+                if let Some(flight) = flight {
+                    flight.finish(Outcome {
+                        upstream: None,
+                        status: StatusCode::BadGateway,
+                        response: jsonrpc::Response::error(payload.id(), error.clone()),
+                    });
+                }
+                error_response(StatusCode::BadGateway, &payload, error.code, &error.message)?
+            }
+            Some(mut upstream)
+                if payload.is_batch()
+                    || cacheable
+                    || tracked
+                    || flight.is_some()
+                    || max_response.is_some() =>
+            {
+                // upstream answers only the forwarded calls, the rest is merged in
+                let (status, responses) = match limits::read_body(&mut upstream, max_response).await
+                {
+                    Ok(body) => (upstream.status(), batch::parse_upstream(&body, &forwarded)),
+                    Err(BodyError::Failed(e)) => {
+                        warn!(
+                            "key = {} response of {} is broken: {}",
+                            key, labels.upstream, e
+                        );
+                        (
+                            StatusCode::BadGateway,
+                            batch::reject_calls(&forwarded, &response_failed()),
+                        )
+                    }
+                    Err(BodyError::Exceeded) => {
+                        let max = max_response.unwrap_or_default();
+                        info!("key = {} response exceeds {} bytes", key, max);
+                        let error = response_exceeded(max);
+                        (
+                            StatusCode::PayloadTooLarge,
+                            batch::reject_calls(&forwarded, &error),
+                        )
+                    }
+                };
+                if status.is_success() {
+                    for res in &responses {
+                        let req = forwarded.iter().find(|r| r.id.as_ref() == Some(&res.id));
+                        if let (Some(req), Some(result)) = (req, &res.result) {
+                            state.cache.store(&app.slug, &path, req, result, head);
+                        }
+                    }
+                    if tracked {
+                        let upstream = &labels.upstream;
+                        state
+                            .filters
+                            .track(&app.slug, upstream, &forwarded, &responses)
+                            .await;
+                    }
+                }
+                if let (Some(flight), Some(response)) = (flight, responses.first()) {
+                    flight.finish(Outcome {
+                        upstream: Some(labels.upstream.clone()),
+                        status,
+                        response: response.clone(),
+                    });
+                }
+                records.extend(usage::batch_records(&forwarded, &responses));
+                record(records);
+                let merged = batch::merge(items, responses);
+                if payload.is_batch() {
+                    reply_batch(merged)?
+                } else {
+                    match merged.into_iter().next() {
+                        Some(x) => reply(status, &x)?,
+                        None => Response::new(StatusCode::NoContent),
+                    }
+                }
+            }
+            Some(mut upstream) => {
+                let mut res = Response::new(upstream.status());
+                if let Some(ct) = upstream.content_type() {
+                    res.set_content_type(ct);
+                }
+                let body = upstream.take_body();
+                let len = body.len();
+                let failed_status = !upstream.status().is_success();
+                let (storage, slug, key) =
+                    (state.usage.clone(), app.slug.clone(), key_hash.clone());
+                let method = app.method_label(&forwarded[0].method).to_owned();
+                let bytes_in = upstream_body.len() as u64;
+                let metered = usage::Metered::new(body, move |bytes_out, error| {
+                    let record =
+                        UsageRecord::call(&method, failed_status || error, bytes_in, bytes_out);
+                    usage::spawn_save(storage, slug, key, vec![record]);
+                });
+                res.set_body(Body::from_reader(BufReader::new(metered), len));
+                res
+            }
+        }
+    };
+    if let Some(quota) = &quota {
+        set_rate_limit(&mut res, quota);
+    }
     Ok(res)
 }
+
+/// Caller authorized by the key
+#[derive(Clone)]
+pub struct Caller {
+    pub route: Route,
+    // rest of the request path after the key
+    pub path: String,
+    pub key_hash: String,
+    pub rpc_key: RpcKey,
+}
+
+/// Reason to deny the caller
+pub struct Denied {
+    pub status: StatusCode,
+    pub error: ErrorObject,
+}
+
+impl Denied {
+    fn new(status: StatusCode, code: i64, message: &str) -> Self {
+        Self {
+            status,
+            error: ErrorObject::new(code, message),
+        }
+    }
+}
+
+/// Resolves the application of the request and checks the key
+pub async fn authorize(
+    req: &Request<State>,
+    labels: &mut RequestLabels,
+    span: &Span,
+) -> std::result::Result<Caller, Denied> {
+    let host = req.header("Host").map(|x| x.to_string());
+    let route = req
+        .state()
+        .router
+        .read()
+        .expect("lock error")
+        .resolve(host.as_deref(), req.url().path());
+    let route = match route {
+        Some(x) => x,
+        None => {
+            info!(
+                "no application for host = {:?} path = {}",
+                host,
+                redact::path(req.url().path())
+            );
+            return Err(Denied::new(
+                StatusCode::NotFound,
+                jsonrpc::APP_NOT_FOUND,
+                "application not found",
+            ));
+        }
+    };
+    let app = &route.app;
+    labels.app = app.slug.clone();
+    let (used_key, path) = match req.header("X-Key") {
+        Some(x) => (x.to_string(), route.rest.clone()),
+        None => {
+            let rest = route.rest.trim_start_matches('/');
+            match rest.find('/') {
+                Some(i) => (rest[..i].to_owned(), rest[i..].to_owned()),
+                None => (rest.to_owned(), String::new()),
+            }
+        }
+    };
+
+    let state = req.state();
+    let key_hash = state.hasher.digest(&used_key);
+    // the key itself never gets into the logs
+    let key = redact::key_hash(&key_hash);
+    let mut lookup = span.child("jsonrpc.key_lookup", otlp::KIND_INTERNAL);
+    lookup.set_str("jsonrpc.app", &app.slug);
+    lookup.set_str("jsonrpc.key_hash", key);
+    let rpc_key = match state.rpckeys.get(&app.slug, &key_hash).await {
+        Ok(x) => x,
+        Err(e) => {
+            error!("key storage error: {}", e);
+            lookup.set_error(&e.to_string());
+            return Err(Denied::new(
+                StatusCode::ServiceUnavailable,
+                jsonrpc::INTERNAL_ERROR,
+                "key storage is not available",
+            ));
+        }
+    };
+    lookup.set_str("jsonrpc.key_found", &rpc_key.is_some().to_string());
+    drop(lookup);
+    let rpc_key = check_key(state, &app.slug, &key_hash, rpc_key)?;
+    Ok(Caller {
+        route,
+        path,
+        key_hash,
+        rpc_key,
+    })
+}
+
+/// Checks that the key found in the storage may call the application now,
+/// denials are logged and counted
+pub fn check_key(
+    state: &State,
+    app: &str,
+    key_hash: &str,
+    rpc_key: Option<RpcKey>,
+) -> std::result::Result<RpcKey, Denied> {
+    let key = redact::key_hash(key_hash);
+    let rpc_key = match rpc_key {
+        Some(x) => x,
+        None => {
+            info!("key = {} denied: key not found", key);
+            state.metrics.auth_failure(app, "not_found");
+            return Err(Denied::new(
+                StatusCode::Forbidden,
+                jsonrpc::ACCESS_DENIED,
+                "access denied",
+            ));
+        }
+    };
+    if !rpc_key.active {
+        info!("key = {} denied: key is not active", key);
+        state.metrics.auth_failure(app, "inactive");
+        return Err(Denied::new(
+            StatusCode::Forbidden,
+            jsonrpc::KEY_INACTIVE,
+            "key is not active",
+        ));
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs();
+    if rpc_key.expires <= now {
+        info!("key = {} denied: key expired at {}", key, rpc_key.expires);
+        state.metrics.auth_failure(app, "expired");
+        return Err(Denied::new(
+            StatusCode::Forbidden,
+            jsonrpc::KEY_EXPIRED,
+            "key expired",
+        ));
+    }
+    if rpc_key.app != app {
+        info!(
+            "key = {} denied: key belongs to app {}, not {}",
+            key, rpc_key.app, app
+        );
+        state.metrics.auth_failure(app, "wrong_app");
+        return Err(Denied::new(
+            StatusCode::Forbidden,
+            jsonrpc::KEY_WRONG_APP,
+            "key is not valid for this application",
+        ));
+    }
+    Ok(rpc_key)
+}
+
+/// Splits the payload into calls, rejecting the methods that are not allowed for the key
+pub fn deny_methods(
+    state: &State,
+    app: &Application,
+    rpc_key: &RpcKey,
+    payload: &Payload,
+    records: &mut Vec<UsageRecord>,
+) -> Vec<Item> {
+    let key = redact::key_hash(&rpc_key.key_hash);
+    let policy = rpc_key.method_policy(app);
+    batch::items(payload)
+        .into_iter()
+        .map(|item| match item {
+            Item::Forward(req) if !policy.permits(&req.method) => {
+                info!("key = {} denied: method {} is not allowed", key, req.method);
+                records.push(UsageRecord::call(&req.method, true, 0, 0));
+                state.metrics.auth_failure(&app.slug, "method_denied");
+                let error = ErrorObject::new(
+                    jsonrpc::METHOD_NOT_ALLOWED,
+                    &format!("method {} is not allowed", req.method),
+                );
+                Item::reject(&req, error)
+            }
+            x => x,
+        })
+        .collect()
+}
+
+/// Calls of the application pinning the head see the block reached by every healthy node,
+/// `eth_blockNumber` is answered with it. Returns whether any call was rewritten
+pub fn pin_head(
+    state: &State,
+    app: &Application,
+    items: Vec<Item>,
+    records: &mut Vec<UsageRecord>,
+) -> (Vec<Item>, bool) {
+    if !app.pin_head {
+        return (items, false);
+    }
+    let head = match state.health.agreed_height(&app.slug) {
+        Some(x) => x,
+        None => return (items, false),
+    };
+    let mut rewritten = false;
+    let items = items
+        .into_iter()
+        .map(|item| match item {
+            Item::Forward(req) if req.method == "eth_blockNumber" && req.id.is_some() => {
+                records.push(UsageRecord::call(&req.method, false, 0, 0));
+                let result = Value::String(format!("0x{:x}", head));
+                Item::Answered(jsonrpc::Response::result(req.id(), result))
+            }
+            Item::Forward(mut req) => {
+                rewritten |= head::pin(&mut req, head);
+                Item::Forward(req)
+            }
+            x => x,
+        })
+        .collect();
+    (items, rewritten)
+}
+
+/// Calls of the filter go to the node that created it, returns the node for the payload.
+/// Unknown filters and filters of different nodes in one batch are rejected
+pub async fn pin_filters(
+    state: &State,
+    app: &Application,
+    items: Vec<Item>,
+    records: &mut Vec<UsageRecord>,
+) -> (Vec<Item>, Option<String>) {
+    let mut pinned: Option<String> = None;
+    let mut pinned_items = Vec::with_capacity(items.len());
+    for item in items {
+        let (req, id) = match &item {
+            Item::Forward(req) => match filters::filter_id(req) {
+                Some(id) => (req, id),
+                None => {
+                    pinned_items.push(item);
+                    continue;
+                }
+            },
+            _ => {
+                pinned_items.push(item);
+                continue;
+            }
+        };
+        let error = match state.filters.get(&app.slug, id).await {
+            Ok(Some(node)) => match &pinned {
+                Some(x) if *x != node => ErrorObject::new(
+                    jsonrpc::INVALID_REQUEST,
+                    "filters of different upstream nodes can not be batched",
+                ),
+                _ => {
+                    pinned = Some(node);
+                    pinned_items.push(item);
+                    continue;
+                }
+            },
+            // any node would answer that the filter is not found
+            Ok(None) => ErrorObject::new(jsonrpc::FILTER_NOT_FOUND, "filter not found"),
+            Err(e) => {
+                error!("filter storage error: {}", e);
+                ErrorObject::new(jsonrpc::INTERNAL_ERROR, "filter storage is not available")
+            }
+        };
+        records.push(UsageRecord::call(&req.method, true, 0, 0));
+        let rejected = Item::reject(req, error);
+        pinned_items.push(rejected);
+    }
+    (pinned_items, pinned)
+}
+
+/// Rejects the calls exceeding the limits of the key, such as the block range of `eth_getLogs`
+pub fn check_limits(
+    state: &State,
+    app: &Application,
+    rpc_key: &RpcKey,
+    items: Vec<Item>,
+    records: &mut Vec<UsageRecord>,
+) -> Vec<Item> {
+    let key = redact::key_hash(&rpc_key.key_hash);
+    let limits = rpc_key.limits(app);
+    let head = state.health.best_height(&app.slug);
+    items
+        .into_iter()
+        .map(|item| match item {
+            Item::Forward(req) => match limits::check(&req, &limits, head) {
+                Ok(()) => Item::Forward(req),
+                Err(error) => {
+                    info!("key = {} {} rejected: {}", key, req.method, error.message);
+                    records.push(UsageRecord::call(&req.method, true, 0, 0));
+                    Item::reject(&req, error)
+                }
+            },
+            x => x,
+        })
+        .collect()
+}
+
+/// Error of the calls whose upstream response was larger than the limit
+pub fn response_exceeded(max: u64) -> ErrorObject {
+    ErrorObject::new(
+        jsonrpc::LIMIT_EXCEEDED,
+        &format!("response exceeds {} bytes, narrow the query", max),
+    )
+}
+
+/// Error of the calls whose upstream response could not be read
+pub fn response_failed() -> ErrorObject {
+    ErrorObject::new(
+        jsonrpc::UPSTREAM_UNAVAILABLE,
+        "invalid response from upstream",
+    )
+}
+
+// the only call of the payload was rejected by the gateway
+fn rejected_status(res: &jsonrpc::Response) -> StatusCode {
+    match res.error.as_ref().map(|e| e.code) {
+        Some(jsonrpc::LIMIT_EXCEEDED) => StatusCode::PayloadTooLarge,
+        Some(jsonrpc::FILTER_NOT_FOUND) => StatusCode::NotFound,
+        Some(jsonrpc::INTERNAL_ERROR) => StatusCode::ServiceUnavailable,
+        _ => StatusCode::Forbidden,
+    }
+}
+
+// budget of the tightest quota window of the key
+fn set_rate_limit(res: &mut Response, usage: &QuotaUsage) {
+    res.insert_header("X-RateLimit-Limit", usage.limit.to_string());
+    res.insert_header("X-RateLimit-Remaining", usage.remaining.to_string());
+    res.insert_header("X-RateLimit-Reset", usage.reset.to_string());
+}
+
+// budget of the key on the rejections made before the quota is charged
+async fn peek_rate_limit(
+    state: &State,
+    app: &Application,
+    key_hash: &str,
+    rpc_key: &RpcKey,
+    res: &mut Response,
+) {
+    match state
+        .quotas
+        .peek(&app.slug, key_hash, &rpc_key.quotas())
+        .await
+    {
+        Ok(Some(usage)) => set_rate_limit(res, &usage),
+        Ok(None) => {}
+        Err(e) => warn!("quota storage error: {}", e),
+    }
+}
+
+/// Error message for the payload that costs more than the whole quota window
+pub fn quota_overflow(cost: u64, usage: &QuotaUsage) -> String {
+    format!(
+        "cost {} exceeds the quota of {} per {}",
+        cost,
+        usage.limit,
+        usage.window.name()
+    )
+}
+
+// sends the payload to the nodes of the application until one of them answers,
+// returns the node that gave the response
+pub async fn forward(
+    state: &State,
+    route: &Route,
+    path: &str,
+    body: &str,
+    forwarded: &[&jsonrpc::Request],
+    pinned: Option<&str>,
+    span: &Span,
+) -> Option<(String, http_types::Response)> {
+    let app = &route.app;
+    // only calls that are safe to repeat are retried on the next node after they were sent
+    let retriable = forwarded.iter().all(|r| is_idempotent(&r.method));
+    let method = match forwarded {
+        [req] => req.method.clone(),
+        _ => "batch".to_owned(),
+    };
+    let ids: Vec<String> = forwarded
+        .iter()
+        .filter_map(|r| r.id.as_ref())
+        .filter_map(|id| serde_json::to_string(id).ok())
+        .collect();
+    let mut upstream = None;
+    let mut plan = state.balancer.plan(app);
+    // calls of the filter can only be answered by the node that has it
+    if let Some(url) = pinned {
+        plan.retain(|node| node.url == url);
+    }
+    // unhealthy nodes stay in the plan as the last resort
+    plan.sort_by_key(|node| !state.health.is_healthy(&app.slug, &node.url));
+    for node in plan {
+        let rpc_url = route.upstream_url(&node.url, path);
+        let mut call = span.child("jsonrpc.upstream", otlp::KIND_CLIENT);
+        call.set_str("http.url", &redact::path(&rpc_url));
+        call.set_str("rpc.method", &method);
+        call.set_str("rpc.jsonrpc.request_id", &ids.join(","));
+        let traceparent = Some(call.context().traceparent());
+        let started = Instant::now();
+        let res = state
+            .upstreams
+            .post(
+                &app.slug,
+                &app.proxy,
+                &rpc_url,
+                body,
+                traceparent,
+                retriable,
+            )
+            .await;
+        match &res {
+            Ok(x) => call.set_int("http.status_code", x.status() as i64),
+            Err(e) => call.set_error(&e.to_string()),
+        }
+        match res {
+            Ok(x) if x.status().is_server_error() && retriable => {
+                warn!("upstream {} responded with {}", node.url, x.status());
+                state.metrics.upstream_error(&app.slug, &node.url, "status");
+                state.balancer.record(&node.url, started.elapsed());
+                upstream = Some((node.url, x));
+            }
+            Ok(x) => {
+                if x.status().is_server_error() {
+                    state.metrics.upstream_error(&app.slug, &node.url, "status");
+                }
+                state.balancer.record(&node.url, started.elapsed());
+                upstream = Some((node.url, x));
+                break;
+            }
+            Err(e) => {
+                warn!("upstream {} error: {}", node.url, e);
+                state
+                    .metrics
+                    .upstream_error(&app.slug, &node.url, "connection");
+                let penalty = Duration::from_secs(app.proxy.timeouts.read);
+                state.balancer.record(&node.url, penalty);
+                // the call never reached the node, any call can go to the next one
+                if e.is::<ConnectError>() {
+                    continue;
+                }
+            }
+        }
+        if !retriable {
+            break;
+        }
+    }
+    upstream
+}
+
+pub fn reply(status: StatusCode, body: &jsonrpc::Response) -> Result {
+    let mut res = Response::new(status);
+    res.set_body(Body::from_json(body)?);
+    Ok(res)
+}
+
+// responses to the batch, batch of notifications gets no content
+fn reply_batch(responses: Vec<jsonrpc::Response>) -> Result {
+    if responses.is_empty() {
+        return Ok(Response::new(StatusCode::NoContent));
+    }
+    let mut res = Response::new(StatusCode::Ok);
+    res.set_body(Body::from_json(&responses)?);
+    Ok(res)
+}
+
+// JSON-RPC error response for the failure detected by the gateway,
+// every call of the batch gets its own error
+pub fn error_response(status: StatusCode, payload: &Payload, code: i64, message: &str) -> Result {
+    let error = ErrorObject::new(code, message);
+    if payload.is_batch() {
+        let mut res = Response::new(status);
+        let responses = batch::reject_all(payload, &error);
+        // batch of notifications gets no content
+        if !responses.is_empty() {
+            res.set_body(Body::from_json(&responses)?);
+        }
+        return Ok(res);
+    }
+    reply(status, &jsonrpc::Response::error(payload.id(), error))
+}
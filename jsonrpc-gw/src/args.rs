@@ -1,3 +1,5 @@
+use crate::cache::CacheMode;
+use jsonrpc_proto::parse_pepper;
 use jsonrpc_proto::redis::RedisConnection;
 use structopt::StructOpt;
 use tracing_subscriber::prelude::*;
@@ -17,17 +19,81 @@ pub struct Args {
     pub redis_db: u32,
     #[structopt(long, env = "REDIS_TLS")]
     pub redis_tls: bool,
+    /// Secret for hashing the keys, must be the same as in jsonrpc-key
+    #[structopt(
+        long,
+        env = "KEY_PEPPER",
+        hide_env_values = true,
+        parse(try_from_str = parse_pepper)
+    )]
+    pub key_pepper: String,
+    #[structopt(long, default_value = "16", env = "REDIS_POOL_SIZE")]
+    pub redis_pool_size: usize,
     #[structopt(short, long, default_value = "", env = "APPLICATION")]
     pub application: String,
-    #[structopt(short, long, default_value = "0.0.0.0:8000", env = "LISTEN")]
+    #[structopt(long, default_value = "0.0.0.0:8000", env = "LISTEN")]
     pub addr: String,
+    #[structopt(long, default_value = "127.0.0.1:8001", env = "ADMIN_LISTEN")]
+    pub admin_addr: String,
+    #[structopt(long, default_value = "10", env = "HEALTH_INTERVAL")]
+    pub health_interval: u64,
+    #[structopt(long, default_value = "5", env = "HEALTH_MAX_LAG")]
+    pub health_max_lag: u64,
+    /// Seconds to wait for one node to answer the health probe
+    #[structopt(long, default_value = "5", env = "HEALTH_TIMEOUT")]
+    pub health_timeout: u64,
+    #[structopt(long, default_value = "100", env = "MAX_BATCH_SIZE")]
+    pub max_batch_size: usize,
+    /// Where the results of the calls are cached: off, memory or redis
+    #[structopt(
+        long,
+        default_value = "off",
+        env = "CACHE",
+        possible_values = &CacheMode::variants(),
+        case_insensitive = true,
+    )]
+    pub cache: CacheMode,
+    /// Maximum number of results in the memory cache
+    #[structopt(long, default_value = "10000", env = "CACHE_SIZE")]
+    pub cache_size: usize,
+    /// Seconds to keep the results following the head, such as eth_blockNumber
+    #[structopt(long, default_value = "2", env = "CACHE_HEAD_TTL")]
+    pub cache_head_ttl: u64,
+    /// Blocks behind the head that are not expected to be reorganized
+    #[structopt(long, default_value = "64", env = "CACHE_CONFIRMATIONS")]
+    pub cache_confirmations: u64,
+    /// Seconds to remember the node of the filter that is not polled
+    #[structopt(long, default_value = "300", env = "FILTER_IDLE_TIMEOUT")]
+    pub filter_idle_timeout: u64,
+    /// Seconds between checks of the key of an open websocket session
+    #[structopt(
+        long,
+        default_value = "30",
+        env = "WS_KEY_INTERVAL",
+        parse(try_from_str = parse_interval)
+    )]
+    pub ws_key_interval: u64,
+    /// OTLP/HTTP collector for the traces, such as `http://localhost:4318`
+    #[structopt(long, default_value = "", env = "OTEL_EXPORTER_OTLP_ENDPOINT")]
+    pub otlp_endpoint: String,
+    #[structopt(long, default_value = "jsonrpc-gw", env = "OTEL_SERVICE_NAME")]
+    pub otlp_service: String,
+    /// Share of the new traces that are exported, from 0 to 1.
+    /// Traces started by the callers follow their `traceparent` flag
+    #[structopt(
+        long,
+        default_value = "0.1",
+        env = "OTEL_TRACES_SAMPLER_ARG",
+        parse(try_from_str = parse_ratio)
+    )]
+    pub otlp_sample_ratio: f64,
 }
 
 impl Args {
     pub fn get_redis_connection(&self) -> jsonrpc_proto::redis::RedisConnection {
         RedisConnection {
             host: self.redis_host.clone(),
-            port: self.redis_port.clone(),
+            port: self.redis_port,
             username: self.redis_username.clone(),
             password: self.redis_password.clone(),
             db: self.redis_db,
@@ -36,6 +102,23 @@ impl Args {
     }
 }
 
+fn parse_ratio(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(x) if (0.0..=1.0).contains(&x) => Ok(x),
+        Ok(_) => Err("ratio must be from 0 to 1".to_owned()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// zero would query the key storage without a pause
+fn parse_interval(s: &str) -> Result<u64, String> {
+    match s.parse::<u64>() {
+        Ok(0) => Err("interval must be at least 1 second".to_owned()),
+        Ok(x) => Ok(x),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
 pub fn parse() -> anyhow::Result<Args> {
     dotenv::dotenv().ok();
     let log_level: String = std::env::var("LOG_LEVEL").unwrap_or("info".to_owned());
@@ -54,6 +137,10 @@ pub fn parse() -> anyhow::Result<Args> {
         .init();
 
     let res = Args::from_args();
-    tracing::debug!("{:?}", res);
+    // secrets are not logged
+    let mut shown = res.clone();
+    shown.redis_password = "***".to_owned();
+    shown.key_pepper = "***".to_owned();
+    tracing::debug!("{:?}", shown);
     Ok(res)
 }
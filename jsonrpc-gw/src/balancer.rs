@@ -0,0 +1,186 @@
+use jsonrpc_proto::{Application, Balancing, Upstream};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::RwLock;
+use std::time::Duration;
+
+// weight of the newest sample in the latency moving average
+const LATENCY_ALPHA: f64 = 0.2;
+
+#[derive(Debug, Default, Clone)]
+struct Stats {
+    // moving average of the response latency in milliseconds
+    latency: Option<f64>,
+}
+
+/// Picks upstream nodes of the applications and remembers how they perform
+#[derive(Default)]
+pub struct Balancer {
+    counters: RwLock<HashMap<String, AtomicUsize>>,
+    stats: RwLock<HashMap<String, Stats>>,
+}
+
+/// Methods that change the node state and must not be sent twice
+pub fn is_idempotent(method: &str) -> bool {
+    !(method.starts_with("eth_send")
+        || method.starts_with("eth_sign")
+        || method.starts_with("eth_submit")
+        || method.starts_with("eth_new")
+        || method.starts_with("eth_uninstall")
+        || method.starts_with("personal_")
+        || method.starts_with("admin_")
+        || method.starts_with("miner_"))
+}
+
+impl Balancer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn next(&self, key: &str) -> usize {
+        if let Some(counter) = self.counters.read().expect("lock error").get(key) {
+            return counter.fetch_add(1, Ordering::Relaxed);
+        }
+        self.counters
+            .write()
+            .expect("lock error")
+            .entry(key.to_owned())
+            .or_default()
+            .fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn latency(&self, url: &str) -> f64 {
+        self.stats
+            .read()
+            .expect("lock error")
+            .get(url)
+            .and_then(|s| s.latency)
+            .unwrap_or(0.0)
+    }
+
+    // orders one priority group, the chosen node goes first
+    fn order(&self, slug: &str, balancing: Balancing, mut group: Vec<Upstream>) -> Vec<Upstream> {
+        match balancing {
+            Balancing::RoundRobin => {
+                let total: usize = group.iter().map(|u| u.weight as usize).sum();
+                if total == 0 || group.len() < 2 {
+                    return group;
+                }
+                // every priority group keeps its own turn
+                let key = format!("{}/{}", slug, group[0].priority);
+                let mut n = self.next(&key) % total;
+                let pos = group
+                    .iter()
+                    .position(|u| {
+                        if n < u.weight as usize {
+                            true
+                        } else {
+                            n -= u.weight as usize;
+                            false
+                        }
+                    })
+                    .unwrap_or(0);
+                group.rotate_left(pos);
+                group
+            }
+            Balancing::LeastLatency => {
+                group.sort_by(|a, b| {
+                    self.latency(&a.url)
+                        .partial_cmp(&self.latency(&b.url))
+                        .unwrap_or(std::cmp::Ordering::Equal)
+                });
+                group
+            }
+        }
+    }
+
+    /// Upstreams to try for the request, in order.
+    /// Nodes with lower priority value come first, the group is ordered by the balancing mode
+    pub fn plan(&self, app: &Application) -> Vec<Upstream> {
+        let mut upstreams = app.upstreams();
+        upstreams.sort_by_key(|u| u.priority);
+        let mut res = vec![];
+        let mut rest = upstreams.as_slice();
+        while let Some(first) = rest.first() {
+            let len = rest
+                .iter()
+                .take_while(|u| u.priority == first.priority)
+                .count();
+            res.extend(self.order(&app.slug, app.balancing, rest[..len].to_vec()));
+            rest = &rest[len..];
+        }
+        res
+    }
+
+    /// Records response latency of the upstream
+    pub fn record(&self, url: &str, elapsed: Duration) {
+        let ms = elapsed.as_secs_f64() * 1000.0;
+        let mut stats = self.stats.write().expect("lock error");
+        let entry = stats.entry(url.to_owned()).or_default();
+        entry.latency = Some(match entry.latency {
+            Some(avg) => avg + LATENCY_ALPHA * (ms - avg),
+            None => ms,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn app(upstreams: &[(&str, u32, u32)]) -> Application {
+        let mut app = Application::new(
+            "test",
+            None,
+            "/".to_owned(),
+            "http://default".to_owned(),
+            false,
+            vec![],
+        );
+        app.upstreams = upstreams
+            .iter()
+            .map(|&(url, weight, priority)| Upstream {
+                url: url.to_owned(),
+                weight,
+                priority,
+            })
+            .collect();
+        app
+    }
+
+    fn urls(plan: Vec<Upstream>) -> Vec<String> {
+        plan.into_iter().map(|u| u.url).collect()
+    }
+
+    #[test]
+    fn lower_priority_value_goes_first() {
+        let app = app(&[("b", 1, 1), ("a", 1, 0), ("c", 1, 2)]);
+        assert_eq!(urls(Balancer::new().plan(&app)), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn round_robin_follows_weights() {
+        let app = app(&[("a", 2, 0), ("b", 1, 0), ("backup", 1, 1)]);
+        let balancer = Balancer::new();
+        let firsts: Vec<String> = (0..6).map(|_| balancer.plan(&app)[0].url.clone()).collect();
+        assert_eq!(firsts, ["a", "a", "b", "a", "a", "b"]);
+        assert_eq!(balancer.plan(&app).last().unwrap().url, "backup");
+    }
+
+    #[test]
+    fn least_latency_prefers_fastest() {
+        let mut app = app(&[("slow", 1, 0), ("fast", 1, 0)]);
+        app.balancing = Balancing::LeastLatency;
+        let balancer = Balancer::new();
+        balancer.record("slow", Duration::from_millis(200));
+        balancer.record("fast", Duration::from_millis(20));
+        assert_eq!(urls(balancer.plan(&app)), ["fast", "slow"]);
+    }
+
+    #[test]
+    fn state_changing_calls_are_not_idempotent() {
+        assert!(is_idempotent("eth_call"));
+        assert!(!is_idempotent("eth_sendRawTransaction"));
+        assert!(!is_idempotent("eth_newFilter"));
+    }
+}
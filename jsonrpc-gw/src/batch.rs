@@ -0,0 +1,183 @@
+use jsonrpc_proto::jsonrpc::{self, ErrorObject, Id, Payload, Request, Response};
+
+/// Call of the payload, either sent upstream or answered by the gateway
+pub enum Item {
+    Forward(Request),
+    // error response, notifications get none
+    Rejected(Option<Response>),
+    // answered from the cache or by the gateway subscriptions
+    Answered(Response),
+}
+
+impl Item {
+    /// Rejects the call with the error, notifications are dropped silently
+    pub fn reject(req: &Request, error: ErrorObject) -> Self {
+        match &req.id {
+            Some(id) => Self::Rejected(Some(Response::error(id.clone(), error))),
+            None => Self::Rejected(None),
+        }
+    }
+}
+
+/// Splits the payload into calls, invalid calls of the batch are rejected individually
+pub fn items(payload: &Payload) -> Vec<Item> {
+    match payload {
+        Payload::Single(req) => vec![Item::Forward(req.clone())],
+        Payload::Batch(items) => items
+            .iter()
+            .map(|x| match x {
+                Ok(req) => Item::Forward(req.clone()),
+                Err(e) => Item::Rejected(Some(e.clone().into())),
+            })
+            .collect(),
+    }
+}
+
+/// Same error for every forwarded call that expects a response
+pub fn reject_calls(calls: &[&Request], error: &ErrorObject) -> Vec<Response> {
+    calls
+        .iter()
+        .filter_map(|r| r.id.clone())
+        .map(|id| Response::error(id, error.clone()))
+        .collect()
+}
+
+/// Same error for every call of the payload that expects a response
+pub fn reject_all(payload: &Payload, error: &ErrorObject) -> Vec<Response> {
+    let mut res = vec![];
+    if let Payload::Batch(items) = payload {
+        for item in items {
+            match item {
+                Ok(req) => {
+                    if let Some(id) = &req.id {
+                        res.push(Response::error(id.clone(), error.clone()));
+                    }
+                }
+                Err(e) => res.push(e.clone().into()),
+            }
+        }
+    }
+    res
+}
+
+/// Reads upstream reply to the forwarded calls.
+/// Error object for the whole batch is copied to every call
+pub fn parse_upstream(body: &str, forwarded: &[&Request]) -> Vec<Response> {
+    if let Ok(list) = serde_json::from_str::<Vec<Response>>(body) {
+        return list;
+    }
+    let error = match serde_json::from_str::<Response>(body) {
+        Ok(x) if matches!(forwarded, [req] if req.id.as_ref() == Some(&x.id)) => return vec![x],
+        Ok(Response {
+            error: Some(error), ..
+        }) => error,
+        _ => ErrorObject::new(
+            jsonrpc::UPSTREAM_UNAVAILABLE,
+            "invalid response from upstream",
+        ),
+    };
+    forwarded
+        .iter()
+        .filter_map(|req| req.id.clone())
+        .map(|id| Response::error(id, error.clone()))
+        .collect()
+}
+
+/// Puts upstream responses and rejections together in the order of the original calls,
+/// matching upstream responses by id
+pub fn merge(items: Vec<Item>, mut upstream: Vec<Response>) -> Vec<Response> {
+    let mut res = vec![];
+    for item in items {
+        match item {
+            Item::Forward(req) => {
+                let id: Id = match req.id {
+                    Some(id) => id,
+                    None => continue,
+                };
+                match upstream.iter().position(|r| r.id == id) {
+                    Some(pos) => res.push(upstream.remove(pos)),
+                    None => res.push(Response::error(
+                        id,
+                        ErrorObject::new(jsonrpc::INTERNAL_ERROR, "no response from upstream"),
+                    )),
+                }
+            }
+            Item::Rejected(Some(x)) | Item::Answered(x) => res.push(x),
+            Item::Rejected(None) => {}
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn ids(responses: &[Response]) -> Vec<String> {
+        responses
+            .iter()
+            .map(|r| serde_json::to_string(&r.id).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn batch_of_notifications_gets_no_errors() {
+        let payload =
+            Payload::parse(r#"[{"jsonrpc":"2.0","method":"a"},{"jsonrpc":"2.0","method":"b"}]"#)
+                .unwrap();
+        let error = ErrorObject::new(jsonrpc::LIMIT_EXCEEDED, "quota exceeded");
+        assert!(reject_all(&payload, &error).is_empty());
+    }
+
+    #[test]
+    fn invalid_items_are_rejected_in_place() {
+        let payload = Payload::parse(r#"[{"jsonrpc":"2.0","method":"a","id":1},1]"#).unwrap();
+        let error = ErrorObject::new(jsonrpc::LIMIT_EXCEEDED, "quota exceeded");
+        let res = reject_all(&payload, &error);
+        assert_eq!(res.len(), 2);
+        assert_eq!(res[0].error.as_ref().unwrap().code, jsonrpc::LIMIT_EXCEEDED);
+        assert_eq!(
+            res[1].error.as_ref().unwrap().code,
+            jsonrpc::INVALID_REQUEST
+        );
+    }
+
+    #[test]
+    fn merge_follows_batch_order() {
+        let payload = Payload::parse(
+            r#"[{"jsonrpc":"2.0","method":"a","id":1},{"jsonrpc":"2.0","method":"b"},
+                {"jsonrpc":"2.0","method":"c","id":"x"},{"jsonrpc":"2.0","method":"d","id":3}]"#,
+        )
+        .unwrap();
+        let upstream = parse_upstream(
+            r#"[{"jsonrpc":"2.0","id":3,"result":"d"},{"jsonrpc":"2.0","id":1,"result":"a"},
+                {"jsonrpc":"2.0","id":"x","result":"c"}]"#,
+            &payload.requests(),
+        );
+        let res = merge(items(&payload), upstream);
+        assert_eq!(ids(&res), ["1", "\"x\"", "3"]);
+        assert_eq!(res[0].result, Some(json!("a")));
+    }
+
+    #[test]
+    fn missing_upstream_response_becomes_error() {
+        let payload = Payload::parse(r#"[{"jsonrpc":"2.0","method":"a","id":1}]"#).unwrap();
+        let res = merge(items(&payload), vec![]);
+        assert_eq!(res[0].error.as_ref().unwrap().code, jsonrpc::INTERNAL_ERROR);
+    }
+
+    #[test]
+    fn upstream_error_for_whole_batch_is_copied() {
+        let payload = Payload::parse(
+            r#"[{"jsonrpc":"2.0","method":"a","id":1},{"jsonrpc":"2.0","method":"b","id":2}]"#,
+        )
+        .unwrap();
+        let res = parse_upstream(
+            r#"{"jsonrpc":"2.0","id":null,"error":{"code":-32000,"message":"busy"}}"#,
+            &payload.requests(),
+        );
+        assert_eq!(ids(&res), ["1", "2"]);
+        assert!(res.iter().all(|r| r.error.as_ref().unwrap().code == -32000));
+    }
+}
@@ -0,0 +1,485 @@
+use crate::health::parse_hex;
+use jsonrpc_proto::jsonrpc::Request;
+use jsonrpc_proto::redis::CacheStorage;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use structopt::clap::arg_enum;
+use tracing::warn;
+
+arg_enum! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum CacheMode {
+        Off,
+        Memory,
+        Redis,
+    }
+}
+
+/// How long the result of the call stays valid
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ttl {
+    Seconds(u64),
+    Forever,
+}
+
+impl Ttl {
+    fn seconds(&self) -> Option<u64> {
+        match self {
+            Self::Seconds(x) => Some(*x),
+            Self::Forever => None,
+        }
+    }
+}
+
+/// Result of looking up the call in the cache
+pub enum Lookup {
+    // the call is not cacheable
+    Bypass,
+    Miss,
+    Hit(Value),
+}
+
+/// Methods reading the state at the block, with the position of the block parameter
+pub fn block_param(method: &str) -> Option<usize> {
+    match method {
+        "eth_getBlockByNumber"
+        | "eth_getBlockTransactionCountByNumber"
+        | "eth_getTransactionByBlockNumberAndIndex"
+        | "eth_getUncleByBlockNumberAndIndex"
+        | "eth_getUncleCountByBlockNumber" => Some(0),
+        "eth_getBalance" | "eth_getCode" | "eth_getTransactionCount" | "eth_call" => Some(1),
+        "eth_getStorageAt" => Some(2),
+        _ => None,
+    }
+}
+
+// methods addressing the data by the block hash, which never changes
+fn by_block_hash(method: &str) -> bool {
+    matches!(
+        method,
+        "eth_getBlockByHash"
+            | "eth_getBlockTransactionCountByHash"
+            | "eth_getTransactionByBlockHashAndIndex"
+            | "eth_getUncleByBlockHashAndIndex"
+            | "eth_getUncleCountByBlockHash"
+    )
+}
+
+// transactions are immutable once their block is final, which is known from the result
+fn by_tx_hash(method: &str) -> bool {
+    matches!(
+        method,
+        "eth_getTransactionByHash" | "eth_getTransactionReceipt"
+    )
+}
+
+// block number or EIP-1898 block object, tags like `latest` are never pinned
+fn pinned(block: &Value, finalized: u64) -> bool {
+    match block.as_object() {
+        Some(x) if x.contains_key("blockHash") => true,
+        Some(x) => x.get("blockNumber").is_some_and(|n| pinned(n, finalized)),
+        None => parse_hex(block).is_some_and(|n| n <= finalized),
+    }
+}
+
+/// Which calls are cached and for how long
+#[derive(Debug, Clone)]
+pub struct Policy {
+    /// seconds to keep the results following the head, such as `eth_blockNumber`
+    pub head_ttl: u64,
+    /// blocks behind the head that are not expected to be reorganized
+    pub confirmations: u64,
+}
+
+impl Policy {
+    fn finalized(&self, head: Option<u64>) -> Option<u64> {
+        head?.checked_sub(self.confirmations)
+    }
+
+    // ttl of the call before its result is known, notifications expect no result
+    fn ttl(&self, req: &Request, head: Option<u64>) -> Option<Ttl> {
+        req.id.as_ref()?;
+        match req.method.as_str() {
+            "eth_chainId" | "net_version" => Some(Ttl::Forever),
+            "eth_blockNumber" if self.head_ttl > 0 => Some(Ttl::Seconds(self.head_ttl)),
+            m if by_block_hash(m) || by_tx_hash(m) => Some(Ttl::Forever),
+            m => {
+                let block = req.params.as_ref()?.as_array()?.get(block_param(m)?)?;
+                pinned(block, self.finalized(head)?).then_some(Ttl::Forever)
+            }
+        }
+    }
+
+    // missing blocks and pending transactions are not stored
+    fn result_ttl(&self, req: &Request, result: &Value, head: Option<u64>) -> Option<Ttl> {
+        if result.is_null() {
+            return None;
+        }
+        let ttl = self.ttl(req, head)?;
+        if by_tx_hash(&req.method) {
+            let mined = parse_hex(result.get("blockNumber")?)?;
+            return (mined <= self.finalized(head)?).then_some(ttl);
+        }
+        Some(ttl)
+    }
+}
+
+struct Entry {
+    value: Value,
+    expires: Option<Instant>,
+    // position in the insertion order
+    seq: u64,
+}
+
+// entries of every application, expired and then the oldest ones make room when full
+struct Memory {
+    entries: HashMap<String, Entry>,
+    order: BTreeMap<u64, String>,
+    // entries with the ttl by their expiry
+    expiring: BTreeMap<(Instant, u64), String>,
+    next: u64,
+    capacity: usize,
+}
+
+impl Memory {
+    fn new(capacity: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            expiring: BTreeMap::new(),
+            next: 0,
+            capacity,
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(x) = self.entries.remove(key) {
+            self.order.remove(&x.seq);
+            if let Some(t) = x.expires {
+                self.expiring.remove(&(t, x.seq));
+            }
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Option<Value> {
+        let entry = self.entries.get(key)?;
+        if entry.expires.is_none_or(|t| t > Instant::now()) {
+            return Some(entry.value.clone());
+        }
+        self.remove(key);
+        None
+    }
+
+    fn insert(&mut self, key: String, value: Value, expires: Option<Instant>) {
+        self.remove(&key);
+        if self.entries.len() >= self.capacity {
+            let now = Instant::now();
+            while let Some(entry) = self.expiring.first_entry() {
+                if entry.key().0 > now {
+                    break;
+                }
+                let k = entry.remove();
+                self.remove(&k);
+            }
+        }
+        while self.entries.len() >= self.capacity {
+            match self.order.first_key_value() {
+                Some((_, k)) => {
+                    let k = k.clone();
+                    self.remove(&k);
+                }
+                None => break,
+            };
+        }
+        if self.capacity == 0 {
+            return;
+        }
+        let seq = self.next;
+        self.next += 1;
+        self.order.insert(seq, key.clone());
+        if let Some(t) = expires {
+            self.expiring.insert((t, seq), key.clone());
+        }
+        self.entries.insert(
+            key,
+            Entry {
+                value,
+                expires,
+                seq,
+            },
+        );
+    }
+
+    fn clear(&mut self, prefix: &str) {
+        let keys: Vec<String> = self
+            .entries
+            .keys()
+            .filter(|k| k.starts_with(prefix))
+            .cloned()
+            .collect();
+        for k in keys {
+            self.remove(&k);
+        }
+    }
+}
+
+enum Backend {
+    Off,
+    Memory(Mutex<Memory>),
+    Redis(CacheStorage),
+}
+
+/// Results of the calls, kept in memory of the gateway or shared in Redis
+pub struct Cache {
+    policy: Policy,
+    backend: Backend,
+}
+
+// routes of one application may reach different upstream paths,
+// params are part of the key as they were sent by the client
+fn cache_key(path: &str, req: &Request) -> String {
+    let params = req.params.as_ref().map(|x| x.to_string());
+    format!("{}:{}:{}", path, req.method, params.unwrap_or_default())
+}
+
+impl Cache {
+    pub fn off() -> Self {
+        Self {
+            policy: Policy {
+                head_ttl: 0,
+                confirmations: 0,
+            },
+            backend: Backend::Off,
+        }
+    }
+
+    pub fn memory(policy: Policy, capacity: usize) -> Self {
+        Self {
+            policy,
+            backend: Backend::Memory(Mutex::new(Memory::new(capacity))),
+        }
+    }
+
+    pub fn redis(policy: Policy, storage: CacheStorage) -> Self {
+        Self {
+            policy,
+            backend: Backend::Redis(storage),
+        }
+    }
+
+    /// `path` is the upstream path of the call,
+    /// `head` is the best block of the application nodes, if known
+    pub async fn lookup(&self, app: &str, path: &str, req: &Request, head: Option<u64>) -> Lookup {
+        if self.policy.ttl(req, head).is_none() {
+            return Lookup::Bypass;
+        }
+        let key = cache_key(path, req);
+        let value = match &self.backend {
+            Backend::Off => return Lookup::Bypass,
+            Backend::Memory(memory) => memory
+                .lock()
+                .expect("lock error")
+                .get(&format!("{}:{}", app, key)),
+            Backend::Redis(storage) => match storage.get(app, &key).await {
+                Ok(x) => x,
+                Err(e) => {
+                    warn!("cache storage error: {}", e);
+                    None
+                }
+            },
+        };
+        match value {
+            Some(x) => Lookup::Hit(x),
+            None => Lookup::Miss,
+        }
+    }
+
+    /// Keeps the result of the call if the policy allows
+    pub fn store(&self, app: &str, path: &str, req: &Request, result: &Value, head: Option<u64>) {
+        let ttl = match self.policy.result_ttl(req, result, head) {
+            Some(x) => x,
+            None => return,
+        };
+        let key = cache_key(path, req);
+        match &self.backend {
+            Backend::Off => {}
+            Backend::Memory(memory) => {
+                let expires = ttl
+                    .seconds()
+                    .map(|x| Instant::now() + Duration::from_secs(x));
+                memory.lock().expect("lock error").insert(
+                    format!("{}:{}", app, key),
+                    result.clone(),
+                    expires,
+                );
+            }
+            Backend::Redis(storage) => {
+                let (storage, app, value) = (storage.clone(), app.to_owned(), result.clone());
+                async_std::task::spawn(async move {
+                    if let Err(e) = storage.set(&app, &key, &value, ttl.seconds()).await {
+                        warn!("cache storage error: {}", e);
+                    }
+                });
+            }
+        }
+    }
+
+    /// Forgets every result of the application, as its nodes may have changed
+    pub fn clear(&self, app: &str) {
+        match &self.backend {
+            Backend::Off => {}
+            Backend::Memory(memory) => memory
+                .lock()
+                .expect("lock error")
+                .clear(&format!("{}:", app)),
+            Backend::Redis(storage) => {
+                let (storage, app) = (storage.clone(), app.to_owned());
+                async_std::task::spawn(async move {
+                    if let Err(e) = storage.clear(&app).await {
+                        warn!("cache storage error: {}", e);
+                    }
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn call(method: &str, params: Value) -> Request {
+        serde_json::from_value(json!({
+            "jsonrpc": "2.0", "id": 1, "method": method, "params": params
+        }))
+        .unwrap()
+    }
+
+    fn by_hash(n: u64) -> Request {
+        call(
+            "eth_getBlockByHash",
+            json!([format!("0x{:064x}", n), false]),
+        )
+    }
+
+    fn policy() -> Policy {
+        Policy {
+            head_ttl: 2,
+            confirmations: 10,
+        }
+    }
+
+    async fn hit(cache: &Cache, app: &str, req: &Request) -> Option<Value> {
+        match cache.lookup(app, "/", req, Some(100)).await {
+            Lookup::Hit(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    #[async_std::test]
+    async fn oldest_entry_makes_room() {
+        let cache = Cache::memory(policy(), 3);
+        for n in 0..3 {
+            cache.store("main", "/", &by_hash(n), &json!(n), Some(100));
+        }
+        // stored again, so it is the newest now
+        cache.store("main", "/", &by_hash(0), &json!(0), Some(100));
+        cache.store("main", "/", &by_hash(3), &json!(3), Some(100));
+        assert_eq!(hit(&cache, "main", &by_hash(1)).await, None);
+        for n in [0, 2, 3] {
+            assert_eq!(hit(&cache, "main", &by_hash(n)).await, Some(json!(n)));
+        }
+        cache.store("main", "/", &by_hash(4), &json!(4), Some(100));
+        assert_eq!(hit(&cache, "main", &by_hash(2)).await, None);
+        assert_eq!(hit(&cache, "main", &by_hash(0)).await, Some(json!(0)));
+    }
+
+    #[async_std::test]
+    async fn expired_entries_go_first() {
+        let cache = Cache::memory(
+            Policy {
+                head_ttl: 0,
+                confirmations: 10,
+            },
+            2,
+        );
+        cache.store("main", "/", &by_hash(0), &json!(0), Some(100));
+        if let Backend::Memory(memory) = &cache.backend {
+            let mut memory = memory.lock().unwrap();
+            let key = format!("main:{}", cache_key("/", &by_hash(1)));
+            memory.insert(key, json!(1), Some(Instant::now()));
+        }
+        cache.store("main", "/", &by_hash(2), &json!(2), Some(100));
+        assert_eq!(hit(&cache, "main", &by_hash(0)).await, Some(json!(0)));
+        assert_eq!(hit(&cache, "main", &by_hash(1)).await, None);
+        assert_eq!(hit(&cache, "main", &by_hash(2)).await, Some(json!(2)));
+    }
+
+    #[async_std::test]
+    async fn paths_do_not_share_results() {
+        let cache = Cache::memory(policy(), 10);
+        cache.store("main", "/v1", &by_hash(0), &json!("v1"), Some(100));
+        let lookup = cache.lookup("main", "/v2", &by_hash(0), Some(100)).await;
+        assert!(matches!(lookup, Lookup::Miss));
+        let lookup = cache.lookup("main", "/v1", &by_hash(0), Some(100)).await;
+        assert!(matches!(lookup, Lookup::Hit(x) if x == json!("v1")));
+    }
+
+    #[async_std::test]
+    async fn expired_entries_are_indexed() {
+        let cache = Cache::memory(policy(), 2);
+        let head = call("eth_blockNumber", json!([]));
+        cache.store("main", "/", &head, &json!("0x64"), Some(100));
+        cache.store("main", "/", &by_hash(0), &json!(0), Some(100));
+        if let Backend::Memory(memory) = &cache.backend {
+            let mut memory = memory.lock().unwrap();
+            assert_eq!(memory.expiring.len(), 1);
+            // stored again with a new expiry, the old one is not indexed anymore
+            let key = format!("main:{}", cache_key("/", &head));
+            memory.insert(key, json!("0x65"), Some(Instant::now()));
+            assert_eq!(memory.expiring.len(), 1);
+        }
+        cache.store("main", "/", &by_hash(1), &json!(1), Some(100));
+        assert_eq!(hit(&cache, "main", &by_hash(0)).await, Some(json!(0)));
+        assert_eq!(hit(&cache, "main", &by_hash(1)).await, Some(json!(1)));
+        if let Backend::Memory(memory) = &cache.backend {
+            let memory = memory.lock().unwrap();
+            assert!(memory.expiring.is_empty());
+            assert_eq!((memory.entries.len(), memory.order.len()), (2, 2));
+        }
+    }
+
+    #[async_std::test]
+    async fn clear_forgets_one_application() {
+        let cache = Cache::memory(policy(), 10);
+        let chain = call("eth_chainId", json!([]));
+        for app in ["eth", "eth_x"] {
+            cache.store(app, "/", &chain, &json!("0x1"), Some(100));
+        }
+        cache.clear("eth");
+        assert_eq!(hit(&cache, "eth", &chain).await, None);
+        assert_eq!(hit(&cache, "eth_x", &chain).await, Some(json!("0x1")));
+        // cleared entries do not count against the capacity
+        if let Backend::Memory(memory) = &cache.backend {
+            let memory = memory.lock().unwrap();
+            assert_eq!((memory.entries.len(), memory.order.len()), (1, 1));
+            assert!(memory.expiring.is_empty());
+        }
+    }
+
+    #[async_std::test]
+    async fn off_is_the_default() {
+        use structopt::StructOpt;
+        let args = crate::args::Args::from_iter_safe(["jsonrpc-gw", "--key-pepper", "x"]);
+        assert_eq!(args.unwrap().cache, CacheMode::Off);
+        let cache = Cache::off();
+        cache.store("main", "/", &by_hash(0), &json!(0), Some(100));
+        assert!(matches!(
+            cache.lookup("main", "/", &by_hash(0), Some(100)).await,
+            Lookup::Bypass
+        ));
+    }
+}
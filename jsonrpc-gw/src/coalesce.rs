@@ -0,0 +1,187 @@
+use async_std::channel::{bounded, Receiver, Sender};
+use jsonrpc_proto::jsonrpc::{Request, Response};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tide::StatusCode;
+
+/// Reply of the upstream, shared with every caller waiting for it
+#[derive(Debug, Clone)]
+pub struct Outcome {
+    // node that answered, none when every node failed
+    pub upstream: Option<String>,
+    pub status: StatusCode,
+    pub response: Response,
+}
+
+/// Part of the caller in the upstream call
+pub enum Role {
+    // makes the call and shares its outcome
+    Leader(Flight),
+    // gets the outcome of the call made by the leader
+    Waiter(Receiver<Outcome>),
+}
+
+/// Identical calls in flight, keyed by the application, upstream path, caller, method and params.
+/// Calls of different keys are never shared, their limits and errors may differ
+#[derive(Default)]
+pub struct Coalescer {
+    pending: Mutex<HashMap<String, Vec<Sender<Outcome>>>>,
+}
+
+impl Coalescer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The first caller of the identical calls leads, the rest wait for it
+    pub fn join(self: &Arc<Self>, app: &str, path: &str, caller: &str, req: &Request) -> Role {
+        let params = req.params.as_ref().map(|x| x.to_string());
+        let key = format!(
+            "{}:{}:{}:{}:{}",
+            app,
+            path,
+            caller,
+            req.method,
+            params.unwrap_or_default()
+        );
+        let mut pending = self.pending.lock().expect("lock error");
+        if let Some(waiters) = pending.get_mut(&key) {
+            let (tx, rx) = bounded(1);
+            waiters.push(tx);
+            return Role::Waiter(rx);
+        }
+        pending.insert(key.clone(), vec![]);
+        Role::Leader(Flight {
+            coalescer: self.clone(),
+            key,
+            outcome: None,
+        })
+    }
+}
+
+/// Upstream call of the leader. Waiters are released when it is dropped,
+/// those left without the outcome make their own calls
+pub struct Flight {
+    coalescer: Arc<Coalescer>,
+    key: String,
+    outcome: Option<Outcome>,
+}
+
+impl Flight {
+    pub fn finish(mut self, outcome: Outcome) {
+        self.outcome = Some(outcome);
+    }
+}
+
+impl Drop for Flight {
+    fn drop(&mut self) {
+        let waiters = self
+            .coalescer
+            .pending
+            .lock()
+            .expect("lock error")
+            .remove(&self.key)
+            .unwrap_or_default();
+        if let Some(outcome) = &self.outcome {
+            for tx in waiters {
+                let _ = tx.try_send(outcome.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonrpc_proto::jsonrpc::Id;
+    use serde_json::json;
+
+    fn call(method: &str, params: serde_json::Value) -> Request {
+        serde_json::from_value(
+            json!({"jsonrpc": "2.0", "id": 1, "method": method, "params": params}),
+        )
+        .unwrap()
+    }
+
+    fn outcome(result: &str) -> Outcome {
+        Outcome {
+            upstream: Some("http://node".to_owned()),
+            status: StatusCode::Ok,
+            response: Response::result(Id::Null, json!(result)),
+        }
+    }
+
+    fn lead(role: Role) -> Flight {
+        match role {
+            Role::Leader(x) => x,
+            Role::Waiter(_) => panic!("leader expected"),
+        }
+    }
+
+    fn wait(role: Role) -> Receiver<Outcome> {
+        match role {
+            Role::Waiter(x) => x,
+            Role::Leader(_) => panic!("waiter expected"),
+        }
+    }
+
+    #[async_std::test]
+    async fn identical_calls_share_the_outcome() {
+        let coalescer = Arc::new(Coalescer::new());
+        let req = call("eth_getBalance", json!(["0x01", "0x64"]));
+        let flight = lead(coalescer.join("main", "/", "k1", &req));
+        let first = wait(coalescer.join("main", "/", "k1", &req));
+        let second = wait(coalescer.join("main", "/", "k1", &req));
+        flight.finish(outcome("0x10"));
+        for rx in [first, second] {
+            let shared = rx.recv().await.unwrap();
+            assert_eq!(shared.response.result, Some(json!("0x10")));
+        }
+        // the flight is over, the next call leads
+        lead(coalescer.join("main", "/", "k1", &req));
+    }
+
+    #[test]
+    fn calls_differing_in_any_part_lead() {
+        let coalescer = Arc::new(Coalescer::new());
+        let req = call("eth_getBalance", json!(["0x01", "0x64"]));
+        let _flight = lead(coalescer.join("main", "/", "k1", &req));
+        let _apps = lead(coalescer.join("other", "/", "k1", &req));
+        let _paths = lead(coalescer.join("main", "/v2", "k1", &req));
+        let _callers = lead(coalescer.join("main", "/", "k2", &req));
+        let other = call("eth_getBalance", json!(["0x01", "0x65"]));
+        let _params = lead(coalescer.join("main", "/", "k1", &other));
+        let other = call("eth_getCode", json!(["0x01", "0x64"]));
+        let _methods = lead(coalescer.join("main", "/", "k1", &other));
+    }
+
+    #[async_std::test]
+    async fn waiters_are_released_when_leader_gives_up() {
+        let coalescer = Arc::new(Coalescer::new());
+        let req = call("eth_blockNumber", json!([]));
+        // dropped without the outcome
+        let flight = lead(coalescer.join("main", "/", "k1", &req));
+        let rx = wait(coalescer.join("main", "/", "k1", &req));
+        drop(flight);
+        assert!(rx.recv().await.is_err());
+        // panicked
+        let flight = lead(coalescer.join("main", "/", "k1", &req));
+        let rx = wait(coalescer.join("main", "/", "k1", &req));
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(move || {
+            let _flight = flight;
+            panic!("leader failed");
+        }));
+        assert!(panicked.is_err());
+        assert!(rx.recv().await.is_err());
+        // cancelled while waiting for the upstream
+        let flight = lead(coalescer.join("main", "/", "k1", &req));
+        let rx = wait(coalescer.join("main", "/", "k1", &req));
+        let task = async_std::task::spawn(async move {
+            async_std::future::pending::<()>().await;
+            flight.finish(outcome("0x1"));
+        });
+        assert!(task.cancel().await.is_none());
+        assert!(rx.recv().await.is_err());
+        lead(coalescer.join("main", "/", "k1", &req));
+    }
+}
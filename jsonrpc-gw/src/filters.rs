@@ -0,0 +1,94 @@
+use jsonrpc_proto::jsonrpc::{Request, Response};
+use jsonrpc_proto::redis::FilterStorage;
+use std::time::Duration;
+use tracing::error;
+
+/// Methods creating filters that live on one node
+pub fn creates_filter(method: &str) -> bool {
+    matches!(
+        method,
+        "eth_newFilter" | "eth_newBlockFilter" | "eth_newPendingTransactionFilter"
+    )
+}
+
+/// Calls whose responses change the filters of the node
+pub fn changes_filters(method: &str) -> bool {
+    creates_filter(method) || method == "eth_uninstallFilter"
+}
+
+/// Filter id of the calls that use the filter
+pub fn filter_id(req: &Request) -> Option<&str> {
+    match req.method.as_str() {
+        "eth_getFilterChanges" | "eth_getFilterLogs" | "eth_uninstallFilter" => {
+            req.params.as_ref()?.get(0)?.as_str()
+        }
+        _ => None,
+    }
+}
+
+/// Upstream nodes that issued the filters, so the calls of the filter reach the same node
+/// whichever gateway replica serves them. Filters that are not used for `idle` are forgotten,
+/// as the nodes drop them too
+pub struct Filters {
+    storage: FilterStorage,
+    idle: Duration,
+}
+
+impl Filters {
+    pub fn new(storage: FilterStorage, idle: Duration) -> Self {
+        Self { storage, idle }
+    }
+
+    pub async fn insert(&self, app: &str, id: &str, upstream: &str) {
+        let idle = self.idle.as_secs();
+        if let Err(e) = self.storage.set(app, id, upstream, idle).await {
+            error!("filter storage error: {}", e);
+        }
+    }
+
+    /// Node of the filter, unknown and idle filters have none
+    pub async fn get(&self, app: &str, id: &str) -> anyhow::Result<Option<String>> {
+        self.storage.get(app, id, self.idle.as_secs()).await
+    }
+
+    pub async fn remove(&self, app: &str, id: &str) {
+        if let Err(e) = self.storage.remove(app, id).await {
+            error!("filter storage error: {}", e);
+        }
+    }
+
+    /// Filters of the calls whose node is gone are lost with it
+    pub async fn forget(&self, app: &str, calls: &[&Request]) {
+        for id in calls.iter().filter_map(|x| filter_id(x)) {
+            self.remove(app, id).await;
+        }
+    }
+
+    /// Remembers the filters created by the node and forgets the uninstalled ones
+    pub async fn track(
+        &self,
+        app: &str,
+        upstream: &str,
+        calls: &[&Request],
+        responses: &[Response],
+    ) {
+        for req in calls {
+            if req.method == "eth_uninstallFilter" {
+                if let Some(id) = filter_id(req) {
+                    self.remove(app, id).await;
+                }
+                continue;
+            }
+            if !creates_filter(&req.method) {
+                continue;
+            }
+            let id = responses
+                .iter()
+                .find(|x| req.id.as_ref() == Some(&x.id))
+                .and_then(|x| x.result.as_ref()?.as_str());
+            if let Some(id) = id {
+                self.insert(app, id, upstream).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,175 @@
+use crate::cache::block_param;
+use jsonrpc_proto::jsonrpc::Request;
+use serde_json::Value;
+
+// `pending` is kept for the state, as it includes the transactions of the mempool,
+// only the ranges of the logs end at the head either way
+fn pin_tag(block: &mut Value, head: &str, pending: bool) -> bool {
+    let pinned = match block {
+        Value::String(x) if x == "latest" => true,
+        Value::String(x) if x == "pending" => pending,
+        // null is the same as the omitted block
+        Value::Null => true,
+        _ => false,
+    };
+    if pinned {
+        *block = Value::String(head.to_owned());
+    }
+    pinned
+}
+
+/// Rewrites the `latest` block of the call to the head, the omitted block is `latest`
+/// for the nodes and is set to the head too. Returns whether the call changed
+pub fn pin(req: &mut Request, head: u64) -> bool {
+    let head = format!("0x{:x}", head);
+    let params = match req.params.as_mut().and_then(|x| x.as_array_mut()) {
+        Some(x) => x,
+        None => return false,
+    };
+    match req.method.as_str() {
+        "eth_getLogs" | "trace_filter" => {
+            let filter = match params.get_mut(0).and_then(|x| x.as_object_mut()) {
+                Some(x) => x,
+                None => return false,
+            };
+            // the logs of one block are not a range
+            if filter.contains_key("blockHash") {
+                return false;
+            }
+            // omitted start of the traces is the first block
+            let omitted_from = req.method == "eth_getLogs";
+            let mut changed = false;
+            for (field, omitted) in [("fromBlock", omitted_from), ("toBlock", true)] {
+                match filter.get_mut(field) {
+                    Some(block) => changed |= pin_tag(block, &head, true),
+                    None if omitted => {
+                        filter.insert(field.to_owned(), Value::String(head.clone()));
+                        changed = true;
+                    }
+                    None => {}
+                }
+            }
+            changed
+        }
+        m => match block_param(m) {
+            // the block follows the required params, calls missing those are left as is
+            Some(i) if i > 0 && params.len() == i => {
+                params.push(Value::String(head));
+                true
+            }
+            Some(i) => match params.get_mut(i) {
+                Some(block) => pin_tag(block, &head, false),
+                None => false,
+            },
+            None => false,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn pinned(method: &str, params: Value) -> (bool, Value) {
+        let mut req: Request = serde_json::from_value(
+            json!({"jsonrpc": "2.0", "id": 1, "method": method, "params": params}),
+        )
+        .unwrap();
+        let changed = pin(&mut req, 0x64);
+        (changed, req.params.unwrap())
+    }
+
+    #[test]
+    fn latest_block_is_pinned() {
+        let call = json!({"to": "0x01"});
+        assert_eq!(
+            pinned("eth_call", json!([call, "latest"])),
+            (true, json!([call, "0x64"]))
+        );
+        assert_eq!(
+            pinned("eth_call", json!([call, null])),
+            (true, json!([call, "0x64"]))
+        );
+        assert_eq!(
+            pinned("eth_getStorageAt", json!(["0x01", "0x0", "latest"])),
+            (true, json!(["0x01", "0x0", "0x64"]))
+        );
+        // the state of the mempool is not the head
+        assert_eq!(
+            pinned("eth_getBalance", json!(["0x01", "pending"])),
+            (false, json!(["0x01", "pending"]))
+        );
+        assert_eq!(
+            pinned("eth_getBalance", json!(["0x01", "0x10"])),
+            (false, json!(["0x01", "0x10"]))
+        );
+        assert_eq!(
+            pinned("eth_getBlockByNumber", json!(["latest", false])),
+            (true, json!(["0x64", false]))
+        );
+    }
+
+    #[test]
+    fn omitted_block_is_pinned() {
+        let call = json!({"to": "0x01"});
+        assert_eq!(
+            pinned("eth_call", json!([call])),
+            (true, json!([call, "0x64"]))
+        );
+        assert_eq!(
+            pinned("eth_getBalance", json!(["0x01"])),
+            (true, json!(["0x01", "0x64"]))
+        );
+        assert_eq!(
+            pinned("eth_getStorageAt", json!(["0x01", "0x0"])),
+            (true, json!(["0x01", "0x0", "0x64"]))
+        );
+        // invalid calls are for the node to answer
+        assert_eq!(pinned("eth_getBalance", json!([])), (false, json!([])));
+        assert_eq!(
+            pinned("eth_getBlockByNumber", json!([])),
+            (false, json!([]))
+        );
+        assert_eq!(pinned("eth_chainId", json!([])), (false, json!([])));
+    }
+
+    #[test]
+    fn log_ranges_are_pinned() {
+        assert_eq!(
+            pinned("eth_getLogs", json!([{"fromBlock": "0x10"}])),
+            (true, json!([{"fromBlock": "0x10", "toBlock": "0x64"}]))
+        );
+        assert_eq!(
+            pinned("eth_getLogs", json!([{"address": "0x01"}])),
+            (
+                true,
+                json!([{"address": "0x01", "fromBlock": "0x64", "toBlock": "0x64"}])
+            )
+        );
+        assert_eq!(
+            pinned(
+                "eth_getLogs",
+                json!([{"fromBlock": "pending", "toBlock": "latest"}])
+            ),
+            (true, json!([{"fromBlock": "0x64", "toBlock": "0x64"}]))
+        );
+        assert_eq!(
+            pinned(
+                "eth_getLogs",
+                json!([{"fromBlock": "0x1", "toBlock": "0x2"}])
+            ),
+            (false, json!([{"fromBlock": "0x1", "toBlock": "0x2"}]))
+        );
+        let by_hash = json!([{"blockHash": "0xab"}]);
+        assert_eq!(pinned("eth_getLogs", by_hash.clone()), (false, by_hash));
+        assert_eq!(
+            pinned("trace_filter", json!([{"toBlock": "latest"}])),
+            (true, json!([{"toBlock": "0x64"}]))
+        );
+        assert_eq!(
+            pinned("trace_filter", json!([{"fromBlock": "0x1"}])),
+            (true, json!([{"fromBlock": "0x1", "toBlock": "0x64"}]))
+        );
+    }
+}
@@ -0,0 +1,226 @@
+use crate::router::{Route, Router};
+use crate::upstream::Upstreams;
+use async_std::future::timeout;
+use futures_util::future::join_all;
+use jsonrpc_proto::Application;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, info, warn};
+
+/// Last known state of the upstream node
+#[derive(Debug, Clone, Serialize)]
+pub struct NodeHealth {
+    pub url: String,
+    pub healthy: bool,
+    pub height: Option<u64>,
+    pub syncing: bool,
+    pub error: Option<String>,
+    pub checked_at: u64,
+}
+
+/// Health of upstream nodes per application, updated by the background checker
+#[derive(Default)]
+pub struct Health {
+    apps: RwLock<HashMap<String, Vec<NodeHealth>>>,
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Nodes that were never checked are considered healthy
+    pub fn is_healthy(&self, slug: &str, url: &str) -> bool {
+        self.apps
+            .read()
+            .expect("lock error")
+            .get(slug)
+            .and_then(|nodes| nodes.iter().find(|n| n.url == url))
+            .map(|n| n.healthy)
+            .unwrap_or(true)
+    }
+
+    /// Highest block reported by the healthy nodes of the application
+    pub fn best_height(&self, slug: &str) -> Option<u64> {
+        self.apps
+            .read()
+            .expect("lock error")
+            .get(slug)?
+            .iter()
+            .filter(|n| n.healthy)
+            .filter_map(|n| n.height)
+            .max()
+    }
+
+    /// Block reached by every healthy node of the application
+    pub fn agreed_height(&self, slug: &str) -> Option<u64> {
+        self.apps
+            .read()
+            .expect("lock error")
+            .get(slug)?
+            .iter()
+            .filter(|n| n.healthy)
+            .filter_map(|n| n.height)
+            .min()
+    }
+
+    pub fn snapshot(&self) -> HashMap<String, Vec<NodeHealth>> {
+        self.apps.read().expect("lock error").clone()
+    }
+
+    fn update(&self, slug: &str, nodes: Vec<NodeHealth>) {
+        self.apps
+            .write()
+            .expect("lock error")
+            .insert(slug.to_owned(), nodes);
+    }
+
+    fn retain(&self, slugs: &[String]) {
+        self.apps
+            .write()
+            .expect("lock error")
+            .retain(|slug, _| slugs.contains(slug));
+    }
+}
+
+pub fn parse_hex(v: &Value) -> Option<u64> {
+    u64::from_str_radix(v.as_str()?.trim_start_matches("0x"), 16).ok()
+}
+
+async fn call(
+    upstreams: &Upstreams,
+    app: &Application,
+    url: &str,
+    method: &str,
+) -> anyhow::Result<Value> {
+    let payload = json!({"jsonrpc": "2.0", "id": 1, "method": method, "params": []});
+    let mut res = upstreams
+        .post(&app.slug, &app.proxy, url, &payload.to_string(), None, true)
+        .await?;
+    if !res.status().is_success() {
+        return Err(anyhow::Error::msg(format!("status {}", res.status())));
+    }
+    let body: Value = res
+        .body_json()
+        .await
+        .map_err(|e| anyhow::Error::msg(e.to_string()))?;
+    if let Some(err) = body.get("error") {
+        return Err(anyhow::Error::msg(format!("{} error {}", method, err)));
+    }
+    Ok(body.get("result").cloned().unwrap_or(Value::Null))
+}
+
+// height of the node and whether it reports syncing
+async fn probe(upstreams: &Upstreams, app: &Application, url: &str) -> anyhow::Result<(u64, bool)> {
+    let height = call(upstreams, app, url, "eth_blockNumber").await?;
+    let height = parse_hex(&height)
+        .ok_or_else(|| anyhow::Error::msg(format!("invalid block number {}", height)))?;
+    let syncing = call(upstreams, app, url, "eth_syncing").await?;
+    Ok((height, syncing != Value::Bool(false) && !syncing.is_null()))
+}
+
+// node state, the probe that takes longer than `limit` fails
+async fn check_node(
+    upstreams: &Upstreams,
+    route: &Route,
+    url: String,
+    limit: Duration,
+    now: u64,
+) -> NodeHealth {
+    let rpc_url = route.upstream_url(&url, "");
+    let res = timeout(limit, probe(upstreams, &route.app, &rpc_url))
+        .await
+        .unwrap_or_else(|_| Err(anyhow::Error::msg("health probe timeout")));
+    match res {
+        Ok((height, syncing)) => NodeHealth {
+            url,
+            healthy: !syncing,
+            height: Some(height),
+            syncing,
+            error: None,
+            checked_at: now,
+        },
+        Err(e) => NodeHealth {
+            url,
+            healthy: false,
+            height: None,
+            syncing: false,
+            error: Some(e.to_string()),
+            checked_at: now,
+        },
+    }
+}
+
+async fn check_app(
+    upstreams: &Upstreams,
+    health: &Health,
+    app: &Application,
+    limit: Duration,
+    max_lag: u64,
+) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs();
+    let route = Route::root(app);
+    let probes = app
+        .upstreams()
+        .into_iter()
+        .map(|node| check_node(upstreams, &route, node.url, limit, now));
+    let mut nodes = join_all(probes).await;
+    let best = nodes.iter().filter_map(|n| n.height).max().unwrap_or(0);
+    for node in nodes.iter_mut() {
+        if let Some(height) = node.height {
+            if best - height > max_lag {
+                node.healthy = false;
+                node.error = Some(format!("{} blocks behind", best - height));
+            }
+        }
+        debug!(
+            "app = {} upstream = {} height = {:?} healthy = {}",
+            app.slug, node.url, node.height, node.healthy
+        );
+        let was_healthy = health.is_healthy(&app.slug, &node.url);
+        if was_healthy && !node.healthy {
+            warn!(
+                "app = {} upstream = {} is unhealthy: {}",
+                app.slug,
+                node.url,
+                node.error.as_deref().unwrap_or("syncing")
+            );
+        } else if !was_healthy && node.healthy {
+            info!(
+                "app = {} upstream = {} recovered at height {:?}",
+                app.slug, node.url, node.height
+            );
+        }
+    }
+    health.update(&app.slug, nodes);
+}
+
+/// Polls every upstream of every served application with eth_blockNumber and eth_syncing.
+/// Nodes of the application are probed at once, each within `limit`.
+/// Node is unhealthy when it fails, syncs or is more than `max_lag` blocks behind the best one
+pub fn spawn(
+    router: Arc<RwLock<Router>>,
+    upstreams: Arc<Upstreams>,
+    health: Arc<Health>,
+    interval: Duration,
+    limit: Duration,
+    max_lag: u64,
+) {
+    async_std::task::spawn(async move {
+        loop {
+            let apps = router.read().expect("lock error").apps().to_vec();
+            let slugs: Vec<String> = apps.iter().map(|a| a.slug.clone()).collect();
+            health.retain(&slugs);
+            for app in apps {
+                check_app(&upstreams, &health, &app, limit, max_lag).await;
+            }
+            async_std::task::sleep(interval).await;
+        }
+    });
+}
@@ -0,0 +1,211 @@
+use crate::health::parse_hex;
+use async_std::io::ReadExt;
+use jsonrpc_proto::jsonrpc::{self, ErrorObject, Request};
+use jsonrpc_proto::Limits;
+use serde_json::Value;
+
+// block of the range, missing block and tags other than `earliest` are the head,
+// `Err` when the block is the head and the head is unknown
+fn block(value: Option<&Value>, head: Option<u64>) -> Result<Option<u64>, ()> {
+    match value {
+        Some(x) if x == "earliest" => Ok(Some(0)),
+        Some(x) if x.as_str().is_some_and(|s| s.starts_with("0x")) => Ok(parse_hex(x)),
+        _ => head.map(Some).ok_or(()),
+    }
+}
+
+// addresses and topics are given one by one or as lists, topics are nested
+fn count(value: Option<&Value>) -> usize {
+    match value {
+        Some(Value::Array(list)) => list.iter().map(|x| count(Some(x))).sum(),
+        Some(Value::Null) | None => 0,
+        Some(_) => 1,
+    }
+}
+
+fn exceeded(message: &str) -> ErrorObject {
+    ErrorObject::new(jsonrpc::LIMIT_EXCEEDED, message)
+}
+
+/// Checks the filter of `eth_getLogs`, `eth_newFilter` and `trace_filter` against the limits,
+/// `head` is the best block of the application nodes, if known
+pub fn check(req: &Request, limits: &Limits, head: Option<u64>) -> Result<(), ErrorObject> {
+    let filter = match req.method.as_str() {
+        "eth_getLogs" | "eth_newFilter" | "trace_filter" => {
+            req.params.as_ref().and_then(|x| x.get(0))
+        }
+        _ => None,
+    };
+    let filter = match filter {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+    // block hash stands for one block
+    if let (Some(max), None) = (limits.max_block_range, filter.get("blockHash")) {
+        let (from, to) =
+            match (
+                block(filter.get("fromBlock"), head),
+                block(filter.get("toBlock"), head),
+            ) {
+                (Ok(from), Ok(to)) => (from, to),
+                _ => return Err(exceeded(
+                    "block range is open while the head is unknown, give both blocks as numbers",
+                )),
+            };
+        if let (Some(from), Some(to)) = (from, to) {
+            let range = to.saturating_sub(from) + 1;
+            if range > max {
+                return Err(exceeded(&format!(
+                    "block range of {} blocks exceeds {}, split the range",
+                    range, max
+                )));
+            }
+        }
+    }
+    if let Some(max) = limits.max_addresses {
+        let addresses = match req.method.as_str() {
+            "trace_filter" => count(filter.get("fromAddress")) + count(filter.get("toAddress")),
+            _ => count(filter.get("address")),
+        };
+        if addresses > max {
+            return Err(exceeded(&format!(
+                "filter of {} addresses exceeds {}",
+                addresses, max
+            )));
+        }
+    }
+    if let Some(max) = limits.max_topics {
+        let topics = count(filter.get("topics"));
+        if topics > max {
+            return Err(exceeded(&format!(
+                "filter of {} topics exceeds {}",
+                topics, max
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Upstream response that could not be read
+#[derive(Debug)]
+pub enum BodyError {
+    /// Larger than the limit
+    Exceeded,
+    /// Broken or not UTF-8
+    Failed(String),
+}
+
+/// Reads the upstream response up to `max` bytes.
+/// Reading stops at the limit, so the oversized response is never kept in memory
+pub async fn read_body(
+    res: &mut http_types::Response,
+    max: Option<u64>,
+) -> Result<String, BodyError> {
+    if let (Some(max), Some(len)) = (max, res.len()) {
+        if len as u64 > max {
+            return Err(BodyError::Exceeded);
+        }
+    }
+    let mut body = Vec::new();
+    res.take_body()
+        .take(max.map_or(u64::MAX, |x| x + 1))
+        .read_to_end(&mut body)
+        .await
+        .map_err(|e| BodyError::Failed(e.to_string()))?;
+    if max.is_some_and(|x| body.len() as u64 > x) {
+        return Err(BodyError::Exceeded);
+    }
+    String::from_utf8(body).map_err(|e| BodyError::Failed(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_types::Body;
+    use serde_json::json;
+
+    fn checked(method: &str, filter: Value, head: Option<u64>) -> Result<(), ErrorObject> {
+        let req: Request = serde_json::from_value(
+            json!({"jsonrpc": "2.0", "id": 1, "method": method, "params": [filter]}),
+        )
+        .unwrap();
+        let limits = Limits {
+            max_block_range: Some(100),
+            max_addresses: Some(2),
+            max_topics: Some(3),
+            ..Default::default()
+        };
+        check(&req, &limits, head)
+    }
+
+    #[test]
+    fn block_range_is_limited() {
+        let range = |from: &str, to: &str| json!({"fromBlock": from, "toBlock": to});
+        assert!(checked("eth_getLogs", range("0x1", "0x64"), None).is_ok());
+        assert!(checked("eth_getLogs", range("0x1", "0x65"), None).is_err());
+        assert!(checked("trace_filter", range("earliest", "0x64"), None).is_err());
+        assert!(checked("eth_getLogs", range("0x3e8", "latest"), Some(0x44b)).is_ok());
+        assert!(checked("eth_getLogs", range("0x3e8", "latest"), Some(0x44c)).is_err());
+        assert!(checked("eth_getLogs", json!({"fromBlock": "0x3e8"}), Some(0x44c)).is_err());
+        assert!(checked("eth_getLogs", json!({}), Some(0x44c)).is_ok());
+    }
+
+    #[test]
+    fn open_range_is_rejected_without_head() {
+        assert!(checked("eth_getLogs", json!({}), None).is_err());
+        assert!(checked("eth_getLogs", json!({"fromBlock": "0x1"}), None).is_err());
+        let range = json!({"fromBlock": "0x1", "toBlock": "latest"});
+        assert!(checked("trace_filter", range, None).is_err());
+    }
+
+    #[test]
+    fn block_hash_stands_for_one_block() {
+        let filter = json!({"blockHash": "0xab"});
+        assert!(checked("eth_getLogs", filter, None).is_ok());
+    }
+
+    #[test]
+    fn new_filter_is_checked() {
+        let filter = json!({"fromBlock": "0x0", "toBlock": "latest"});
+        assert!(checked("eth_newFilter", filter, Some(0x64)).is_err());
+        let filter = json!({"fromBlock": "0x64", "address": ["0x01", "0x02", "0x03"]});
+        assert!(checked("eth_newFilter", filter, Some(0x64)).is_err());
+    }
+
+    #[test]
+    fn addresses_and_nested_topics_are_counted() {
+        let filter = json!({"blockHash": "0xab", "address": ["0x01", "0x02"]});
+        assert!(checked("eth_getLogs", filter, None).is_ok());
+        let filter = json!({"blockHash": "0xab", "address": ["0x01", "0x02", "0x03"]});
+        assert!(checked("eth_getLogs", filter, None).is_err());
+        let filter =
+            json!({"blockHash": "0xab", "fromAddress": ["0x01"], "toAddress": ["0x02", "0x03"]});
+        assert!(checked("trace_filter", filter, None).is_err());
+        let filter = json!({"blockHash": "0xab", "topics": ["0xa", null, ["0xb", "0xc"]]});
+        assert!(checked("eth_getLogs", filter, None).is_ok());
+        let filter = json!({"blockHash": "0xab", "topics": [["0xa", "0xb"], null, ["0xc", "0xd"]]});
+        assert!(checked("eth_getLogs", filter, None).is_err());
+    }
+
+    fn response(body: &'static [u8], sized: bool) -> http_types::Response {
+        let mut res = http_types::Response::new(200);
+        let len = sized.then_some(body.len());
+        res.set_body(Body::from_reader(async_std::io::Cursor::new(body), len));
+        res
+    }
+
+    #[async_std::test]
+    async fn body_is_read_up_to_the_limit() {
+        let body = "{\"result\":\"ÿ\"}".as_bytes();
+        let read = read_body(&mut response(body, false), Some(body.len() as u64)).await;
+        assert_eq!(read.unwrap(), "{\"result\":\"ÿ\"}");
+        let read = read_body(&mut response(body, false), None).await;
+        assert!(read.is_ok());
+        for sized in [true, false] {
+            let read = read_body(&mut response(body, sized), Some(body.len() as u64 - 1)).await;
+            assert!(matches!(read, Err(BodyError::Exceeded)));
+        }
+        let read = read_body(&mut response(b"{\"result\":\"\xff\"}", false), None).await;
+        assert!(matches!(read, Err(BodyError::Failed(_))));
+    }
+}
@@ -1,19 +1,68 @@
+pub mod admin;
 pub mod api;
 pub mod args;
+pub mod balancer;
+pub mod batch;
+pub mod cache;
+pub mod coalesce;
+pub mod filters;
+pub mod head;
+pub mod health;
+pub mod limits;
+pub mod metrics;
+pub mod otlp;
+pub mod redact;
+pub mod reload;
+pub mod router;
+pub mod subscriptions;
 pub mod telemetry;
+#[cfg(test)]
+mod tests;
+pub mod upstream;
+pub mod usage;
+pub mod ws;
 
+use admin::AdminState;
+use balancer::Balancer;
+use cache::{Cache, CacheMode, Policy};
+use coalesce::Coalescer;
+use filters::Filters;
+use health::Health;
 use http_types::headers::HeaderValue;
-use jsonrpc_proto::redis::{AppStorage, RpcKeyStorage};
-use jsonrpc_proto::Application;
-use std::sync::{Arc, Mutex};
+use jsonrpc_proto::redis::{
+    AppStorage, AsyncRpcKeyStorage, AsyncUsageStorage, CacheStorage, FilterStorage, QuotaStorage,
+    RedisPool,
+};
+use jsonrpc_proto::KeyHasher;
+use metrics::Metrics;
+use otlp::Tracer;
+use router::Router;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use subscriptions::Subscriptions;
 use tide::security::{CorsMiddleware, Origin};
-use tracing::info;
+use tracing::{error, info};
+use upstream::Upstreams;
 
 #[derive(Clone)]
 pub struct State {
-    default_app: Application,
-    apps: Arc<Mutex<AppStorage>>,
-    rpckeys: Arc<Mutex<RpcKeyStorage>>,
+    router: Arc<RwLock<Router>>,
+    rpckeys: AsyncRpcKeyStorage,
+    hasher: KeyHasher,
+    quotas: QuotaStorage,
+    usage: AsyncUsageStorage,
+    cache: Arc<Cache>,
+    coalescer: Arc<Coalescer>,
+    filters: Arc<Filters>,
+    subscriptions: Arc<Subscriptions>,
+    upstreams: Arc<Upstreams>,
+    balancer: Arc<Balancer>,
+    health: Arc<Health>,
+    metrics: Arc<Metrics>,
+    tracer: Tracer,
+    max_batch: usize,
+    // how often websocket sessions check their key again
+    key_interval: Duration,
 }
 
 #[async_std::main]
@@ -24,18 +73,103 @@ async fn main() -> anyhow::Result<()> {
     };
     let conn = args.get_redis_connection();
     let mut apps = AppStorage::from_redis(&conn).expect("apps storage init error");
-    let rpckeys = RpcKeyStorage::from_redis(&conn).expect("key storage init error");
-    let state = State {
-        default_app: apps
+    let pool = RedisPool::from_redis(&conn, args.redis_pool_size)
+        .await
+        .expect("redis pool init error");
+    let default_app = if args.application.is_empty() {
+        None
+    } else {
+        let app = apps
             .get(&args.application)
-            .expect("APPLICATION not configured"),
-        apps: Arc::new(Mutex::new(apps)),
-        rpckeys: Arc::new(Mutex::new(rpckeys)),
+            .expect("apps storage error")
+            .expect("APPLICATION not configured");
+        info!("Using default gateway for {:?}", app);
+        if !app.active {
+            panic!("Application is not active")
+        }
+        Some(app.slug)
     };
-    info!("Using default gateway for {:?}", state.default_app);
-    if !state.default_app.active {
-        panic!("Application is not active")
+    let slugs = apps.scan().expect("apps storage error");
+    let all = slugs
+        .iter()
+        .filter_map(|slug| apps.get(slug).expect("apps storage error"))
+        .collect();
+    let router = Router::new(all, default_app);
+    for app in router.apps() {
+        info!("Serving {} {:?}", app.slug, app.proxy);
     }
+    let policy = Policy {
+        head_ttl: args.cache_head_ttl,
+        confirmations: args.cache_confirmations,
+    };
+    let cache = Arc::new(match args.cache {
+        CacheMode::Off => Cache::off(),
+        CacheMode::Memory => Cache::memory(policy, args.cache_size),
+        CacheMode::Redis => Cache::redis(policy, CacheStorage::new(pool.clone())),
+    });
+    info!("Caching results: {}", args.cache);
+    let router = Arc::new(RwLock::new(router));
+    let subscriptions = Arc::new(Subscriptions::new());
+    let reloaded = reload::Reloaded {
+        router: router.clone(),
+        cache: cache.clone(),
+        subscriptions: subscriptions.clone(),
+    };
+    reload::spawn(args.get_redis_connection(), reloaded);
+    let upstreams = Arc::new(Upstreams::new());
+    let health = Arc::new(Health::new());
+    health::spawn(
+        router.clone(),
+        upstreams.clone(),
+        health.clone(),
+        Duration::from_secs(args.health_interval),
+        Duration::from_secs(args.health_timeout),
+        args.health_max_lag,
+    );
+    let metrics = Arc::new(Metrics::new());
+    let tracer = if args.otlp_endpoint.is_empty() {
+        Tracer::disabled()
+    } else {
+        info!("Exporting traces to {}", &args.otlp_endpoint);
+        Tracer::spawn(
+            &args.otlp_endpoint,
+            &args.otlp_service,
+            args.otlp_sample_ratio,
+        )
+        .expect("invalid OTLP endpoint")
+    };
+    let state = State {
+        router,
+        rpckeys: AsyncRpcKeyStorage::new(pool.clone()),
+        hasher: KeyHasher::new(&args.key_pepper),
+        quotas: QuotaStorage::new(pool.clone()),
+        usage: AsyncUsageStorage::new(pool.clone()),
+        cache,
+        coalescer: Arc::new(Coalescer::new()),
+        filters: Arc::new(Filters::new(
+            FilterStorage::new(pool),
+            Duration::from_secs(args.filter_idle_timeout),
+        )),
+        subscriptions,
+        upstreams,
+        balancer: Arc::new(Balancer::new()),
+        health: health.clone(),
+        metrics: metrics.clone(),
+        tracer,
+        max_batch: args.max_batch_size,
+        key_interval: Duration::from_secs(args.ws_key_interval),
+    };
+
+    let mut admin = tide::with_state(AdminState { health, metrics });
+    admin.at("/health").get(admin::health);
+    admin.at("/metrics").get(admin::metrics);
+    info!("Starting admin server {}", &args.admin_addr);
+    let admin_addr = args.admin_addr.clone();
+    async_std::task::spawn(async move {
+        if let Err(e) = admin.listen(&admin_addr).await {
+            error!("admin server error {}", e);
+        }
+    });
 
     let mut app = tide::with_state(state);
     app.with(telemetry::TraceMiddleware::new());
@@ -43,11 +177,16 @@ async fn main() -> anyhow::Result<()> {
         CorsMiddleware::new()
             .allow_methods("POST, OPTIONS".parse::<HeaderValue>().unwrap())
             .allow_origin(Origin::from("*"))
+            .expose_headers(
+                "X-RateLimit-Limit, X-RateLimit-Remaining, X-RateLimit-Reset, Retry-After"
+                    .parse::<HeaderValue>()
+                    .unwrap(),
+            )
             .allow_credentials(false),
     );
     info!("Starting HTTP GW server {}", &args.addr);
-    app.at("/*").post(api::proxy_rpc);
-    app.at("/").post(api::proxy_rpc);
+    app.at("/*").post(api::proxy_rpc).get(ws::proxy_ws);
+    app.at("/").post(api::proxy_rpc).get(ws::proxy_ws);
     app.listen(&args.addr).await?;
     Ok(())
 }
@@ -0,0 +1,318 @@
+use crate::redact;
+use jsonrpc_proto::{Application, KNOWN_METHODS, OTHER_METHOD};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tide::StatusCode;
+
+// latency buckets in seconds
+const BUCKETS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+/// Method label of the request. Methods are chosen by the clients, so the names
+/// that neither the standard nor the application knows are counted together
+/// to keep the number of series bounded
+pub fn method_label<'a>(app: Option<&Application>, method: &'a str) -> &'a str {
+    match app {
+        Some(app) => app.method_label(method),
+        None if KNOWN_METHODS.contains(&method) => method,
+        None => OTHER_METHOD,
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn render_labels(labels: &[(&str, &str)]) -> String {
+    labels
+        .iter()
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+struct Counter {
+    name: &'static str,
+    help: &'static str,
+    values: Mutex<BTreeMap<String, u64>>,
+}
+
+impl Counter {
+    fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn inc(&self, labels: &[(&str, &str)]) {
+        let mut values = self.values.lock().expect("lock error");
+        *values.entry(render_labels(labels)).or_default() += 1;
+    }
+
+    fn render(&self, out: &mut String) {
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help);
+        let _ = writeln!(out, "# TYPE {} counter", self.name);
+        for (labels, value) in self.values.lock().expect("lock error").iter() {
+            let _ = writeln!(out, "{}{{{}}} {}", self.name, labels, value);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Buckets {
+    counts: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+struct Histogram {
+    name: &'static str,
+    help: &'static str,
+    values: Mutex<BTreeMap<String, Buckets>>,
+}
+
+impl Histogram {
+    fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn observe(&self, labels: &[(&str, &str)], value: f64) {
+        let mut values = self.values.lock().expect("lock error");
+        let buckets = values.entry(render_labels(labels)).or_default();
+        for (i, le) in BUCKETS.iter().enumerate() {
+            if value <= *le {
+                buckets.counts[i] += 1;
+            }
+        }
+        buckets.sum += value;
+        buckets.count += 1;
+    }
+
+    fn render(&self, out: &mut String) {
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help);
+        let _ = writeln!(out, "# TYPE {} histogram", self.name);
+        for (labels, buckets) in self.values.lock().expect("lock error").iter() {
+            for (le, count) in BUCKETS.iter().zip(buckets.counts.iter()) {
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{{{},le=\"{}\"}} {}",
+                    self.name, labels, le, count
+                );
+            }
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{},le=\"+Inf\"}} {}",
+                self.name, labels, buckets.count
+            );
+            let _ = writeln!(out, "{}_sum{{{}}} {}", self.name, labels, buckets.sum);
+            let _ = writeln!(out, "{}_count{{{}}} {}", self.name, labels, buckets.count);
+        }
+    }
+}
+
+/// Labels of the request, filled in while it is served
+pub struct RequestLabels {
+    pub app: String,
+    /// set with `method_label` or `batch`
+    pub method: String,
+    pub upstream: String,
+}
+
+impl Default for RequestLabels {
+    fn default() -> Self {
+        Self {
+            app: String::new(),
+            method: String::new(),
+            upstream: "none".to_owned(),
+        }
+    }
+}
+
+/// Decrements the number of requests in flight when dropped
+pub struct InFlight {
+    metrics: Arc<Metrics>,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.metrics.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Gateway metrics in Prometheus text format
+pub struct Metrics {
+    requests: Counter,
+    duration: Histogram,
+    auth_failures: Counter,
+    quota_rejections: Counter,
+    upstream_errors: Counter,
+    cache_lookups: Counter,
+    coalesced: Counter,
+    in_flight: AtomicI64,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            requests: Counter::new("jsonrpc_requests_total", "Requests served by the gateway"),
+            duration: Histogram::new(
+                "jsonrpc_request_duration_seconds",
+                "Time to serve the request, without streaming the response body",
+            ),
+            auth_failures: Counter::new(
+                "jsonrpc_auth_failures_total",
+                "Requests and calls denied because of the key",
+            ),
+            quota_rejections: Counter::new(
+                "jsonrpc_quota_rejections_total",
+                "Requests rejected by the key quotas",
+            ),
+            upstream_errors: Counter::new(
+                "jsonrpc_upstream_errors_total",
+                "Failed attempts to get the response from upstream",
+            ),
+            cache_lookups: Counter::new(
+                "jsonrpc_cache_lookups_total",
+                "Cacheable calls looked up in the response cache",
+            ),
+            coalesced: Counter::new(
+                "jsonrpc_coalesced_calls_total",
+                "Calls answered by the upstream call of an identical call in flight",
+            ),
+            in_flight: AtomicI64::new(0),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn in_flight(self: &Arc<Self>) -> InFlight {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight {
+            metrics: self.clone(),
+        }
+    }
+
+    pub fn observe(&self, req: &RequestLabels, status: StatusCode, elapsed: Duration) {
+        let status = format!("{}xx", status as u16 / 100);
+        let upstream = redact::path(&req.upstream);
+        let labels = [
+            ("app", req.app.as_str()),
+            ("method", req.method.as_str()),
+            ("status", status.as_str()),
+            ("upstream", upstream.as_str()),
+        ];
+        self.requests.inc(&labels);
+        self.duration.observe(&labels, elapsed.as_secs_f64());
+    }
+
+    pub fn auth_failure(&self, app: &str, reason: &str) {
+        self.auth_failures.inc(&[("app", app), ("reason", reason)]);
+    }
+
+    pub fn quota_rejection(&self, app: &str, window: &str) {
+        self.quota_rejections
+            .inc(&[("app", app), ("window", window)]);
+    }
+
+    /// `kind` is either `connection` or `status`
+    pub fn upstream_error(&self, app: &str, upstream: &str, kind: &str) {
+        let upstream = redact::path(upstream);
+        self.upstream_errors.inc(&[
+            ("app", app),
+            ("upstream", upstream.as_str()),
+            ("kind", kind),
+        ]);
+    }
+
+    pub fn cache_lookup(&self, app: &Application, method: &str, hit: bool) {
+        let result = if hit { "hit" } else { "miss" };
+        self.cache_lookups.inc(&[
+            ("app", app.slug.as_str()),
+            ("method", app.method_label(method)),
+            ("result", result),
+        ]);
+    }
+
+    pub fn coalesced(&self, app: &Application, method: &str) {
+        self.coalesced.inc(&[
+            ("app", app.slug.as_str()),
+            ("method", app.method_label(method)),
+        ]);
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        self.requests.render(&mut out);
+        self.duration.render(&mut out);
+        self.auth_failures.render(&mut out);
+        self.quota_rejections.render(&mut out);
+        self.upstream_errors.render(&mut out);
+        self.cache_lookups.render(&mut out);
+        self.coalesced.render(&mut out);
+        let _ = writeln!(
+            out,
+            "# HELP jsonrpc_requests_in_flight Requests being served\n\
+             # TYPE jsonrpc_requests_in_flight gauge\n\
+             jsonrpc_requests_in_flight {}",
+            self.in_flight.load(Ordering::Relaxed)
+        );
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn app() -> Application {
+        let mut app = Application::new(
+            "test",
+            None,
+            "/".to_owned(),
+            "http://node".to_owned(),
+            false,
+            vec![],
+        );
+        app.costs.insert("bor_getAuthor".to_owned(), 2);
+        app
+    }
+
+    #[test]
+    fn unknown_methods_share_one_series() {
+        assert_eq!(method_label(None, "eth_call"), "eth_call");
+        assert_eq!(method_label(None, "bor_getAuthor"), OTHER_METHOD);
+        assert_eq!(method_label(Some(&app()), "bor_getAuthor"), "bor_getAuthor");
+        let metrics = Metrics::new();
+        for method in ["x_1", "x_2", "x_3"] {
+            metrics.coalesced(&app(), method);
+        }
+        let out = metrics.render();
+        assert!(out.contains(r#"jsonrpc_coalesced_calls_total{app="test",method="other"} 3"#));
+        assert!(!out.contains("x_1"));
+    }
+
+    #[test]
+    fn auth_failures_are_counted_by_reason() {
+        let metrics = Metrics::new();
+        metrics.auth_failure("test", "expired");
+        let out = metrics.render();
+        assert!(out.contains(r#"jsonrpc_auth_failures_total{app="test",reason="expired"} 1"#));
+    }
+}
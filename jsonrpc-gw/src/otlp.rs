@@ -0,0 +1,328 @@
+use async_std::channel::{bounded, Receiver, Sender};
+use async_std::future::timeout;
+use http_client::h1::H1Client;
+use http_client::HttpClient;
+use http_types::{Method, Request, Url};
+use rand::Rng;
+use serde_json::{json, Value};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+// spans are dropped when the collector cannot keep up
+const QUEUE_SIZE: usize = 4096;
+const EXPORT_BATCH: usize = 512;
+const EXPORT_INTERVAL: Duration = Duration::from_secs(1);
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub const KIND_INTERNAL: u8 = 1;
+pub const KIND_SERVER: u8 = 2;
+pub const KIND_CLIENT: u8 = 3;
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// odd length fails on the last pair
+fn unhex(value: &str) -> Option<Vec<u8>> {
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(value.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn now_nanos() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_nanos()
+}
+
+/// W3C trace context of the span
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpanContext {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub sampled: bool,
+}
+
+impl SpanContext {
+    /// Parses `traceparent` header, such as `00-{trace id}-{span id}-01`
+    pub fn from_traceparent(value: &str) -> Option<Self> {
+        let parts: Vec<&str> = value.trim().split('-').collect();
+        if parts.len() < 4 || parts[0].len() != 2 || parts[0] == "ff" {
+            return None;
+        }
+        let mut trace_id = [0u8; 16];
+        let mut span_id = [0u8; 8];
+        let flags = unhex(parts[3])?;
+        trace_id.copy_from_slice(&unhex(parts[1]).filter(|x| x.len() == 16)?);
+        span_id.copy_from_slice(&unhex(parts[2]).filter(|x| x.len() == 8)?);
+        if trace_id == [0; 16] || span_id == [0; 8] || flags.len() != 1 {
+            return None;
+        }
+        Some(Self {
+            trace_id,
+            span_id,
+            sampled: flags[0] & 1 == 1,
+        })
+    }
+
+    // new trace, sampled by its id so the decision is the same for every span of it
+    fn root(ratio: f64) -> Self {
+        let mut rng = rand::thread_rng();
+        let trace_id: [u8; 16] = rng.gen();
+        let mut low = [0u8; 8];
+        low.copy_from_slice(&trace_id[8..]);
+        Self {
+            trace_id,
+            span_id: rng.gen(),
+            sampled: (u64::from_be_bytes(low) as f64) < ratio * u64::MAX as f64,
+        }
+    }
+
+    // next span of the same trace
+    fn child(&self) -> Self {
+        Self {
+            trace_id: self.trace_id,
+            span_id: rand::thread_rng().gen(),
+            sampled: self.sampled,
+        }
+    }
+
+    pub fn traceparent(&self) -> String {
+        format!(
+            "00-{}-{}-{:02x}",
+            hex(&self.trace_id),
+            hex(&self.span_id),
+            self.sampled as u8
+        )
+    }
+}
+
+/// Span that is sent to the collector when it is dropped
+pub struct Span {
+    tx: Option<Sender<Value>>,
+    context: SpanContext,
+    parent: Option<[u8; 8]>,
+    name: String,
+    kind: u8,
+    start: u128,
+    attributes: Vec<Value>,
+    error: Option<String>,
+}
+
+impl Span {
+    fn new(
+        tx: Option<Sender<Value>>,
+        context: SpanContext,
+        parent: Option<&SpanContext>,
+        name: &str,
+        kind: u8,
+    ) -> Self {
+        Self {
+            tx: tx.filter(|_| context.sampled),
+            context,
+            parent: parent.map(|x| x.span_id),
+            name: name.to_owned(),
+            kind,
+            start: now_nanos(),
+            attributes: vec![],
+            error: None,
+        }
+    }
+
+    pub fn context(&self) -> &SpanContext {
+        &self.context
+    }
+
+    pub fn child(&self, name: &str, kind: u8) -> Self {
+        let context = self.context.child();
+        Self::new(self.tx.clone(), context, Some(&self.context), name, kind)
+    }
+
+    pub fn set_str(&mut self, key: &str, value: &str) {
+        if self.tx.is_some() {
+            let value = json!({ "stringValue": value });
+            self.attributes.push(json!({ "key": key, "value": value }));
+        }
+    }
+
+    pub fn set_int(&mut self, key: &str, value: i64) {
+        if self.tx.is_some() {
+            let value = json!({ "intValue": value.to_string() });
+            self.attributes.push(json!({ "key": key, "value": value }));
+        }
+    }
+
+    pub fn set_error(&mut self, message: &str) {
+        self.error = Some(message.to_owned());
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let tx = match self.tx.take() {
+            Some(x) => x,
+            None => return,
+        };
+        let mut span = json!({
+            "traceId": hex(&self.context.trace_id),
+            "spanId": hex(&self.context.span_id),
+            "name": self.name,
+            "kind": self.kind,
+            "startTimeUnixNano": self.start.to_string(),
+            "endTimeUnixNano": now_nanos().to_string(),
+            "attributes": std::mem::take(&mut self.attributes),
+        });
+        if let Some(parent) = self.parent {
+            span["parentSpanId"] = json!(hex(&parent));
+        }
+        if let Some(message) = &self.error {
+            span["status"] = json!({ "code": 2, "message": message });
+        }
+        let _ = tx.try_send(span);
+    }
+}
+
+/// Creates spans and exports them in background to OTLP/HTTP collector,
+/// does nothing when the collector is not configured
+#[derive(Clone)]
+pub struct Tracer {
+    tx: Option<Sender<Value>>,
+    ratio: f64,
+}
+
+impl Tracer {
+    pub fn disabled() -> Self {
+        Self {
+            tx: None,
+            ratio: 0.0,
+        }
+    }
+
+    /// Exports spans to `{endpoint}/v1/traces` in JSON encoding.
+    /// `ratio` of the new traces is sampled, traces of the callers keep their decision
+    pub fn spawn(endpoint: &str, service: &str, ratio: f64) -> anyhow::Result<Self> {
+        let url = Url::parse(&format!("{}/v1/traces", endpoint.trim_end_matches('/')))?;
+        let (tx, rx) = bounded(QUEUE_SIZE);
+        let resource = json!({
+            "attributes": [{ "key": "service.name", "value": { "stringValue": service } }]
+        });
+        async_std::task::spawn(export(rx, url, resource));
+        Ok(Self {
+            tx: Some(tx),
+            ratio,
+        })
+    }
+
+    /// Root span of the incoming request, continuing the trace of the caller if any
+    pub fn start(&self, parent: Option<&SpanContext>, name: &str, kind: u8) -> Span {
+        let context = match parent {
+            Some(x) => x.child(),
+            None => SpanContext::root(self.ratio),
+        };
+        Span::new(self.tx.clone(), context, parent, name, kind)
+    }
+}
+
+async fn export(rx: Receiver<Value>, url: Url, resource: Value) {
+    let client = H1Client::new();
+    while let Ok(first) = rx.recv().await {
+        async_std::task::sleep(EXPORT_INTERVAL).await;
+        let mut spans = vec![first];
+        while spans.len() < EXPORT_BATCH {
+            match rx.try_recv() {
+                Ok(x) => spans.push(x),
+                Err(_) => break,
+            }
+        }
+        let body = json!({
+            "resourceSpans": [{
+                "resource": resource,
+                "scopeSpans": [{ "scope": { "name": "jsonrpc-gw" }, "spans": spans }]
+            }]
+        });
+        let mut req = Request::new(Method::Post, url.clone());
+        req.insert_header("Content-Type", "application/json");
+        req.set_body(body.to_string());
+        match timeout(EXPORT_TIMEOUT, client.send(req)).await {
+            Ok(Ok(res)) if !res.status().is_success() => {
+                warn!("trace export failed with {}", res.status())
+            }
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => warn!("trace export error: {}", e),
+            Err(_) => warn!("trace export timeout"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn traceparent_round_trip() {
+        let ctx = SpanContext::from_traceparent(TRACEPARENT).unwrap();
+        assert_eq!(hex(&ctx.trace_id), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(hex(&ctx.span_id), "00f067aa0ba902b7");
+        assert!(ctx.sampled);
+        assert_eq!(ctx.traceparent(), TRACEPARENT);
+        let ctx = SpanContext::from_traceparent(&TRACEPARENT.replace("-01", "-00")).unwrap();
+        assert!(!ctx.sampled);
+    }
+
+    #[test]
+    fn future_versions_may_add_fields() {
+        let value = format!(" 01{}-extra ", &TRACEPARENT[2..]);
+        assert!(SpanContext::from_traceparent(&value).is_some());
+    }
+
+    #[test]
+    fn invalid_traceparent_is_ignored() {
+        for value in [
+            "",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "0-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e473x-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-1",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-0101",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902é-01",
+        ] {
+            assert!(SpanContext::from_traceparent(value).is_none(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn new_traces_follow_the_ratio() {
+        assert!((0..100).all(|_| SpanContext::root(1.0).sampled));
+        assert!((0..100).all(|_| !SpanContext::root(0.0).sampled));
+        let sampled = (0..10000)
+            .filter(|_| SpanContext::root(0.5).sampled)
+            .count();
+        assert!((4000..6000).contains(&sampled), "{}", sampled);
+    }
+
+    #[test]
+    fn callers_keep_their_decision() {
+        let (tx, _rx) = bounded(1);
+        let tracer = Tracer {
+            tx: Some(tx),
+            ratio: 0.0,
+        };
+        let parent = SpanContext::from_traceparent(TRACEPARENT).unwrap();
+        let span = tracer.start(Some(&parent), "test", KIND_SERVER);
+        assert!(span.context().sampled);
+        assert_eq!(span.context().trace_id, parent.trace_id);
+        assert_eq!(span.parent, Some(parent.span_id));
+        let child = span.child("child", KIND_CLIENT);
+        assert_eq!(child.context().trace_id, parent.trace_id);
+        assert!(!tracer.start(None, "test", KIND_SERVER).context().sampled);
+    }
+}
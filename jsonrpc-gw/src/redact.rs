@@ -0,0 +1,30 @@
+/// Keys with this tag get their payloads logged at info level
+pub const DEBUG_TAG: &str = "debug";
+
+// segments shorter than that are routes, not keys
+const KEY_MIN_LEN: usize = 16;
+const KEY_PREFIX_LEN: usize = 4;
+const HASH_PREFIX_LEN: usize = 12;
+
+/// Short prefix of the key hash, enough to find the key in `jsonrpc-key list`
+pub fn key_hash(hash: &str) -> &str {
+    &hash[..hash.len().min(HASH_PREFIX_LEN)]
+}
+
+fn looks_like_key(segment: &str) -> bool {
+    segment.len() >= KEY_MIN_LEN && segment.bytes().all(|b| b.is_ascii_alphanumeric())
+}
+
+/// Masks path segments that look like keys, keeping their short prefix
+pub fn path(path: &str) -> String {
+    path.split('/')
+        .map(|segment| {
+            if looks_like_key(segment) {
+                format!("{}***", &segment[..KEY_PREFIX_LEN])
+            } else {
+                segment.to_owned()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
@@ -0,0 +1,186 @@
+use crate::cache::Cache;
+use crate::router::Router;
+use crate::subscriptions::Subscriptions;
+use jsonrpc_proto::redis::{AppChanges, AppStorage, RedisConnection};
+use jsonrpc_proto::Application;
+use std::cell::RefCell;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing::{info, warn};
+
+// cached results of the removed applications and of those served
+// by other nodes now are not valid anymore
+fn clear_changed(cache: &Cache, before: &[Application], after: &[Application]) {
+    for app in before {
+        let changed = match after.iter().find(|x| x.slug == app.slug) {
+            Some(x) => x.proxy != app.proxy || x.upstreams != app.upstreams,
+            None => true,
+        };
+        if changed {
+            info!("clearing cache of application {}", app.slug);
+            cache.clear(&app.slug);
+        }
+    }
+}
+
+/// Gateway state following the applications
+pub struct Reloaded {
+    pub router: Arc<RwLock<Router>>,
+    pub cache: Arc<Cache>,
+    pub subscriptions: Arc<Subscriptions>,
+}
+
+impl Reloaded {
+    // applies the change to the routing table, clears the cache it invalidates
+    // and stops the websocket upstreams that are not used anymore
+    fn update(&self, change: impl FnOnce(&mut Router)) {
+        let mut guard = self.router.write().expect("lock error");
+        let before = guard.apps().to_vec();
+        change(&mut guard);
+        clear_changed(&self.cache, &before, guard.apps());
+        self.subscriptions.retain(guard.apps());
+    }
+}
+
+// reads every application from the storage and replaces the routing table,
+// the table is kept when the storage fails
+fn reload_all(apps: &mut AppStorage, reloaded: &Reloaded) -> anyhow::Result<()> {
+    let mut all = vec![];
+    for slug in apps.scan()? {
+        all.extend(apps.get(&slug)?);
+    }
+    reloaded.update(|x| x.reload(all));
+    let count = reloaded.router.read().expect("lock error").apps().len();
+    info!("reloaded {} active applications", count);
+    Ok(())
+}
+
+fn listen(conn: &RedisConnection, reloaded: &Reloaded) -> anyhow::Result<()> {
+    let apps = RefCell::new(AppStorage::from_redis(conn)?);
+    let mut changes = AppChanges::from_redis(conn)?;
+    changes.listen(
+        || reload_all(&mut apps.borrow_mut(), reloaded),
+        |slug| {
+            match apps.borrow_mut().get(&slug)? {
+                Some(app) => {
+                    info!("application {} updated {:?}", slug, app);
+                    reloaded.update(|x| x.upsert(app));
+                }
+                None => warn!("application {} was announced but not found", slug),
+            }
+            Ok(())
+        },
+    )
+}
+
+/// Keeps the routing table in sync with the applications storage.
+/// Storage errors keep the current table, it is reloaded in full once the subscription is back.
+/// RPC keys are read from the storage on every request and need no reloading
+pub fn spawn(conn: RedisConnection, reloaded: Reloaded) {
+    std::thread::spawn(move || loop {
+        if let Err(e) = listen(&conn, &reloaded) {
+            warn!("application changes subscription lost: {}", e);
+        }
+        std::thread::sleep(Duration::from_secs(1));
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::{Lookup, Policy};
+    use crate::tests::RedisStub;
+    use jsonrpc_proto::jsonrpc::Request;
+    use serde_json::json;
+    use std::sync::atomic::Ordering;
+
+    fn app(slug: &str, url: &str) -> Application {
+        Application::new(
+            slug,
+            None,
+            format!("/{}", slug),
+            url.to_owned(),
+            false,
+            vec![],
+        )
+    }
+
+    fn chain_id() -> Request {
+        serde_json::from_value(json!({"jsonrpc": "2.0", "id": 1, "method": "eth_chainId"})).unwrap()
+    }
+
+    async fn cached(cache: &Cache, slug: &str) -> bool {
+        matches!(
+            cache.lookup(slug, "/", &chain_id(), None).await,
+            Lookup::Hit(_)
+        )
+    }
+
+    fn reloaded(apps: Vec<Application>) -> Reloaded {
+        Reloaded {
+            router: Arc::new(RwLock::new(Router::new(apps, None))),
+            cache: Arc::new(Cache::memory(
+                Policy {
+                    head_ttl: 0,
+                    confirmations: 0,
+                },
+                10,
+            )),
+            subscriptions: Arc::new(Subscriptions::new()),
+        }
+    }
+
+    fn routed(reloaded: &Reloaded) -> Vec<String> {
+        let router = reloaded.router.read().unwrap();
+        let mut slugs: Vec<String> = router.apps().iter().map(|x| x.slug.clone()).collect();
+        slugs.sort();
+        slugs
+    }
+
+    #[async_std::test]
+    async fn changed_applications_are_cleared() {
+        let slugs = ["same", "moved", "renamed", "gone"];
+        let reloaded = reloaded(slugs.iter().map(|x| app(x, "http://a")).collect());
+        let cache = &reloaded.cache;
+        let req = chain_id();
+        for slug in slugs {
+            cache.store(slug, "/", &req, &json!("0x1"), None);
+        }
+        let mut renamed = app("renamed", "http://a");
+        renamed.name = "Renamed".to_owned();
+        let after = vec![app("same", "http://a"), app("moved", "http://b"), renamed];
+        reloaded.update(|x| x.reload(after));
+        assert!(cached(cache, "same").await);
+        assert!(cached(cache, "renamed").await);
+        assert!(!cached(cache, "moved").await);
+        assert!(!cached(cache, "gone").await);
+
+        cache.store("moved", "/", &req, &json!("0x1"), None);
+        let mut inactive = app("moved", "http://b");
+        inactive.active = false;
+        reloaded.update(|x| x.upsert(inactive));
+        assert!(!cached(cache, "moved").await);
+        assert!(cached(cache, "same").await);
+    }
+
+    #[async_std::test]
+    async fn failed_reload_keeps_the_routes() {
+        let (stub, conn) = RedisStub::spawn().await;
+        for slug in ["one", "two"] {
+            stub.set(&format!("app_{}", slug), &app(slug, "http://a"));
+        }
+        let reloaded = reloaded(vec![app("old", "http://a")]);
+        let mut apps = AppStorage::from_redis(&conn).unwrap();
+        reload_all(&mut apps, &reloaded).unwrap();
+        assert_eq!(routed(&reloaded), ["one", "two"]);
+
+        stub.set("app_three", &app("three", "http://a"));
+        stub.down.store(true, Ordering::SeqCst);
+        assert!(reload_all(&mut apps, &reloaded).is_err());
+        assert_eq!(routed(&reloaded), ["one", "two"]);
+
+        stub.down.store(false, Ordering::SeqCst);
+        reload_all(&mut apps, &reloaded).unwrap();
+        assert_eq!(routed(&reloaded), ["one", "three", "two"]);
+    }
+}
@@ -0,0 +1,231 @@
+use jsonrpc_proto::Application;
+
+/// Application matched for the incoming request
+#[derive(Debug, Clone)]
+pub struct Route {
+    pub app: Application,
+    /// path prefix of the application that was matched, without trailing slash
+    pub prefix: String,
+    /// rest of the request path after the prefix
+    pub rest: String,
+}
+
+impl Route {
+    /// Route to the root of the application under its path prefix,
+    /// for the calls made by the gateway itself
+    pub fn root(app: &Application) -> Self {
+        Self {
+            app: app.clone(),
+            prefix: normalize_prefix(&app.proxy.path),
+            rest: String::new(),
+        }
+    }
+
+    /// Upstream URL for the given node and path, which is the rest of the request path
+    /// after the key was taken out of it. The matched prefix is kept
+    /// unless the application proxy is configured to strip it
+    pub fn upstream_url(&self, base: &str, path: &str) -> String {
+        let mut url = base.trim_end_matches('/').to_owned();
+        if !self.app.proxy.strip {
+            url.push_str(&self.prefix);
+        }
+        url.push_str(path.trim_end_matches('/'));
+        url
+    }
+}
+
+/// Resolves applications by Host header or by the path prefix
+#[derive(Debug, Clone)]
+pub struct Router {
+    apps: Vec<Application>,
+    default_app: Option<String>,
+}
+
+fn normalize_prefix(path: &str) -> String {
+    let trimmed = path.trim_matches('/');
+    if trimmed.is_empty() {
+        String::new()
+    } else {
+        format!("/{}", trimmed)
+    }
+}
+
+fn strip_prefix<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    let rest = path.strip_prefix(prefix)?;
+    if rest.is_empty() || rest.starts_with('/') {
+        Some(rest)
+    } else {
+        None
+    }
+}
+
+impl Router {
+    pub fn new(apps: Vec<Application>, default_app: Option<String>) -> Self {
+        Self {
+            apps: apps.into_iter().filter(|a| a.active).collect(),
+            default_app,
+        }
+    }
+
+    /// Replaces the whole set of applications
+    pub fn reload(&mut self, apps: Vec<Application>) {
+        self.apps = apps.into_iter().filter(|a| a.active).collect();
+    }
+
+    /// Adds or replaces application, inactive applications are removed
+    pub fn upsert(&mut self, app: Application) {
+        self.apps.retain(|a| a.slug != app.slug);
+        if app.active {
+            self.apps.push(app);
+        }
+    }
+
+    pub fn apps(&self) -> &[Application] {
+        &self.apps
+    }
+
+    fn route(app: &Application, path: &str) -> Route {
+        let prefix = normalize_prefix(&app.proxy.path);
+        match strip_prefix(path, &prefix) {
+            Some(rest) => Route {
+                app: app.clone(),
+                prefix,
+                rest: rest.to_owned(),
+            },
+            None => Route {
+                app: app.clone(),
+                prefix: String::new(),
+                rest: path.to_owned(),
+            },
+        }
+    }
+
+    /// Finds application for the request: first by Host header,
+    /// then by the longest matching path prefix, then the default application
+    pub fn resolve(&self, host: Option<&str>, path: &str) -> Option<Route> {
+        if let Some(host) = host {
+            let hostname = host.split(':').next().unwrap_or(host);
+            let by_host = self.apps.iter().find(|a| {
+                a.proxy
+                    .hosts
+                    .iter()
+                    .any(|h| h.eq_ignore_ascii_case(hostname))
+            });
+            if let Some(app) = by_host {
+                return Some(Self::route(app, path));
+            }
+        }
+        let by_path = self
+            .apps
+            .iter()
+            .filter(|a| {
+                let prefix = normalize_prefix(&a.proxy.path);
+                !prefix.is_empty() && strip_prefix(path, &prefix).is_some()
+            })
+            .max_by_key(|a| normalize_prefix(&a.proxy.path).len());
+        if let Some(app) = by_path {
+            return Some(Self::route(app, path));
+        }
+        let fallback = match &self.default_app {
+            Some(slug) => self.apps.iter().find(|a| &a.slug == slug),
+            None if self.apps.len() == 1 => self.apps.first(),
+            None => None,
+        };
+        fallback.map(|app| Self::route(app, path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn app(path: &str, strip: bool) -> Application {
+        Application::new(
+            "test",
+            None,
+            path.to_owned(),
+            "http://node".to_owned(),
+            strip,
+            vec![],
+        )
+    }
+
+    #[test]
+    fn root_keeps_prefix_unless_stripped() {
+        let route = Route::root(&app("/main/", false));
+        assert_eq!(route.upstream_url("http://node/", ""), "http://node/main");
+        let route = Route::root(&app("/main", true));
+        assert_eq!(route.upstream_url("http://node", ""), "http://node");
+    }
+
+    #[test]
+    fn rest_of_path_is_appended() {
+        let route = Router::route(&app("/main", false), "/main/v1/");
+        assert_eq!(
+            route.upstream_url("http://node", &route.rest),
+            "http://node/main/v1"
+        );
+    }
+
+    fn app_at(name: &str, path: &str, hosts: &[&str]) -> Application {
+        Application::new(
+            name,
+            None,
+            path.to_owned(),
+            "http://node".to_owned(),
+            false,
+            hosts.iter().map(|h| h.to_string()).collect(),
+        )
+    }
+
+    fn router() -> Router {
+        Router::new(
+            vec![
+                app_at("mainnet", "/", &["mainnet.example.com"]),
+                app_at("xdai", "/xdai", &["xdai.example.com"]),
+                app_at("goerli", "/goerli", &[]),
+            ],
+            None,
+        )
+    }
+
+    fn slug(route: Option<Route>) -> Option<String> {
+        route.map(|r| r.app.slug)
+    }
+
+    #[test]
+    fn host_is_matched_before_path() {
+        let router = router();
+        let route = router.resolve(Some("MAINNET.example.com:8000"), "/v1");
+        assert_eq!(slug(route), Some("mainnet".to_owned()));
+        let route = router.resolve(Some("mainnet.example.com"), "/goerli");
+        assert_eq!(slug(route), Some("mainnet".to_owned()));
+    }
+
+    #[test]
+    fn host_with_prefix_takes_out_the_prefix() {
+        let router = router();
+        let route = router
+            .resolve(Some("xdai.example.com"), "/xdai/v1")
+            .unwrap();
+        assert_eq!(
+            (route.prefix.as_str(), route.rest.as_str()),
+            ("/xdai", "/v1")
+        );
+        // the host alone is enough, the path is kept whole
+        let route = router.resolve(Some("xdai.example.com"), "/v1").unwrap();
+        assert_eq!(route.app.slug, "xdai");
+        assert_eq!((route.prefix.as_str(), route.rest.as_str()), ("", "/v1"));
+    }
+
+    #[test]
+    fn unknown_host_falls_back_to_path() {
+        let router = router();
+        let route = router.resolve(Some("other.example.com"), "/goerli/v1");
+        assert_eq!(slug(route), Some("goerli".to_owned()));
+        assert_eq!(slug(router.resolve(Some("other.example.com"), "/v1")), None);
+        let router = Router::new(router.apps().to_vec(), Some("xdai".to_owned()));
+        let route = router.resolve(Some("other.example.com"), "/v1");
+        assert_eq!(slug(route), Some("xdai".to_owned()));
+    }
+}
@@ -0,0 +1,641 @@
+use async_std::channel::{bounded, Receiver, Sender};
+use async_std::future::timeout;
+use async_tungstenite::async_std::{connect_async, ConnectStream};
+use async_tungstenite::tungstenite::Message;
+use async_tungstenite::WebSocketStream;
+use futures_util::future::{self, Either};
+use futures_util::{SinkExt, StreamExt};
+use jsonrpc_proto::jsonrpc::{self, ErrorObject};
+use jsonrpc_proto::Application;
+use rand::Rng;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+const COMMAND_QUEUE: usize = 1024;
+/// Notifications waiting for the client, the rest is dropped for slow clients
+pub const NOTICE_QUEUE: usize = 1024;
+/// Pause before connecting again and before subscribing again after the upstream error
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Notification for the subscription of the client
+#[derive(Debug, Clone)]
+pub struct Notice {
+    pub subscription: String,
+    pub result: Value,
+}
+
+impl Notice {
+    pub fn to_text(&self) -> String {
+        json!({
+            "jsonrpc": "2.0",
+            "method": "eth_subscription",
+            "params": { "subscription": self.subscription, "result": self.result },
+        })
+        .to_string()
+    }
+}
+
+type Reply = Sender<Result<(), ErrorObject>>;
+
+enum Command {
+    Subscribe {
+        params: Value,
+        id: String,
+        listener: Sender<Notice>,
+        reply: Reply,
+    },
+    Unsubscribe {
+        id: String,
+        reply: Sender<bool>,
+    },
+}
+
+/// Upstream subscription shared by the clients with the same params
+struct Topic {
+    params: Value,
+    upstream_id: Option<String>,
+    clients: HashMap<String, Sender<Notice>>,
+    // clients waiting for the upstream to confirm the subscription
+    waiting: Vec<(String, Reply)>,
+    // the subscribe call is sent and not answered yet
+    subscribing: bool,
+    // when the upstream subscription may be requested again
+    retry_at: Instant,
+}
+
+/// Subscriptions of the clients, one upstream subscription per application,
+/// subscription type and filter, fanned out to every client with its own id
+#[derive(Default)]
+pub struct Subscriptions {
+    // keyed by the application slug and its websocket url
+    apps: Mutex<HashMap<(String, String), Sender<Command>>>,
+}
+
+fn unavailable() -> ErrorObject {
+    ErrorObject::new(jsonrpc::UPSTREAM_UNAVAILABLE, "upstream is not available")
+}
+
+impl Subscriptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // connection of the application, started on the first subscription
+    fn connection(&self, slug: &str, url: &str, connect_timeout: Duration) -> Sender<Command> {
+        let mut apps = self.apps.lock().expect("lock error");
+        apps.entry((slug.to_owned(), url.to_owned()))
+            .or_insert_with(|| {
+                let (tx, rx) = bounded(COMMAND_QUEUE);
+                let connection = Connection {
+                    slug: slug.to_owned(),
+                    url: url.to_owned(),
+                    connect_timeout,
+                    topics: HashMap::new(),
+                    next_id: 0,
+                    pending: HashMap::new(),
+                };
+                async_std::task::spawn(connection.run(rx));
+                tx
+            })
+            .clone()
+    }
+
+    /// Stops the connections to the websocket urls the applications do not have anymore,
+    /// their clients are left without notifications
+    pub fn retain(&self, apps: &[Application]) {
+        let mut connections = self.apps.lock().expect("lock error");
+        connections.retain(|(slug, url), _| {
+            let live = apps
+                .iter()
+                .any(|a| &a.slug == slug && a.ws_url.as_ref() == Some(url));
+            if !live {
+                info!(
+                    "app = {} websocket upstream {} is not used anymore",
+                    slug, url
+                );
+            }
+            live
+        });
+    }
+
+    /// Subscribes the client, returns the subscription id given to the client
+    pub async fn subscribe(
+        &self,
+        slug: &str,
+        url: &str,
+        connect_timeout: Duration,
+        params: Value,
+        listener: Sender<Notice>,
+    ) -> Result<String, ErrorObject> {
+        let id = format!("0x{:032x}", rand::thread_rng().gen::<u128>());
+        let (reply, rx) = bounded(1);
+        let command = Command::Subscribe {
+            params,
+            id: id.clone(),
+            listener,
+            reply,
+        };
+        let connection = self.connection(slug, url, connect_timeout);
+        if connection.send(command).await.is_err() {
+            return Err(unavailable());
+        }
+        rx.recv().await.unwrap_or_else(|_| Err(unavailable()))?;
+        Ok(id)
+    }
+
+    /// Cancels the subscription of the client, stopped connections are not started again
+    pub async fn unsubscribe(&self, slug: &str, url: &str, id: &str) -> bool {
+        let key = (slug.to_owned(), url.to_owned());
+        let connection = match self.apps.lock().expect("lock error").get(&key) {
+            Some(x) => x.clone(),
+            None => return false,
+        };
+        let (reply, rx) = bounded(1);
+        let command = Command::Unsubscribe {
+            id: id.to_owned(),
+            reply,
+        };
+        if connection.send(command).await.is_err() {
+            return false;
+        }
+        rx.recv().await.unwrap_or(false)
+    }
+}
+
+// calls of the connection sent to the upstream
+enum Pending {
+    Subscribe(String),
+    Unsubscribe,
+}
+
+type Upstream = WebSocketStream<ConnectStream>;
+
+// what the connection task is woken up by
+enum Event {
+    Command(Option<Command>),
+    Message(Option<Result<Message, async_tungstenite::tungstenite::Error>>),
+    // time to retry the subscriptions that failed
+    Retry,
+}
+
+/// Upstream WebSocket of the application, connected while there are subscriptions
+struct Connection {
+    slug: String,
+    url: String,
+    connect_timeout: Duration,
+    // keyed by the params of the subscription
+    topics: HashMap<String, Topic>,
+    next_id: u64,
+    pending: HashMap<u64, Pending>,
+}
+
+impl Connection {
+    async fn run(mut self, rx: Receiver<Command>) {
+        let mut upstream: Option<Upstream> = None;
+        loop {
+            if upstream.is_none() && !self.topics.is_empty() {
+                upstream = self.connect().await;
+            }
+            let event = match upstream.as_mut() {
+                Some(ws) => {
+                    let next = future::select(Box::pin(rx.recv()), ws.next());
+                    match timeout(RETRY_INTERVAL, next).await {
+                        Ok(Either::Left((command, _))) => Event::Command(command.ok()),
+                        Ok(Either::Right((message, _))) => Event::Message(message),
+                        Err(_) => Event::Retry,
+                    }
+                }
+                // commands still come while the upstream is away
+                None if self.topics.is_empty() => Event::Command(rx.recv().await.ok()),
+                None => match timeout(RETRY_INTERVAL, rx.recv()).await {
+                    Ok(command) => Event::Command(command.ok()),
+                    Err(_) => continue,
+                },
+            };
+            let mut calls = match event {
+                Event::Command(Some(command)) => self.command(command),
+                // the gateway is shutting down or the application is gone
+                Event::Command(None) => {
+                    if let Some(mut ws) = upstream.take() {
+                        let _ = ws.close(None).await;
+                    }
+                    return;
+                }
+                Event::Message(Some(Ok(Message::Text(text)))) => self.message(&text),
+                Event::Message(Some(Ok(Message::Close(_)))) | Event::Message(Some(Err(_))) => {
+                    upstream = self.disconnected();
+                    vec![]
+                }
+                Event::Message(Some(Ok(_))) | Event::Retry => vec![],
+                Event::Message(None) => {
+                    upstream = self.disconnected();
+                    vec![]
+                }
+            };
+            if let Some(ws) = upstream.as_mut() {
+                calls.extend(self.resubscribe());
+                if !self.send(ws, calls).await {
+                    upstream = self.disconnected();
+                }
+            }
+            // nothing to listen to, the upstream is not kept open
+            if self.topics.is_empty() {
+                if let Some(mut ws) = upstream.take() {
+                    info!("app = {} no subscriptions left, disconnecting", self.slug);
+                    let _ = ws.close(None).await;
+                    self.pending.clear();
+                }
+            }
+        }
+    }
+
+    // connects and subscribes every topic again
+    async fn connect(&mut self) -> Option<Upstream> {
+        let connect = timeout(self.connect_timeout, connect_async(self.url.as_str()));
+        let connected = match connect.await {
+            Ok(Ok((x, _))) => Ok(x),
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(_) => Err("connect timed out".to_owned()),
+        };
+        let mut ws = match connected {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("app = {} upstream websocket error: {}", self.slug, e);
+                // clients do not wait for the upstream that is away
+                for topic in self.topics.values_mut() {
+                    for (id, reply) in topic.waiting.drain(..) {
+                        topic.clients.remove(&id);
+                        let _ = reply.try_send(Err(unavailable()));
+                    }
+                }
+                self.topics.retain(|_, t| !t.clients.is_empty());
+                return None;
+            }
+        };
+        info!(
+            "app = {} upstream websocket connected, {} subscriptions",
+            self.slug,
+            self.topics.len()
+        );
+        let calls = self.resubscribe();
+        if self.send(&mut ws, calls).await {
+            Some(ws)
+        } else {
+            self.disconnected()
+        }
+    }
+
+    // upstream subscriptions are gone with the connection, clients stay
+    fn disconnected(&mut self) -> Option<Upstream> {
+        warn!("app = {} upstream websocket disconnected", self.slug);
+        self.pending.clear();
+        let now = Instant::now();
+        for topic in self.topics.values_mut() {
+            topic.upstream_id = None;
+            topic.subscribing = false;
+            topic.retry_at = now;
+        }
+        None
+    }
+
+    fn call(&mut self, method: &str, params: Value, pending: Pending) -> String {
+        self.next_id += 1;
+        self.pending.insert(self.next_id, pending);
+        json!({ "jsonrpc": "2.0", "id": self.next_id, "method": method, "params": params })
+            .to_string()
+    }
+
+    fn subscribe_call(&mut self, key: String) -> String {
+        let topic = self.topics.get_mut(&key).expect("topic exists");
+        topic.subscribing = true;
+        let params = topic.params.clone();
+        self.call("eth_subscribe", params, Pending::Subscribe(key))
+    }
+
+    // subscribe calls of the topics without the upstream subscription and without
+    // the call in flight: new ones, those of the connection lost and those failed
+    fn resubscribe(&mut self) -> Vec<String> {
+        let now = Instant::now();
+        let keys: Vec<String> = self
+            .topics
+            .iter()
+            .filter(|(_, t)| t.upstream_id.is_none() && !t.subscribing && t.retry_at <= now)
+            .map(|(k, _)| k.clone())
+            .collect();
+        keys.into_iter().map(|k| self.subscribe_call(k)).collect()
+    }
+
+    fn unsubscribe_call(&mut self, upstream_id: &str) -> String {
+        self.call(
+            "eth_unsubscribe",
+            json!([upstream_id]),
+            Pending::Unsubscribe,
+        )
+    }
+
+    async fn send(&self, ws: &mut Upstream, calls: Vec<String>) -> bool {
+        for call in calls {
+            if ws.send(Message::Text(call)).await.is_err() {
+                return false;
+            }
+        }
+        true
+    }
+
+    // returns calls to send upstream, subscriptions are requested by `resubscribe`
+    fn command(&mut self, command: Command) -> Vec<String> {
+        match command {
+            Command::Subscribe {
+                params,
+                id,
+                listener,
+                reply,
+            } => {
+                let key = params.to_string();
+                let topic = self.topics.entry(key).or_insert_with(|| Topic {
+                    params,
+                    upstream_id: None,
+                    clients: HashMap::new(),
+                    waiting: vec![],
+                    subscribing: false,
+                    retry_at: Instant::now(),
+                });
+                topic.clients.insert(id.clone(), listener);
+                if topic.upstream_id.is_some() {
+                    let _ = reply.try_send(Ok(()));
+                } else {
+                    // a new client does not wait for the retry of the failed subscription
+                    topic.retry_at = Instant::now();
+                    topic.waiting.push((id, reply));
+                }
+                vec![]
+            }
+            Command::Unsubscribe { id, reply } => {
+                let key = self
+                    .topics
+                    .iter()
+                    .find(|(_, t)| t.clients.contains_key(&id))
+                    .map(|(k, _)| k.clone());
+                let _ = reply.try_send(key.is_some());
+                match key {
+                    Some(key) => self.leave(&key, &id),
+                    None => vec![],
+                }
+            }
+        }
+    }
+
+    // removes the client, the last one takes the upstream subscription with it
+    fn leave(&mut self, key: &str, id: &str) -> Vec<String> {
+        let topic = match self.topics.get_mut(key) {
+            Some(x) => x,
+            None => return vec![],
+        };
+        topic.clients.remove(id);
+        topic.waiting.retain(|(x, _)| x != id);
+        if !topic.clients.is_empty() {
+            return vec![];
+        }
+        let topic = self.topics.remove(key).expect("topic exists");
+        debug!("app = {} unsubscribed {}", self.slug, key);
+        match topic.upstream_id {
+            Some(upstream_id) => vec![self.unsubscribe_call(&upstream_id)],
+            None => vec![],
+        }
+    }
+
+    // returns calls to send upstream
+    fn message(&mut self, text: &str) -> Vec<String> {
+        let msg: Value = match serde_json::from_str(text) {
+            Ok(x) => x,
+            Err(_) => return vec![],
+        };
+        if msg["method"] == "eth_subscription" {
+            let upstream_id = &msg["params"]["subscription"];
+            let topic = self
+                .topics
+                .values_mut()
+                .find(|t| t.upstream_id.as_deref() == upstream_id.as_str());
+            if let Some(topic) = topic {
+                let result = &msg["params"]["result"];
+                // clients that are gone are dropped, slow ones miss the notification
+                topic.clients.retain(|id, listener| {
+                    let notice = Notice {
+                        subscription: id.clone(),
+                        result: result.clone(),
+                    };
+                    !matches!(
+                        listener.try_send(notice),
+                        Err(async_std::channel::TrySendError::Closed(_))
+                    )
+                });
+            }
+            self.topics.retain(|_, t| !t.clients.is_empty());
+            return vec![];
+        }
+        let pending = match msg["id"].as_u64().and_then(|id| self.pending.remove(&id)) {
+            Some(x) => x,
+            None => return vec![],
+        };
+        let key = match pending {
+            Pending::Subscribe(key) => key,
+            Pending::Unsubscribe => return vec![],
+        };
+        let upstream_id = msg["result"].as_str();
+        let topic = match self.topics.get_mut(&key) {
+            // every client left before the upstream confirmed the subscription
+            None => {
+                return upstream_id
+                    .map(|x| self.unsubscribe_call(x))
+                    .into_iter()
+                    .collect()
+            }
+            Some(x) => x,
+        };
+        topic.subscribing = false;
+        match upstream_id {
+            Some(upstream_id) => {
+                info!(
+                    "app = {} subscribed {} for {} clients",
+                    self.slug,
+                    key,
+                    topic.clients.len()
+                );
+                topic.upstream_id = Some(upstream_id.to_owned());
+                for (_, reply) in topic.waiting.drain(..) {
+                    let _ = reply.try_send(Ok(()));
+                }
+            }
+            None => {
+                let error = serde_json::from_value::<ErrorObject>(msg["error"].clone())
+                    .unwrap_or_else(|_| unavailable());
+                warn!(
+                    "app = {} subscription {} failed: {}",
+                    self.slug, key, error.message
+                );
+                for (id, reply) in topic.waiting.drain(..) {
+                    topic.clients.remove(&id);
+                    let _ = reply.try_send(Err(error.clone()));
+                }
+                // the clients subscribed before are kept for the retry
+                topic.retry_at = Instant::now() + RETRY_INTERVAL;
+                if topic.clients.is_empty() {
+                    self.topics.remove(&key);
+                }
+            }
+        }
+        vec![]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{spawn_silent, WsNode};
+    use std::sync::atomic::Ordering;
+
+    const CONNECT: Duration = Duration::from_secs(1);
+
+    async fn within<T>(f: impl std::future::Future<Output = T>) -> T {
+        timeout(Duration::from_secs(5), f).await.expect("timed out")
+    }
+
+    async fn eventually(check: impl Fn() -> bool) {
+        within(async {
+            while !check() {
+                async_std::task::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+    }
+
+    // notices received after the call, older ones are dropped
+    async fn notified(rx: &Receiver<Notice>, id: &str) {
+        while rx.try_recv().is_ok() {}
+        let notice = within(rx.recv()).await.unwrap();
+        assert_eq!(notice.subscription, id);
+    }
+
+    fn subscribe_calls(node: &WsNode) -> usize {
+        let calls = node.calls.lock().unwrap();
+        calls
+            .iter()
+            .filter(|x| x["method"] == "eth_subscribe")
+            .count()
+    }
+
+    #[async_std::test]
+    async fn clients_share_the_upstream_subscription() {
+        let node = WsNode::spawn().await;
+        let subs = Subscriptions::new();
+        let params = json!(["newHeads"]);
+        let (tx_a, rx_a) = bounded(NOTICE_QUEUE);
+        let (tx_b, rx_b) = bounded(NOTICE_QUEUE);
+        let a = subs.subscribe("app", &node.url, CONNECT, params.clone(), tx_a);
+        let a = within(a).await.unwrap();
+        let b = subs.subscribe("app", &node.url, CONNECT, params, tx_b);
+        let b = within(b).await.unwrap();
+        assert_ne!(a, b);
+        notified(&rx_a, &a).await;
+        notified(&rx_b, &b).await;
+        assert_eq!(subscribe_calls(&node), 1);
+
+        assert!(within(subs.unsubscribe("app", &node.url, &a)).await);
+        assert!(!within(subs.unsubscribe("app", &node.url, &a)).await);
+        assert!(within(subs.unsubscribe("app", &node.url, &b)).await);
+        // the upstream is closed with the last subscription
+        eventually(|| node.open.load(Ordering::SeqCst) == 0).await;
+    }
+
+    #[async_std::test]
+    async fn failed_resubscribe_is_retried() {
+        let node = WsNode::spawn().await;
+        let subs = Subscriptions::new();
+        let params = json!(["newHeads"]);
+        let (tx_a, rx_a) = bounded(NOTICE_QUEUE);
+        let a = subs.subscribe("app", &node.url, CONNECT, params.clone(), tx_a);
+        let a = within(a).await.unwrap();
+        notified(&rx_a, &a).await;
+
+        // the connection is lost and the upstream refuses to subscribe again once
+        node.failures.store(1, Ordering::SeqCst);
+        node.kick.store(true, Ordering::SeqCst);
+        eventually(|| node.failures.load(Ordering::SeqCst) == 0).await;
+
+        // the new client is answered instead of waiting for a call never sent
+        let (tx_b, rx_b) = bounded(NOTICE_QUEUE);
+        let b = subs.subscribe("app", &node.url, CONNECT, params, tx_b);
+        let b = within(b).await.unwrap();
+        notified(&rx_b, &b).await;
+        notified(&rx_a, &a).await;
+        assert_eq!(subscribe_calls(&node), 3);
+    }
+
+    #[async_std::test]
+    async fn confirmed_clients_are_subscribed_again() {
+        let node = WsNode::spawn().await;
+        let subs = Subscriptions::new();
+        let (tx, rx) = bounded(NOTICE_QUEUE);
+        let res = subs.subscribe("app", &node.url, CONNECT, json!(["newHeads"]), tx);
+        let id = within(res).await.unwrap();
+        node.failures.store(2, Ordering::SeqCst);
+        node.kick.store(true, Ordering::SeqCst);
+        eventually(|| node.failures.load(Ordering::SeqCst) == 0).await;
+        notified(&rx, &id).await;
+        assert_eq!(subscribe_calls(&node), 4);
+    }
+
+    #[async_std::test]
+    async fn failed_subscribe_is_reported() {
+        let node = WsNode::spawn().await;
+        node.failures.store(1, Ordering::SeqCst);
+        let subs = Subscriptions::new();
+        let (tx, _rx) = bounded(NOTICE_QUEUE);
+        let res = subs.subscribe("app", &node.url, CONNECT, json!(["newHeads"]), tx);
+        assert_eq!(within(res).await.unwrap_err().message, "busy");
+    }
+
+    #[async_std::test]
+    async fn connect_times_out() {
+        let url = spawn_silent().await;
+        let subs = Subscriptions::new();
+        let (tx, _rx) = bounded(NOTICE_QUEUE);
+        let timeout = Duration::from_millis(200);
+        let res = subs.subscribe("app", &url, timeout, json!(["newHeads"]), tx);
+        let error = within(res).await.unwrap_err();
+        assert_eq!(error.code, jsonrpc::UPSTREAM_UNAVAILABLE);
+    }
+
+    #[async_std::test]
+    async fn unused_upstreams_are_stopped() {
+        let (old, new) = (WsNode::spawn().await, WsNode::spawn().await);
+        let subs = Subscriptions::new();
+        let (tx, _rx) = bounded(NOTICE_QUEUE);
+        let res = subs.subscribe("app", &old.url, CONNECT, json!(["newHeads"]), tx);
+        let id = within(res).await.unwrap();
+        assert_eq!(old.open.load(Ordering::SeqCst), 1);
+
+        let mut app = Application::new(
+            "app",
+            None,
+            "/".to_owned(),
+            "http://node".to_owned(),
+            false,
+            vec![],
+        );
+        app.ws_url = Some(old.url.clone());
+        subs.retain(&[app.clone()]);
+        assert_eq!(subs.apps.lock().unwrap().len(), 1);
+
+        app.ws_url = Some(new.url.clone());
+        subs.retain(&[app]);
+        assert!(subs.apps.lock().unwrap().is_empty());
+        eventually(|| old.open.load(Ordering::SeqCst) == 0).await;
+        // sessions leaving later do not start the old connection again
+        assert!(!within(subs.unsubscribe("app", &old.url, &id)).await);
+        assert!(subs.apps.lock().unwrap().is_empty());
+    }
+}
@@ -1,3 +1,4 @@
+use crate::redact;
 use std::time::Instant;
 
 use tide::{Middleware, Next, Request};
@@ -26,7 +27,8 @@ impl TraceMiddleware {
         ctx: Request<State>,
         next: Next<'a, State>,
     ) -> tide::Result {
-        let path = ctx.url().path().to_owned();
+        // keys passed in the path are masked
+        let path = redact::path(ctx.url().path());
         let method = ctx.method();
 
         let remote = ctx.remote().unwrap_or("").to_owned();
@@ -0,0 +1,1042 @@
+//! Gateway served end to end against local stubs of Redis, the nodes and the trace collector
+
+use super::*;
+use async_std::io::prelude::*;
+use async_std::io::BufReader;
+use async_std::net::{TcpListener, TcpStream};
+use async_tungstenite::async_std::{connect_async, ConnectStream};
+use async_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use async_tungstenite::tungstenite::Message;
+use async_tungstenite::WebSocketStream;
+use futures_util::{SinkExt, StreamExt};
+use jsonrpc_proto::jsonrpc;
+use jsonrpc_proto::redis::{QuotaStorage, RedisConnection, RpcKeyStorage};
+use jsonrpc_proto::{Application, QuotaWindow, RpcKey, Upstream};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const PEPPER: &str = "test-pepper";
+pub const APP: &str = "main";
+
+/// Redis that keeps strings in memory and answers other commands with OK
+#[derive(Clone, Default)]
+pub struct RedisStub {
+    pub data: Arc<Mutex<HashMap<String, String>>>,
+    // answers every command with an error
+    pub down: Arc<AtomicBool>,
+    // names of the commands received
+    pub commands: Arc<Mutex<Vec<String>>>,
+}
+
+impl RedisStub {
+    pub async fn spawn() -> (Self, RedisConnection) {
+        let stub = Self::default();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = stub.clone();
+        async_std::task::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                async_std::task::spawn(server.clone().serve(stream));
+            }
+        });
+        let conn = RedisConnection {
+            host: "127.0.0.1".to_owned(),
+            port: port as u32,
+            username: String::new(),
+            password: String::new(),
+            db: 0,
+            use_tls: false,
+        };
+        (stub, conn)
+    }
+
+    pub fn set<T: serde::Serialize>(&self, key: &str, value: &T) {
+        let value = serde_json::to_string(value).unwrap();
+        self.data.lock().unwrap().insert(key.to_owned(), value);
+    }
+}
+
+async fn read_command(reader: &mut BufReader<TcpStream>) -> Option<Vec<String>> {
+    let mut line = String::new();
+    reader.read_line(&mut line).await.ok()?;
+    let count: usize = line.trim().strip_prefix('*')?.parse().ok()?;
+    let mut args = vec![];
+    for _ in 0..count {
+        line.clear();
+        reader.read_line(&mut line).await.ok()?;
+        let len: usize = line.trim().strip_prefix('$')?.parse().ok()?;
+        let mut buf = vec![0u8; len + 2];
+        reader.read_exact(&mut buf).await.ok()?;
+        buf.truncate(len);
+        args.push(String::from_utf8(buf).ok()?);
+    }
+    Some(args)
+}
+
+impl RedisStub {
+    async fn serve(self, stream: TcpStream) {
+        let data = self.data;
+        let mut writer = stream.clone();
+        let mut reader = BufReader::new(stream);
+        while let Some(args) = read_command(&mut reader).await {
+            if self.down.load(Ordering::SeqCst) {
+                if writer.write_all(b"-ERR unavailable\r\n").await.is_err() {
+                    break;
+                }
+                continue;
+            }
+            let command = args[0].to_uppercase();
+            self.commands.lock().unwrap().push(command.clone());
+            let reply = match command.as_str() {
+                "PING" => "+PONG\r\n".to_owned(),
+                "MGET" => {
+                    let data = data.lock().unwrap();
+                    let values: Vec<String> = args[1..]
+                        .iter()
+                        .map(|k| match data.get(k) {
+                            Some(x) => format!("${}\r\n{}\r\n", x.len(), x),
+                            None => "$-1\r\n".to_owned(),
+                        })
+                        .collect();
+                    format!("*{}\r\n{}", values.len(), values.concat())
+                }
+                "INCRBY" => {
+                    let mut data = data.lock().unwrap();
+                    let value = data
+                        .entry(args[1].clone())
+                        .or_insert_with(|| "0".to_owned());
+                    let sum = value.parse::<i64>().unwrap() + args[2].parse::<i64>().unwrap();
+                    *value = sum.to_string();
+                    format!(":{}\r\n", sum)
+                }
+                // the only script of the gateway
+                "EVALSHA" => quota_script(&mut data.lock().unwrap(), &args),
+                "GET" => match data.lock().unwrap().get(&args[1]) {
+                    Some(x) => format!("${}\r\n{}\r\n", x.len(), x),
+                    None => "$-1\r\n".to_owned(),
+                },
+                "SET" => {
+                    data.lock()
+                        .unwrap()
+                        .insert(args[1].clone(), args[2].clone());
+                    "+OK\r\n".to_owned()
+                }
+                "DEL" => {
+                    let mut data = data.lock().unwrap();
+                    let removed = args[1..].iter().filter(|k| data.remove(*k).is_some());
+                    format!(":{}\r\n", removed.count())
+                }
+                // one pass over the keys, only trailing `*` patterns are supported
+                "SCAN" => {
+                    let prefix = args[3].trim_end_matches('*');
+                    let keys: Vec<String> = data
+                        .lock()
+                        .unwrap()
+                        .keys()
+                        .filter(|k| k.starts_with(prefix))
+                        .map(|k| format!("${}\r\n{}\r\n", k.len(), k))
+                        .collect();
+                    format!("*2\r\n$1\r\n0\r\n*{}\r\n{}", keys.len(), keys.concat())
+                }
+                "RENAME" => {
+                    let mut data = data.lock().unwrap();
+                    match data.remove(&args[1]) {
+                        Some(x) => {
+                            data.insert(args[2].clone(), x);
+                            "+OK\r\n".to_owned()
+                        }
+                        None => "-ERR no such key\r\n".to_owned(),
+                    }
+                }
+                "EXPIRE" | "HINCRBY" | "PUBLISH" => ":1\r\n".to_owned(),
+                _ => "+OK\r\n".to_owned(),
+            };
+            if writer.write_all(reply.as_bytes()).await.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+// the quota script run by the stub itself, as it has no Lua
+fn quota_script(data: &mut HashMap<String, String>, args: &[String]) -> String {
+    let count: usize = args[2].parse().unwrap();
+    let (keys, argv) = args[3..].split_at(count);
+    let cost: u64 = argv[0].parse().unwrap();
+    let mut used: Vec<u64> = keys
+        .iter()
+        .map(|k| data.get(k).map_or(0, |x| x.parse().unwrap()))
+        .collect();
+    let limit = |i: usize| argv[i * 2 + 1].parse::<u64>().unwrap();
+    let exhausted = (0..count).find(|&i| used[i] + cost > limit(i));
+    if exhausted.is_none() {
+        for (k, used) in keys.iter().zip(used.iter_mut()) {
+            *used += cost;
+            data.insert(k.clone(), used.to_string());
+        }
+    }
+    let head = exhausted.map_or(0, |i| i + 1) as u64;
+    let items: Vec<String> = std::iter::once(head)
+        .chain(used)
+        .map(|x| format!(":{}\r\n", x))
+        .collect();
+    format!("*{}\r\n{}", items.len(), items.concat())
+}
+
+/// Bodies received by the stub HTTP server
+pub type Received = Arc<Mutex<Vec<(tide::http::Headers, Value)>>>;
+
+async fn listen<S: Clone + Send + Sync + 'static>(app: tide::Server<S>) -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    async_std::task::spawn(async move { app.listen(listener).await });
+    url
+}
+
+// answers every call with its method, block number is fixed
+fn node_result(req: &Value) -> Value {
+    let method = req["method"].as_str().unwrap_or_default();
+    let result = match method {
+        "eth_blockNumber" => json!("0x64"),
+        "eth_syncing" => json!(false),
+        "eth_newBlockFilter" | "eth_newFilter" => json!("0x1F"),
+        _ => json!({ "method": method, "params": req["params"] }),
+    };
+    json!({ "jsonrpc": "2.0", "id": req["id"], "result": result })
+}
+
+/// JSON-RPC node that records the calls it gets
+pub async fn spawn_node() -> (String, Received) {
+    let received = Received::default();
+    let mut app = tide::with_state(received.clone());
+    app.at("/").post(node_call);
+    app.at("/*").post(node_call);
+    (listen(app).await, received)
+}
+
+async fn node_call(mut req: tide::Request<Received>) -> tide::Result<tide::Body> {
+    let body: Value = req.body_json().await?;
+    let headers = AsRef::<tide::http::Headers>::as_ref(&req).clone();
+    req.state().lock().unwrap().push((headers, body.clone()));
+    let res = match &body {
+        Value::Array(calls) => Value::Array(calls.iter().map(node_result).collect()),
+        call => node_result(call),
+    };
+    tide::Body::from_json(&res)
+}
+
+/// OTLP/HTTP collector that records the exported requests
+pub async fn spawn_collector() -> (String, Received) {
+    let received = Received::default();
+    let mut app = tide::with_state(received.clone());
+    app.at("/v1/traces")
+        .post(|mut req: tide::Request<Received>| async move {
+            let body: Value = req.body_json().await?;
+            let headers = AsRef::<tide::http::Headers>::as_ref(&req).clone();
+            req.state().lock().unwrap().push((headers, body));
+            Ok("{}")
+        });
+    (listen(app).await, received)
+}
+
+/// WebSocket node serving subscriptions, notifying every subscription each tick
+#[derive(Clone, Default)]
+pub struct WsNode {
+    pub url: String,
+    // subscribe calls to answer with the error
+    pub failures: Arc<AtomicUsize>,
+    // drops the open connections on the next tick
+    pub kick: Arc<AtomicBool>,
+    pub open: Arc<AtomicUsize>,
+    pub calls: Arc<Mutex<Vec<Value>>>,
+}
+
+const WS_TICK: Duration = Duration::from_millis(50);
+
+impl WsNode {
+    pub async fn spawn() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let node = Self {
+            url: format!("ws://{}", listener.local_addr().unwrap()),
+            ..Self::default()
+        };
+        let server = node.clone();
+        async_std::task::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                async_std::task::spawn(server.clone().serve(stream));
+            }
+        });
+        node
+    }
+
+    fn answer(&self, text: &str, subscriptions: &mut Vec<String>) -> Option<Value> {
+        let call: Value = serde_json::from_str(text).ok()?;
+        self.calls.lock().unwrap().push(call.clone());
+        let result = match call["method"].as_str()? {
+            "eth_subscribe" => {
+                let failed = self
+                    .failures
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |x| x.checked_sub(1))
+                    .is_ok();
+                if failed {
+                    let error = json!({"code": -32000, "message": "busy"});
+                    return Some(json!({"jsonrpc": "2.0", "id": call["id"], "error": error}));
+                }
+                let id = format!("0x{:x}", self.calls.lock().unwrap().len());
+                subscriptions.push(id.clone());
+                json!(id)
+            }
+            "eth_unsubscribe" => {
+                let id = call["params"][0].as_str().unwrap_or_default();
+                let before = subscriptions.len();
+                subscriptions.retain(|x| x != id);
+                json!(subscriptions.len() < before)
+            }
+            _ => Value::Null,
+        };
+        Some(json!({"jsonrpc": "2.0", "id": call["id"], "result": result}))
+    }
+
+    async fn serve(self, stream: TcpStream) {
+        let mut ws = match async_tungstenite::accept_async(stream).await {
+            Ok(x) => x,
+            Err(_) => return,
+        };
+        self.open.fetch_add(1, Ordering::SeqCst);
+        let mut subscriptions = vec![];
+        loop {
+            let next = async_std::future::timeout(WS_TICK, ws.next()).await;
+            let reply = match next {
+                Ok(Some(Ok(Message::Text(text)))) => self.answer(&text, &mut subscriptions),
+                Ok(Some(Ok(_))) => None,
+                Ok(_) => break,
+                Err(_) if self.kick.swap(false, Ordering::SeqCst) => {
+                    let _ = ws.close(None).await;
+                    break;
+                }
+                Err(_) => {
+                    for id in &subscriptions {
+                        let notice = json!({
+                            "jsonrpc": "2.0",
+                            "method": "eth_subscription",
+                            "params": {"subscription": id, "result": "tick"},
+                        });
+                        let _ = ws.send(Message::Text(notice.to_string())).await;
+                    }
+                    None
+                }
+            };
+            if let Some(reply) = reply {
+                if ws.send(Message::Text(reply.to_string())).await.is_err() {
+                    break;
+                }
+            }
+        }
+        self.open.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Accepts connections and never answers
+pub async fn spawn_silent() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+    async_std::task::spawn(async move {
+        let mut held = vec![];
+        while let Ok((stream, _)) = listener.accept().await {
+            held.push(stream);
+        }
+    });
+    url
+}
+
+/// Answer of the scripted HTTP node to one request
+#[derive(Clone, Copy)]
+pub enum Reply {
+    Sized(&'static str),
+    Chunked(&'static [&'static str]),
+    /// Answers as keep-alive, then closes the connection
+    Closing(&'static str),
+    /// Closes the connection without an answer
+    Drop,
+    /// Never answers
+    Silent,
+}
+
+/// HTTP/1.1 node answering the requests in the order of the script,
+/// requests past the script get `{}`
+#[derive(Clone, Default)]
+pub struct HttpNode {
+    pub url: String,
+    pub connections: Arc<AtomicUsize>,
+    pub requests: Arc<AtomicUsize>,
+}
+
+impl HttpNode {
+    pub async fn spawn(script: Vec<Reply>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let node = Self {
+            url: format!("http://{}", listener.local_addr().unwrap()),
+            ..Self::default()
+        };
+        let server = node.clone();
+        let script = Arc::new(script);
+        async_std::task::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                server.connections.fetch_add(1, Ordering::SeqCst);
+                async_std::task::spawn(server.clone().serve(stream, script.clone()));
+            }
+        });
+        node
+    }
+
+    async fn serve(self, stream: TcpStream, script: Arc<Vec<Reply>>) {
+        let mut writer = stream.clone();
+        let mut reader = BufReader::new(stream);
+        loop {
+            let mut len = 0;
+            loop {
+                let mut line = String::new();
+                match reader.read_line(&mut line).await {
+                    Ok(0) | Err(_) => return,
+                    Ok(_) if line == "\r\n" => break,
+                    Ok(_) => {}
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        len = value.trim().parse().unwrap();
+                    }
+                }
+            }
+            let mut body = vec![0u8; len];
+            if reader.read_exact(&mut body).await.is_err() {
+                return;
+            }
+            let n = self.requests.fetch_add(1, Ordering::SeqCst);
+            let sized = |body: &str| {
+                format!(
+                    "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+            };
+            let (reply, close) = match script.get(n).copied().unwrap_or(Reply::Sized("{}")) {
+                Reply::Sized(body) => (sized(body), false),
+                Reply::Closing(body) => (sized(body), true),
+                Reply::Chunked(parts) => {
+                    let chunks: String = parts
+                        .iter()
+                        .map(|x| format!("{:x}\r\n{}\r\n", x.len(), x))
+                        .collect();
+                    let head = "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ntransfer-encoding: chunked\r\n\r\n";
+                    (format!("{}{}0\r\n\r\n", head, chunks), false)
+                }
+                Reply::Drop => return,
+                Reply::Silent => {
+                    async_std::future::pending::<()>().await;
+                    return;
+                }
+            };
+            if writer.write_all(reply.as_bytes()).await.is_err() || close {
+                return;
+            }
+        }
+    }
+}
+
+async fn server(conn: &RedisConnection, app: Application, tracer: Tracer) -> tide::Server<State> {
+    let pool = RedisPool::from_redis(conn, 4).await.unwrap();
+    let state = State {
+        router: Arc::new(RwLock::new(Router::new(vec![app], Some(APP.to_owned())))),
+        rpckeys: AsyncRpcKeyStorage::new(pool.clone()),
+        hasher: KeyHasher::new(PEPPER),
+        quotas: QuotaStorage::new(pool.clone()),
+        usage: AsyncUsageStorage::new(pool.clone()),
+        cache: Arc::new(Cache::off()),
+        coalescer: Arc::new(Coalescer::new()),
+        filters: Arc::new(Filters::new(
+            FilterStorage::new(pool),
+            Duration::from_secs(60),
+        )),
+        subscriptions: Arc::new(Subscriptions::new()),
+        upstreams: Arc::new(Upstreams::new()),
+        balancer: Arc::new(Balancer::new()),
+        health: Arc::new(Health::new()),
+        metrics: Arc::new(Metrics::new()),
+        tracer,
+        max_batch: 100,
+        key_interval: Duration::from_millis(100),
+    };
+    let mut app = tide::with_state(state);
+    app.at("/*").post(api::proxy_rpc).get(ws::proxy_ws);
+    app.at("/").post(api::proxy_rpc).get(ws::proxy_ws);
+    app
+}
+
+/// Gateway serving one application from `node`, with the key stored in the stub
+pub struct Gateway {
+    pub app: tide::Server<State>,
+    pub redis: RedisStub,
+    pub conn: RedisConnection,
+    pub key: String,
+    pub doc: RpcKey,
+}
+
+impl Gateway {
+    pub async fn new(node: &str, tracer: Tracer) -> Self {
+        let mut app = Application::new(
+            APP,
+            Some(APP.to_owned()),
+            "/".to_owned(),
+            node.to_owned(),
+            false,
+            vec![],
+        );
+        app.proxy.traceparent = true;
+        // sessions are served while no subscription is made
+        app.ws_url = Some("ws://127.0.0.1:1".to_owned());
+        Self::serve(app, tracer).await
+    }
+
+    /// Gateway serving the application, with a new key for it
+    pub async fn serve(app: Application, tracer: Tracer) -> Self {
+        let (redis, conn) = RedisStub::spawn().await;
+        let no_quota = || None;
+        let (key, doc) = RpcKey::generate(
+            &KeyHasher::new(PEPPER),
+            APP.to_owned(),
+            vec![],
+            None,
+            no_quota(),
+            no_quota(),
+            no_quota(),
+            no_quota(),
+            no_quota(),
+            no_quota(),
+            no_quota(),
+        );
+        redis.set(&format!("rk_a{}_{}", APP, doc.key_hash), &doc);
+        Self {
+            app: server(&conn, app, tracer).await,
+            redis,
+            conn,
+            key,
+            doc,
+        }
+    }
+
+    /// Another gateway process sharing the storage, with nothing else in common
+    pub async fn replica(&self) -> Self {
+        let app = self.app.state().router.read().unwrap().apps()[0].clone();
+        Self {
+            app: server(&self.conn, app, Tracer::disabled()).await,
+            redis: self.redis.clone(),
+            conn: self.conn.clone(),
+            key: self.key.clone(),
+            doc: self.doc.clone(),
+        }
+    }
+
+    /// Replaces the stored document of the key
+    pub fn update_key(&self, change: impl FnOnce(&mut RpcKey)) {
+        let mut doc = self.doc.clone();
+        change(&mut doc);
+        self.redis
+            .set(&format!("rk_a{}_{}", APP, doc.key_hash), &doc);
+    }
+
+    /// WebSocket session of the key on the listening gateway
+    pub async fn connect_ws(&self) -> WebSocketStream<ConnectStream> {
+        let url = listen(self.app.clone()).await.replace("http", "ws");
+        let url = format!("{}/{}", url, self.key);
+        connect_async(url.as_str()).await.unwrap().0
+    }
+
+    /// Posts the payload with the key in the path
+    pub async fn send(&self, body: &Value, headers: &[(&str, &str)]) -> http_types::Response {
+        let url = format!("http://gateway/{}", self.key);
+        let mut req = http_types::Request::new(http_types::Method::Post, url.as_str());
+        req.set_body(body.to_string());
+        for (name, value) in headers {
+            req.insert_header(*name, *value);
+        }
+        self.app.respond(req).await.unwrap()
+    }
+
+    /// Status and JSON body of the posted payload
+    pub async fn post(&self, body: &Value, headers: &[(&str, &str)]) -> (u16, Value) {
+        let mut res = self.send(body, headers).await;
+        let body = res.body_string().await.unwrap();
+        let status = res.status() as u16;
+        (status, serde_json::from_str(&body).unwrap_or(Value::Null))
+    }
+}
+
+// exported spans by their names, waiting for the export interval
+async fn exported(collector: &Received, count: usize) -> HashMap<String, Value> {
+    for _ in 0..50 {
+        let spans: Vec<Value> = collector
+            .lock()
+            .unwrap()
+            .iter()
+            .flat_map(|(_, body)| {
+                body["resourceSpans"][0]["scopeSpans"][0]["spans"]
+                    .as_array()
+                    .cloned()
+                    .unwrap_or_default()
+            })
+            .collect();
+        if spans.len() >= count {
+            return spans
+                .into_iter()
+                .map(|x| (x["name"].as_str().unwrap().to_owned(), x))
+                .collect();
+        }
+        async_std::task::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("spans were not exported");
+}
+
+fn attribute(span: &Value, key: &str) -> Value {
+    span["attributes"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|x| x["key"] == key)
+        .map(|x| x["value"].clone())
+        .unwrap_or(Value::Null)
+}
+
+#[async_std::test]
+async fn redis_cache_is_cleared_per_application() {
+    use crate::cache::{Lookup, Policy};
+    use jsonrpc_proto::jsonrpc::Request;
+    let (redis, conn) = RedisStub::spawn().await;
+    let pool = RedisPool::from_redis(&conn, 2).await.unwrap();
+    let storage = CacheStorage::new(pool);
+    let cache = Cache::redis(
+        Policy {
+            head_ttl: 0,
+            confirmations: 0,
+        },
+        storage.clone(),
+    );
+    let req: Request =
+        serde_json::from_value(json!({"jsonrpc": "2.0", "id": 1, "method": "eth_chainId"}))
+            .unwrap();
+    let key = "/:eth_chainId:";
+    for app in ["eth", "eth_x"] {
+        storage.set(app, key, &json!("0x1"), None).await.unwrap();
+    }
+    redis.set("rk_aeth_unrelated", &json!({}));
+    assert!(matches!(
+        cache.lookup("eth", "/", &req, None).await,
+        Lookup::Hit(_)
+    ));
+
+    assert_eq!(storage.clear("eth").await.unwrap(), 1);
+    assert!(matches!(
+        cache.lookup("eth", "/", &req, None).await,
+        Lookup::Miss
+    ));
+    assert!(matches!(
+        cache.lookup("eth_x", "/", &req, None).await,
+        Lookup::Hit(_)
+    ));
+    assert!(redis.data.lock().unwrap().contains_key("rk_aeth_unrelated"));
+}
+
+// the session is closed by the gateway once the key is revoked
+async fn closed_when(change: impl FnOnce(&Gateway), code: CloseCode, reason: &str) {
+    let (node, _) = spawn_node().await;
+    let gw = Gateway::new(&node, Tracer::disabled()).await;
+    let mut ws = gw.connect_ws().await;
+    let call = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_chainId"});
+    ws.send(Message::Text(call.to_string())).await.unwrap();
+    let reply = ws.next().await.unwrap().unwrap();
+    let reply: Value = serde_json::from_str(reply.to_text().unwrap()).unwrap();
+    assert_eq!(reply["result"]["method"], "eth_chainId");
+    // still served after a few checks of the key
+    async_std::task::sleep(Duration::from_millis(300)).await;
+    ws.send(Message::Text(call.to_string())).await.unwrap();
+    assert!(ws.next().await.unwrap().unwrap().is_text());
+
+    change(&gw);
+    let msg = async_std::future::timeout(Duration::from_secs(5), ws.next())
+        .await
+        .expect("session is not closed")
+        .unwrap()
+        .unwrap();
+    match msg {
+        Message::Close(Some(frame)) => {
+            assert_eq!(frame.code, code);
+            assert_eq!(frame.reason, reason);
+        }
+        x => panic!("unexpected message {:?}", x),
+    }
+}
+
+#[async_std::test]
+async fn ws_session_closed_when_key_deactivated() {
+    let change = |gw: &Gateway| gw.update_key(|x| x.active = false);
+    closed_when(change, CloseCode::Policy, "key is not active").await;
+}
+
+#[async_std::test]
+async fn ws_session_closed_when_key_expired() {
+    let change = |gw: &Gateway| gw.update_key(|x| x.expires = 1);
+    closed_when(change, CloseCode::Policy, "key expired").await;
+}
+
+#[async_std::test]
+async fn ws_session_closed_when_upstream_changed() {
+    let change = |gw: &Gateway| {
+        let mut router = gw.app.state().router.write().unwrap();
+        let mut app = router.apps()[0].clone();
+        app.ws_url = Some("ws://127.0.0.1:2".to_owned());
+        router.upsert(app);
+    };
+    closed_when(change, CloseCode::Restart, "upstream changed").await;
+}
+
+fn filter_polls(calls: &Received) -> usize {
+    let calls = calls.lock().unwrap();
+    let polls = calls
+        .iter()
+        .filter(|(_, x)| x["method"] == "eth_getFilterChanges");
+    polls.count()
+}
+
+#[async_std::test]
+async fn filters_are_served_by_their_node_through_any_replica() {
+    let (first, first_calls) = spawn_node().await;
+    let (second, second_calls) = spawn_node().await;
+    let mut app = Application::new(
+        APP,
+        Some(APP.to_owned()),
+        "/".to_owned(),
+        first.clone(),
+        false,
+        vec![],
+    );
+    app.upstreams = vec![Upstream::new(first), Upstream::new(second)];
+    let gw = Gateway::serve(app, Tracer::disabled()).await;
+    let replica = gw.replica().await;
+
+    let create = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_newBlockFilter"});
+    let (status, res) = gw.post(&create, &[]).await;
+    assert_eq!((status, &res["result"]), (200, &json!("0x1F")));
+    let creator = match first_calls.lock().unwrap().len() {
+        1 => &first_calls,
+        _ => &second_calls,
+    };
+
+    // ids differ in case only
+    let poll =
+        json!({"jsonrpc": "2.0", "id": 2, "method": "eth_getFilterChanges", "params": ["0x1f"]});
+    for gw in [&replica, &gw, &replica, &replica] {
+        let (status, res) = gw.post(&poll, &[]).await;
+        assert_eq!(status, 200, "{}", res);
+    }
+    assert_eq!(filter_polls(creator), 4);
+
+    let uninstall =
+        json!({"jsonrpc": "2.0", "id": 3, "method": "eth_uninstallFilter", "params": ["0x1f"]});
+    let (status, _) = replica.post(&uninstall, &[]).await;
+    assert_eq!(status, 200);
+    let (_, res) = gw.post(&poll, &[]).await;
+    assert_eq!(res["error"]["code"], jsonrpc::FILTER_NOT_FOUND);
+    assert_eq!(filter_polls(&first_calls) + filter_polls(&second_calls), 4);
+}
+
+#[async_std::test]
+async fn unknown_filters_are_not_sent_upstream() {
+    let (node, calls) = spawn_node().await;
+    let gw = Gateway::new(&node, Tracer::disabled()).await;
+    let poll =
+        json!({"jsonrpc": "2.0", "id": 2, "method": "eth_getFilterLogs", "params": ["0xabc"]});
+    let (status, res) = gw.post(&poll, &[]).await;
+    assert_eq!(status, 404);
+    assert_eq!(res["error"]["code"], jsonrpc::FILTER_NOT_FOUND);
+    let chain = json!({"jsonrpc": "2.0", "id": 3, "method": "eth_chainId"});
+    let (_, res) = gw.post(&json!([poll, chain]), &[]).await;
+    assert_eq!(res[0]["error"]["code"], jsonrpc::FILTER_NOT_FOUND);
+    assert_eq!(res[1]["result"]["method"], "eth_chainId");
+    assert!(calls.lock().unwrap().iter().all(|(_, x)| x.is_array()));
+}
+
+const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+#[async_std::test]
+async fn request_is_traced_to_the_collector() {
+    let (node, calls) = spawn_node().await;
+    let (collector, received) = spawn_collector().await;
+    let tracer = Tracer::spawn(&collector, "gw-test", 0.0).unwrap();
+    let gw = Gateway::new(&node, tracer).await;
+
+    let call = json!({"jsonrpc": "2.0", "id": 7, "method": "eth_chainId"});
+    let (status, res) = gw.post(&call, &[("traceparent", TRACEPARENT)]).await;
+    assert_eq!(status, 200, "{}", res);
+    assert_eq!(res["result"]["method"], "eth_chainId");
+
+    let spans = exported(&received, 3).await;
+    let server = &spans["jsonrpc.request"];
+    let lookup = &spans["jsonrpc.key_lookup"];
+    let upstream = &spans["jsonrpc.upstream"];
+    let trace_id = "4bf92f3577b34da6a3ce929d0e0e4736";
+    for span in [server, lookup, upstream] {
+        assert_eq!(span["traceId"], trace_id);
+    }
+    assert_eq!(server["parentSpanId"], "00f067aa0ba902b7");
+    assert_eq!(server["kind"], otlp::KIND_SERVER);
+    assert_eq!(lookup["parentSpanId"], server["spanId"]);
+    assert_eq!(upstream["parentSpanId"], server["spanId"]);
+    assert_eq!(upstream["kind"], otlp::KIND_CLIENT);
+
+    assert_eq!(attribute(server, "http.method")["stringValue"], "POST");
+    assert_eq!(attribute(server, "jsonrpc.app")["stringValue"], APP);
+    assert_eq!(
+        attribute(server, "rpc.method")["stringValue"],
+        "eth_chainId"
+    );
+    assert_eq!(attribute(server, "http.status_code")["intValue"], "200");
+    // the key itself never leaves the gateway
+    let target = attribute(server, "http.target")["stringValue"].clone();
+    assert!(!target.as_str().unwrap().contains(&gw.key), "{}", target);
+
+    assert_eq!(
+        attribute(lookup, "jsonrpc.key_found")["stringValue"],
+        "true"
+    );
+    assert_eq!(
+        attribute(upstream, "rpc.method")["stringValue"],
+        "eth_chainId"
+    );
+    assert_eq!(
+        attribute(upstream, "rpc.jsonrpc.request_id")["stringValue"],
+        "7"
+    );
+    assert_eq!(attribute(upstream, "http.status_code")["intValue"], "200");
+
+    // the node continues the trace from the upstream span
+    let calls = calls.lock().unwrap();
+    let traceparent = calls[0].0.get("traceparent").unwrap().as_str().to_owned();
+    let ctx = otlp::SpanContext::from_traceparent(&traceparent).unwrap();
+    assert_eq!(otlp_hex(&ctx.span_id), upstream["spanId"]);
+
+    let resource = &received.lock().unwrap()[0].1["resourceSpans"][0]["resource"];
+    assert_eq!(resource["attributes"][0]["value"]["stringValue"], "gw-test");
+}
+
+#[async_std::test]
+async fn unsampled_requests_are_not_exported() {
+    let (node, _) = spawn_node().await;
+    let (collector, received) = spawn_collector().await;
+    let tracer = Tracer::spawn(&collector, "gw-test", 0.0).unwrap();
+    let gw = Gateway::new(&node, tracer).await;
+    let call = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_chainId"});
+    let (status, _) = gw.post(&call, &[]).await;
+    assert_eq!(status, 200);
+    async_std::task::sleep(Duration::from_millis(1500)).await;
+    assert!(received.lock().unwrap().is_empty());
+}
+
+fn otlp_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+async fn quotas() -> (RedisStub, QuotaStorage) {
+    let (redis, conn) = RedisStub::spawn().await;
+    let pool = RedisPool::from_redis(&conn, 2).await.unwrap();
+    (redis, QuotaStorage::new(pool))
+}
+
+const QUOTAS: [(QuotaWindow, u64); 2] = [(QuotaWindow::Hour, 10), (QuotaWindow::Day, 15)];
+
+#[async_std::test]
+async fn quota_is_charged_only_when_every_window_fits() {
+    let (_redis, quotas) = quotas().await;
+    let charge = |cost| quotas.consume(APP, "k1", &QUOTAS, cost);
+    // the tightest window is reported
+    let usage = charge(6).await.unwrap().unwrap();
+    assert_eq!(
+        (usage.window, usage.remaining, usage.exhausted),
+        (QuotaWindow::Hour, 4, false)
+    );
+    // rejected by the hour, the day is not charged either
+    let usage = charge(6).await.unwrap().unwrap();
+    assert_eq!(
+        (usage.window, usage.remaining, usage.exhausted),
+        (QuotaWindow::Hour, 4, true)
+    );
+    let usage = charge(4).await.unwrap().unwrap();
+    assert_eq!((usage.window, usage.remaining), (QuotaWindow::Hour, 0));
+    let other = quotas
+        .peek(APP, "k1", &[(QuotaWindow::Day, 15)])
+        .await
+        .unwrap();
+    assert_eq!(other.unwrap().remaining, 5);
+    assert!(charge(1).await.unwrap().unwrap().exhausted);
+    // keys without quotas are not counted
+    assert!(quotas.consume(APP, "k1", &[], 1).await.unwrap().is_none());
+}
+
+#[async_std::test]
+async fn quota_peek_only_reads() {
+    let (redis, quotas) = quotas().await;
+    let usage = quotas.peek(APP, "k1", &QUOTAS).await.unwrap().unwrap();
+    assert_eq!((usage.window, usage.remaining), (QuotaWindow::Hour, 10));
+    quotas.consume(APP, "k1", &QUOTAS, 3).await.unwrap();
+    let stored = redis.data.lock().unwrap().clone();
+    redis.commands.lock().unwrap().clear();
+    let usage = quotas.peek(APP, "k1", &QUOTAS).await.unwrap().unwrap();
+    assert_eq!((usage.remaining, usage.exhausted), (7, false));
+    assert!(usage.reset > 0 && usage.reset <= 3600);
+    // the pool pings the connections it reuses
+    let commands = redis.commands.lock().unwrap().clone();
+    let commands: Vec<&String> = commands.iter().filter(|x| *x != "PING").collect();
+    assert_eq!(commands, ["MGET"]);
+    assert_eq!(*redis.data.lock().unwrap(), stored);
+}
+
+// status with the limit, remaining and reset headers of the response
+async fn rate_limited(gw: &Gateway, method: &str) -> (u16, [Option<u64>; 4]) {
+    let call = json!({"jsonrpc": "2.0", "id": 1, "method": method, "params": []});
+    let res = gw.send(&call, &[]).await;
+    let header = |name| res.header(name).map(|x| x.as_str().parse().unwrap());
+    let headers = [
+        header("X-RateLimit-Limit"),
+        header("X-RateLimit-Remaining"),
+        header("X-RateLimit-Reset"),
+        header("Retry-After"),
+    ];
+    (res.status() as u16, headers)
+}
+
+#[async_std::test]
+async fn rate_limit_headers_follow_the_quota() {
+    let (node, _) = spawn_node().await;
+    let gw = Gateway::new(&node, Tracer::disabled()).await;
+    gw.update_key(|k| {
+        k.quota_hour = Some(2);
+        k.methods.deny = vec!["eth_sign".to_owned()];
+    });
+    let (status, [limit, remaining, reset, retry]) = rate_limited(&gw, "eth_chainId").await;
+    assert_eq!(
+        (status, limit, remaining, retry),
+        (200, Some(2), Some(1), None)
+    );
+    assert!(reset.is_some_and(|x| x > 0 && x <= 3600));
+    // rejected before the quota is charged, the budget is only shown
+    let (status, [_, remaining, ..]) = rate_limited(&gw, "eth_sign").await;
+    assert_eq!((status, remaining), (403, Some(1)));
+    let (status, [_, remaining, ..]) = rate_limited(&gw, "eth_chainId").await;
+    assert_eq!((status, remaining), (200, Some(0)));
+    let (status, [limit, remaining, reset, retry]) = rate_limited(&gw, "eth_chainId").await;
+    assert_eq!((status, limit, remaining), (429, Some(2), Some(0)));
+    assert_eq!(retry, reset);
+}
+
+#[async_std::test]
+async fn rate_limit_resets_with_the_window() {
+    let (node, _) = spawn_node().await;
+    let gw = Gateway::new(&node, Tracer::disabled()).await;
+    gw.update_key(|k| k.quota_second = Some(1));
+    // the calls start right after the second turns
+    let skip = || {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        Duration::from_millis(1010) - Duration::from_millis(now.subsec_millis() as u64)
+    };
+    async_std::task::sleep(skip()).await;
+    let (status, [_, remaining, reset, _]) = rate_limited(&gw, "eth_chainId").await;
+    assert_eq!((status, remaining, reset), (200, Some(0), Some(1)));
+    let (status, [_, remaining, _, retry]) = rate_limited(&gw, "eth_chainId").await;
+    assert_eq!((status, remaining, retry), (429, Some(0), Some(1)));
+    async_std::task::sleep(skip()).await;
+    let (status, [_, remaining, ..]) = rate_limited(&gw, "eth_chainId").await;
+    assert_eq!((status, remaining), (200, Some(0)));
+}
+
+// key document stored under the key itself, as before the keys were hashed
+fn plain_key(redis: &RedisStub, key: &str) -> RpcKey {
+    let no_quota = || None;
+    let (_, mut doc) = RpcKey::generate(
+        &KeyHasher::new(PEPPER),
+        APP.to_owned(),
+        vec![],
+        None,
+        no_quota(),
+        no_quota(),
+        no_quota(),
+        no_quota(),
+        no_quota(),
+        no_quota(),
+        no_quota(),
+    );
+    // murmur3 hash of the older documents
+    doc.key_hash = "5f3c2a1b".to_owned();
+    redis.set(&format!("rk_a{}_{}", APP, key), &doc);
+    doc
+}
+
+#[async_std::test]
+async fn migrate_moves_plain_keys_and_their_counters() {
+    let (redis, conn) = RedisStub::spawn().await;
+    let hasher = KeyHasher::new(PEPPER);
+    // a plain key may look like a digest
+    let hex_key = "ab".repeat(32);
+    plain_key(&redis, "plainkey");
+    plain_key(&redis, &hex_key);
+    for prefix in ["rq", "ru"] {
+        redis.set(&format!("{}_a{}_plainkey_hour_1", prefix, APP), &3);
+    }
+    let mut keys = RpcKeyStorage::from_redis(&conn).unwrap();
+
+    let mut moved = keys.migrate(APP, &hasher).unwrap();
+    moved.sort();
+    let mut expected = vec![hasher.digest("plainkey"), hasher.digest(&hex_key)];
+    expected.sort();
+    assert_eq!(moved, expected);
+    let digest = hasher.digest("plainkey");
+    assert!(keys.get(APP, "plainkey").is_none());
+    assert!(keys.get(APP, &hex_key).is_none());
+    assert_eq!(keys.get(APP, &digest).unwrap().key_hash, digest);
+    let mut stored: Vec<String> = redis.data.lock().unwrap().keys().cloned().collect();
+    stored.sort();
+    assert!(stored.contains(&format!("rq_a{}_{}_hour_1", APP, digest)));
+    assert!(stored.contains(&format!("ru_a{}_{}_hour_1", APP, digest)));
+    assert!(!stored.iter().any(|x| x.contains("plainkey")));
+
+    // a second run finds nothing left to move
+    assert!(keys.migrate(APP, &hasher).unwrap().is_empty());
+    let mut again: Vec<String> = redis.data.lock().unwrap().keys().cloned().collect();
+    again.sort();
+    assert_eq!(again, stored);
+}
+
+#[async_std::test]
+async fn keys_resolve_by_the_key_or_its_digest() {
+    let (redis, conn) = RedisStub::spawn().await;
+    let hasher = KeyHasher::new(PEPPER);
+    let mut keys = RpcKeyStorage::from_redis(&conn).unwrap();
+    let digest = hasher.digest("secret");
+    let mut doc = plain_key(&redis, &digest);
+    doc.key_hash = digest.clone();
+    keys.set(APP, &digest, &doc).unwrap();
+    assert_eq!(keys.resolve(APP, "secret", &hasher), digest);
+    assert_eq!(keys.resolve(APP, &digest, &hasher), digest);
+
+    // plain keys looking like digests are hashed, stored or not
+    let hex_key = "cd".repeat(32);
+    assert_eq!(
+        keys.resolve(APP, &hex_key, &hasher),
+        hasher.digest(&hex_key)
+    );
+    plain_key(&redis, &hex_key);
+    assert_eq!(
+        keys.resolve(APP, &hex_key, &hasher),
+        hasher.digest(&hex_key)
+    );
+}
@@ -0,0 +1,614 @@
+use async_io::Timer;
+use async_std::future::timeout;
+use async_std::io::{BufReader, Read, Write};
+use async_std::net::TcpStream;
+use async_tls::client::TlsStream;
+use async_tls::TlsConnector;
+use futures_util::task::noop_waker;
+use http_types::{Body, Method, Request, Response, Url};
+use jsonrpc_proto::{ProxyEndpoint, Timeouts};
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+/// Idle connections kept per node
+const MAX_IDLE: usize = 32;
+/// Nodes close idle keep-alive connections, older ones are not reused
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+enum Stream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl Read for Stream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match &mut *self {
+            Self::Plain(x) => Pin::new(x).poll_read(cx, buf),
+            Self::Tls(x) => Pin::new(x.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match &mut *self {
+            Self::Plain(x) => Pin::new(x).poll_write(cx, buf),
+            Self::Tls(x) => Pin::new(x.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut *self {
+            Self::Plain(x) => Pin::new(x).poll_flush(cx),
+            Self::Tls(x) => Pin::new(x.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut *self {
+            Self::Plain(x) => Pin::new(x).poll_close(cx),
+            Self::Tls(x) => Pin::new(x.as_mut()).poll_close(cx),
+        }
+    }
+}
+
+// fails the pending operation once it makes no progress for longer than the timeout
+fn stalled<T>(
+    res: Poll<io::Result<T>>,
+    timer: &mut Option<Timer>,
+    timeout: Duration,
+    cx: &mut Context<'_>,
+    message: &'static str,
+) -> Poll<io::Result<T>> {
+    if res.is_ready() {
+        *timer = None;
+        return res;
+    }
+    let timer = timer.get_or_insert_with(|| Timer::after(timeout));
+    match Pin::new(timer).poll(cx) {
+        Poll::Ready(_) => Poll::Ready(Err(io::Error::new(io::ErrorKind::TimedOut, message))),
+        Poll::Pending => Poll::Pending,
+    }
+}
+
+/// Connection to the node with the read and write timeouts,
+/// each counted from the last progress of the read or write
+struct Timed {
+    stream: Stream,
+    read: Duration,
+    write: Duration,
+    read_timer: Option<Timer>,
+    write_timer: Option<Timer>,
+}
+
+impl Read for Timed {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let res = Pin::new(&mut this.stream).poll_read(cx, buf);
+        stalled(
+            res,
+            &mut this.read_timer,
+            this.read,
+            cx,
+            "upstream read timeout",
+        )
+    }
+}
+
+impl Write for Timed {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let res = Pin::new(&mut this.stream).poll_write(cx, buf);
+        stalled(
+            res,
+            &mut this.write_timer,
+            this.write,
+            cx,
+            "upstream write timeout",
+        )
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let res = Pin::new(&mut this.stream).poll_flush(cx);
+        stalled(
+            res,
+            &mut this.write_timer,
+            this.write,
+            cx,
+            "upstream write timeout",
+        )
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_close(cx)
+    }
+}
+
+struct Idle {
+    stream: Timed,
+    since: Instant,
+}
+
+type Pool = Mutex<HashMap<String, Vec<Idle>>>;
+
+/// Connection lent to the request, it goes back to the pool
+/// when the response was read to the end
+struct Conn {
+    stream: Option<Timed>,
+    origin: String,
+    pool: Weak<Pool>,
+    reusable: Arc<AtomicBool>,
+    // some of the request reached the connection
+    written: Arc<AtomicBool>,
+}
+
+impl Read for Conn {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.stream.as_mut() {
+            Some(x) => Pin::new(x).poll_read(cx, buf),
+            None => Poll::Ready(Ok(0)),
+        }
+    }
+}
+
+impl Write for Conn {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let res = match self.stream.as_mut() {
+            Some(x) => Pin::new(x).poll_write(cx, buf),
+            None => Poll::Ready(Err(io::ErrorKind::NotConnected.into())),
+        };
+        if let Poll::Ready(Ok(n)) = res {
+            if n > 0 {
+                self.written.store(true, Ordering::Release);
+            }
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.stream.as_mut() {
+            Some(x) => Pin::new(x).poll_flush(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.stream.as_mut() {
+            Some(x) => Pin::new(x).poll_close(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+impl Drop for Conn {
+    fn drop(&mut self) {
+        if !self.reusable.load(Ordering::Acquire) {
+            return;
+        }
+        if let (Some(stream), Some(pool)) = (self.stream.take(), self.pool.upgrade()) {
+            let mut pool = pool.lock().expect("lock error");
+            let idle = pool.entry(std::mem::take(&mut self.origin)).or_default();
+            if idle.len() < MAX_IDLE {
+                idle.push(Idle {
+                    stream,
+                    since: Instant::now(),
+                });
+            }
+        }
+    }
+}
+
+/// Response body that marks the connection reusable once it is read to the end
+struct Tail<R> {
+    inner: R,
+    // bytes left when the length is known, the reader stops there without seeing EOF
+    left: Option<usize>,
+    reusable: Arc<AtomicBool>,
+}
+
+impl<R: Read + Unpin> Read for Tail<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            if let Some(left) = self.left.as_mut() {
+                *left = left.saturating_sub(n);
+            }
+            if self.left == Some(0) || (n == 0 && !buf.is_empty()) {
+                self.reusable.store(true, Ordering::Release);
+            }
+        }
+        res
+    }
+}
+
+/// Node could not be reached, the request was not sent to it
+#[derive(Debug)]
+pub struct ConnectError(io::Error);
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "upstream connect error: {}", self.0)
+    }
+}
+
+impl std::error::Error for ConnectError {}
+
+/// Keep-alive HTTP/1.1 client of one application proxy endpoint
+/// with separate connect, write and read timeouts
+struct Client {
+    timeouts: Timeouts,
+    tls: TlsConnector,
+    pool: Arc<Pool>,
+}
+
+fn is_timeout(e: &http_types::Error) -> bool {
+    e.downcast_ref::<io::Error>()
+        .is_some_and(|x| x.kind() == io::ErrorKind::TimedOut)
+}
+
+// the error of the connection tells more than the context async-h1 adds to it
+fn failure(e: http_types::Error) -> anyhow::Error {
+    let mut cause = match e.downcast_ref::<io::Error>() {
+        Some(x) => x,
+        None => return anyhow::Error::msg(e.to_string()),
+    };
+    while let Some(x) = cause
+        .get_ref()
+        .and_then(|x| x.source())
+        .and_then(|x| x.downcast_ref::<io::Error>())
+    {
+        cause = x;
+    }
+    anyhow::Error::msg(cause.to_string())
+}
+
+// idle connection the node has closed, or sent something nobody asked for
+fn is_closed(stream: &mut Stream) -> bool {
+    let waker = noop_waker();
+    let mut cx = Context::from_waker(&waker);
+    let mut buf = [0u8; 1];
+    Pin::new(stream).poll_read(&mut cx, &mut buf).is_ready()
+}
+
+impl Client {
+    fn new(timeouts: &Timeouts) -> Self {
+        Self {
+            timeouts: timeouts.clone(),
+            tls: TlsConnector::default(),
+            pool: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    async fn connect(&self, url: &Url) -> io::Result<Timed> {
+        let host = url
+            .host_str()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "upstream host expected"))?;
+        let port = url.port_or_known_default().unwrap_or(80);
+        let connect = async {
+            let tcp = TcpStream::connect((host, port)).await?;
+            tcp.set_nodelay(true)?;
+            match url.scheme() {
+                "https" => Ok(Stream::Tls(Box::new(self.tls.connect(host, tcp).await?))),
+                _ => Ok::<_, io::Error>(Stream::Plain(tcp)),
+            }
+        };
+        let stream = timeout(Duration::from_secs(self.timeouts.connect), connect)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "upstream connect timeout"))??;
+        Ok(Timed {
+            stream,
+            read: Duration::from_secs(self.timeouts.read),
+            write: Duration::from_secs(self.timeouts.write),
+            read_timer: None,
+            write_timer: None,
+        })
+    }
+
+    fn idle(&self, origin: &str) -> Option<Timed> {
+        let mut pool = self.pool.lock().expect("lock error");
+        let idle = pool.get_mut(origin)?;
+        while let Some(mut x) = idle.pop() {
+            if x.since.elapsed() < IDLE_TIMEOUT && !is_closed(&mut x.stream.stream) {
+                return Some(x.stream);
+            }
+        }
+        None
+    }
+
+    async fn exchange(
+        &self,
+        stream: Timed,
+        origin: &str,
+        req: Request,
+        written: Arc<AtomicBool>,
+    ) -> http_types::Result<Response> {
+        let reusable = Arc::new(AtomicBool::new(false));
+        let conn = Conn {
+            stream: Some(stream),
+            origin: origin.to_owned(),
+            pool: Arc::downgrade(&self.pool),
+            reusable: reusable.clone(),
+            written,
+        };
+        let mut res = async_h1::client::connect(conn, req).await?;
+        let closed = res
+            .header("Connection")
+            .is_some_and(|x| x.as_str().eq_ignore_ascii_case("close"));
+        let body = res.take_body();
+        let len = body.len();
+        let tail = Tail {
+            inner: body,
+            left: len,
+            reusable: if closed {
+                Arc::new(AtomicBool::new(false))
+            } else {
+                reusable
+            },
+        };
+        res.set_body(Body::from_reader(BufReader::new(tail), len));
+        Ok(res)
+    }
+
+    async fn send(&self, req: impl Fn() -> Request, idempotent: bool) -> anyhow::Result<Response> {
+        let first = req();
+        let origin = first.url().origin().ascii_serialization();
+        // the node may close the idle connection while the call is sent,
+        // the call is repeated on a new one unless the node could have got it
+        if let Some(stream) = self.idle(&origin) {
+            let written = Arc::new(AtomicBool::new(false));
+            match self.exchange(stream, &origin, first, written.clone()).await {
+                Ok(x) => return Ok(x),
+                Err(e) if !is_timeout(&e) && (idempotent || !written.load(Ordering::Acquire)) => {}
+                Err(e) => return Err(failure(e)),
+            }
+        }
+        let fresh = req();
+        let stream = self.connect(fresh.url()).await.map_err(ConnectError)?;
+        let written = Arc::new(AtomicBool::new(false));
+        self.exchange(stream, &origin, fresh, written)
+            .await
+            .map_err(failure)
+    }
+}
+
+/// Keep-alive HTTP clients, one per application proxy endpoint.
+/// Client is rebuilt when the endpoint configuration changes
+#[derive(Default)]
+pub struct Upstreams {
+    clients: RwLock<HashMap<String, (ProxyEndpoint, Arc<Client>)>>,
+}
+
+impl Upstreams {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn client(&self, slug: &str, proxy: &ProxyEndpoint) -> Arc<Client> {
+        if let Some((endpoint, client)) = self.clients.read().expect("lock error").get(slug) {
+            if endpoint == proxy {
+                return client.clone();
+            }
+        }
+        let client = Arc::new(Client::new(&proxy.timeouts));
+        self.clients
+            .write()
+            .expect("lock error")
+            .insert(slug.to_owned(), (proxy.clone(), client.clone()));
+        client
+    }
+
+    /// Posts JSON-RPC payload to the upstream URL.
+    /// Response body is streamed and fails if the upstream stalls longer than the read timeout.
+    /// Payload that is not `idempotent` is never sent twice
+    pub async fn post(
+        &self,
+        slug: &str,
+        proxy: &ProxyEndpoint,
+        url: &str,
+        body: &str,
+        traceparent: Option<String>,
+        idempotent: bool,
+    ) -> anyhow::Result<Response> {
+        let client = self.client(slug, proxy);
+        let url = Url::parse(url)?;
+        let traceparent = traceparent.filter(|_| proxy.traceparent);
+        client
+            .send(
+                || {
+                    let mut req = Request::new(Method::Post, url.clone());
+                    req.insert_header("Content-Type", "application/json");
+                    if let Some(traceparent) = &traceparent {
+                        req.insert_header("traceparent", traceparent.as_str());
+                    }
+                    req.set_body(body.to_owned());
+                    req
+                },
+                idempotent,
+            )
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{spawn_node, spawn_silent, HttpNode, Reply};
+    use serde_json::{json, Value};
+
+    const CALL: &str = r#"{"jsonrpc":"2.0","id":1,"method":"eth_blockNumber","params":[]}"#;
+
+    fn proxy(connect: u64, write: u64, read: u64) -> ProxyEndpoint {
+        ProxyEndpoint {
+            path: "/".to_owned(),
+            url: String::new(),
+            strip: false,
+            hosts: vec![],
+            timeouts: Timeouts {
+                connect,
+                write,
+                read,
+            },
+            traceparent: true,
+        }
+    }
+
+    async fn post(upstreams: &Upstreams, url: &str, idempotent: bool) -> anyhow::Result<String> {
+        let proxy = proxy(1, 1, 1);
+        let mut res = upstreams
+            .post("main", &proxy, url, CALL, None, idempotent)
+            .await?;
+        res.body_string()
+            .await
+            .map_err(|e| anyhow::Error::msg(e.to_string()))
+    }
+
+    #[async_std::test]
+    async fn call_reaches_the_node_with_traceparent() {
+        let (url, received) = spawn_node().await;
+        let upstreams = Upstreams::new();
+        let traceparent = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01".to_owned();
+        let mut res = upstreams
+            .post(
+                "main",
+                &proxy(1, 1, 1),
+                &url,
+                CALL,
+                Some(traceparent.clone()),
+                true,
+            )
+            .await
+            .unwrap();
+        let body: Value = res.body_json().await.unwrap();
+        assert_eq!(body["result"], json!("0x64"));
+        let received = received.lock().unwrap();
+        assert_eq!(received[0].0["traceparent"].as_str(), traceparent);
+    }
+
+    #[async_std::test]
+    async fn connection_is_reused() {
+        let node = HttpNode::spawn(vec![]).await;
+        let upstreams = Upstreams::new();
+        for _ in 0..3 {
+            assert_eq!(post(&upstreams, &node.url, true).await.unwrap(), "{}");
+        }
+        assert_eq!(node.requests.load(Ordering::SeqCst), 3);
+        assert_eq!(node.connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[async_std::test]
+    async fn chunked_body_is_read_and_connection_reused() {
+        let node = HttpNode::spawn(vec![Reply::Chunked(&["{\"result\":", "\"0x1\"}"])]).await;
+        let upstreams = Upstreams::new();
+        let body = post(&upstreams, &node.url, true).await.unwrap();
+        assert_eq!(body, "{\"result\":\"0x1\"}");
+        post(&upstreams, &node.url, true).await.unwrap();
+        assert_eq!(node.connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[async_std::test]
+    async fn connection_closed_by_node_is_not_reused() {
+        let node = HttpNode::spawn(vec![Reply::Closing("{}")]).await;
+        let upstreams = Upstreams::new();
+        post(&upstreams, &node.url, false).await.unwrap();
+        async_std::task::sleep(Duration::from_millis(100)).await;
+        // not idempotent, it passes because the closed connection is skipped
+        post(&upstreams, &node.url, false).await.unwrap();
+        assert_eq!(node.requests.load(Ordering::SeqCst), 2);
+        assert_eq!(node.connections.load(Ordering::SeqCst), 2);
+    }
+
+    #[async_std::test]
+    async fn idempotent_call_is_repeated_when_reused_connection_drops() {
+        let node = HttpNode::spawn(vec![Reply::Sized("{}"), Reply::Drop]).await;
+        let upstreams = Upstreams::new();
+        post(&upstreams, &node.url, true).await.unwrap();
+        post(&upstreams, &node.url, true).await.unwrap();
+        assert_eq!(node.requests.load(Ordering::SeqCst), 3);
+        assert_eq!(node.connections.load(Ordering::SeqCst), 2);
+    }
+
+    #[async_std::test]
+    async fn state_changing_call_is_not_sent_twice() {
+        let node = HttpNode::spawn(vec![Reply::Sized("{}"), Reply::Drop]).await;
+        let upstreams = Upstreams::new();
+        post(&upstreams, &node.url, false).await.unwrap();
+        let e = post(&upstreams, &node.url, false).await.unwrap_err();
+        assert!(!e.is::<ConnectError>());
+        assert_eq!(node.requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[async_std::test]
+    async fn read_timeout_fails_the_call() {
+        let node = HttpNode::spawn(vec![Reply::Silent]).await;
+        let started = Instant::now();
+        let e = post(&Upstreams::new(), &node.url, true).await.unwrap_err();
+        assert!(e.to_string().contains("read timeout"), "{}", e);
+        assert!(started.elapsed() < Duration::from_secs(3));
+        assert_eq!(node.requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[async_std::test]
+    async fn write_timeout_fails_the_call() {
+        // the node never reads, the body fills the socket buffers
+        let url = spawn_silent().await.replacen("ws", "http", 1);
+        let body = format!("[{}]", vec![CALL; 1 << 18].join(","));
+        let started = Instant::now();
+        let e = Upstreams::new()
+            .post("main", &proxy(1, 1, 1), &url, &body, None, true)
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("write timeout"), "{}", e);
+        assert!(started.elapsed() < Duration::from_secs(3));
+    }
+
+    #[async_std::test]
+    async fn connect_timeout_is_a_connect_error() {
+        // TLS handshake is never answered
+        let url =
+            spawn_silent()
+                .await
+                .replacen("ws", "https", 1)
+                .replacen("127.0.0.1", "localhost", 1);
+        let started = Instant::now();
+        let e = post(&Upstreams::new(), &url, false).await.unwrap_err();
+        assert!(e.is::<ConnectError>());
+        assert!(e.to_string().contains("connect timeout"), "{}", e);
+        assert!(started.elapsed() < Duration::from_secs(3));
+    }
+}
@@ -0,0 +1,178 @@
+use async_std::io::Read;
+use jsonrpc_proto::jsonrpc::{Request, Response};
+use jsonrpc_proto::redis::AsyncUsageStorage;
+use jsonrpc_proto::{Application, UsageRecord};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tracing::warn;
+
+// error responses are small, longer bodies are results
+const MAX_ERROR_SIZE: usize = 16 * 1024;
+
+/// Counts bytes of the streamed response and checks whether it is a JSON-RPC error.
+/// The callback gets the totals when the body is dropped
+pub struct Metered<R> {
+    inner: R,
+    bytes: u64,
+    // start of the body, kept while it may still be an error response
+    head: Vec<u8>,
+    done: Option<Box<dyn FnOnce(u64, bool) + Send + Sync>>,
+}
+
+impl<R> Metered<R> {
+    pub fn new<F>(inner: R, done: F) -> Self
+    where
+        F: FnOnce(u64, bool) + Send + Sync + 'static,
+    {
+        Self {
+            inner,
+            bytes: 0,
+            head: vec![],
+            done: Some(Box::new(done)),
+        }
+    }
+
+    fn scan(&mut self, chunk: &[u8]) {
+        self.bytes += chunk.len() as u64;
+        if self.bytes <= MAX_ERROR_SIZE as u64 {
+            self.head.extend_from_slice(chunk);
+        } else {
+            self.head.clear();
+        }
+    }
+
+    // body that is not a valid response counts as an error too
+    fn is_error(&self) -> bool {
+        if self.bytes > MAX_ERROR_SIZE as u64 {
+            return false;
+        }
+        serde_json::from_slice::<Response>(&self.head).map_or(true, |x| x.error.is_some())
+    }
+}
+
+impl<R: Read + Unpin> Read for Metered<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let res = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            this.scan(&buf[..n]);
+        }
+        res
+    }
+}
+
+impl<R> Drop for Metered<R> {
+    fn drop(&mut self) {
+        if let Some(done) = self.done.take() {
+            done(self.bytes, self.is_error());
+        }
+    }
+}
+
+/// Usage of the batch calls answered by the upstream, matched by id
+pub fn batch_records(forwarded: &[&Request], responses: &[Response]) -> Vec<UsageRecord> {
+    forwarded
+        .iter()
+        .map(|req| {
+            let bytes_in = serde_json::to_vec(req).map(|x| x.len()).unwrap_or(0) as u64;
+            let id = match &req.id {
+                Some(id) => id,
+                None => return UsageRecord::call(&req.method, false, bytes_in, 0),
+            };
+            match responses.iter().find(|r| r.id == *id) {
+                Some(res) => {
+                    let bytes_out = serde_json::to_vec(res).map(|x| x.len()).unwrap_or(0);
+                    UsageRecord::call(&req.method, res.error.is_some(), bytes_in, bytes_out as u64)
+                }
+                None => UsageRecord::call(&req.method, true, bytes_in, 0),
+            }
+        })
+        .collect()
+}
+
+/// Saves the usage in background, so the response is not delayed.
+/// Methods that the application does not know are counted together
+pub fn spawn_record(
+    storage: AsyncUsageStorage,
+    app: &Application,
+    key: String,
+    records: Vec<UsageRecord>,
+) {
+    let records = records
+        .into_iter()
+        .map(|r| UsageRecord {
+            method: app.method_label(&r.method).to_owned(),
+            ..r
+        })
+        .collect();
+    spawn_save(storage, app.slug.clone(), key, records)
+}
+
+/// Saves the usage in background, the methods of the records are already labeled
+pub fn spawn_save(
+    storage: AsyncUsageStorage,
+    slug: String,
+    key: String,
+    records: Vec<UsageRecord>,
+) {
+    if records.is_empty() {
+        return;
+    }
+    async_std::task::spawn(async move {
+        if let Err(e) = storage.record(&slug, &key, &records).await {
+            warn!("usage storage error: {}", e);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::io::ReadExt;
+    use std::sync::{Arc, Mutex};
+
+    // totals reported by the metered body after it is read to the end
+    fn meter(body: &str) -> (u64, bool) {
+        let totals = Arc::new(Mutex::new(None));
+        let reported = totals.clone();
+        let mut metered = Metered::new(body.as_bytes(), move |bytes, error| {
+            *reported.lock().unwrap() = Some((bytes, error));
+        });
+        let mut out = vec![];
+        async_std::task::block_on(metered.read_to_end(&mut out)).unwrap();
+        drop(metered);
+        let res = totals.lock().unwrap().take();
+        res.unwrap()
+    }
+
+    #[test]
+    fn error_response_is_detected() {
+        let body = r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32000,"message":"x"}}"#;
+        assert_eq!(meter(body), (body.len() as u64, true));
+    }
+
+    #[test]
+    fn error_text_inside_result_is_not_an_error() {
+        let body = r#"{"jsonrpc":"2.0","id":1,"result":{"output":"\"error\":{"}}"#;
+        assert_eq!(meter(body), (body.len() as u64, false));
+    }
+
+    #[test]
+    fn invalid_body_is_an_error() {
+        assert!(meter("<html>bad gateway</html>").1);
+    }
+
+    #[test]
+    fn long_result_is_not_kept() {
+        let body = format!(
+            r#"{{"jsonrpc":"2.0","id":1,"result":"{}"}}"#,
+            "a".repeat(MAX_ERROR_SIZE)
+        );
+        assert_eq!(meter(&body), (body.len() as u64, false));
+    }
+}
@@ -0,0 +1,474 @@
+use crate::api::{self, Caller};
+use crate::batch::{self, Item};
+use crate::limits::{self, BodyError};
+use crate::metrics::RequestLabels;
+use crate::otlp::{self, SpanContext};
+use crate::redact;
+use crate::subscriptions::{Notice, NOTICE_QUEUE};
+use crate::usage;
+use crate::State;
+use async_std::channel::{bounded, Sender};
+use futures_util::{future, StreamExt};
+use jsonrpc_proto::jsonrpc::{self, ErrorObject, Id, Payload, Request as Call, Response};
+use jsonrpc_proto::UsageRecord;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::time::Duration;
+use tide::{Endpoint, Request, StatusCode};
+use tide_websockets::tungstenite::protocol::frame::coding::CloseCode;
+use tide_websockets::tungstenite::protocol::CloseFrame;
+use tide_websockets::{Message, WebSocket, WebSocketConnection};
+use tracing::{debug, error, info, warn};
+
+/// Method of the subscription notifications, used for their cost
+pub const NOTIFICATION_METHOD: &str = "eth_subscription";
+
+fn to_text<T: Serialize>(value: &T) -> String {
+    serde_json::to_string(value).unwrap_or_default()
+}
+
+// same error for every call of the payload, batch of notifications gets none
+fn errors(payload: &Payload, error: ErrorObject) -> Option<String> {
+    if payload.is_batch() {
+        let responses = batch::reject_all(payload, &error);
+        (!responses.is_empty()).then(|| to_text(&responses))
+    } else {
+        Some(to_text(&Response::error(payload.id(), error)))
+    }
+}
+
+/// Reason to end the session
+struct Close {
+    code: CloseCode,
+    reason: String,
+}
+
+impl Close {
+    fn new(code: CloseCode, reason: &str) -> Self {
+        Self {
+            code,
+            reason: reason.to_owned(),
+        }
+    }
+}
+
+/// Message of the client after the checks
+enum Admitted {
+    // error for the whole message
+    Reply(Option<String>),
+    Calls {
+        batch: bool,
+        items: Vec<Item>,
+        records: Vec<UsageRecord>,
+    },
+}
+
+// applies the same method policy, batch limit and quota as the HTTP endpoint
+async fn admit(state: &State, caller: &Caller, text: &str) -> Admitted {
+    let payload = match Payload::parse(text) {
+        Ok(x) => x,
+        Err(e) => return Admitted::Reply(Some(to_text(&Response::from(e)))),
+    };
+    let app = &caller.route.app;
+    let key = redact::key_hash(&caller.key_hash);
+    let max_batch = app.max_batch.unwrap_or(state.max_batch);
+    if payload.size() > max_batch {
+        let error = ErrorObject::new(
+            jsonrpc::LIMIT_EXCEEDED,
+            &format!("batch size exceeds {}", max_batch),
+        );
+        return Admitted::Reply(Some(to_text(&Response::error(Id::Null, error))));
+    }
+    let mut records = vec![];
+    let items = api::deny_methods(state, app, &caller.rpc_key, &payload, &mut records);
+    let items = api::check_limits(state, app, &caller.rpc_key, items, &mut records);
+    let permitted: Vec<&Call> = items
+        .iter()
+        .filter_map(|x| match x {
+            Item::Forward(req) => Some(req),
+            _ => None,
+        })
+        .collect();
+    let cost: u64 = permitted.iter().map(|r| app.method_cost(&r.method)).sum();
+    if cost > 0 {
+        let quota = state
+            .quotas
+            .consume(&app.slug, &caller.key_hash, &caller.rpc_key.quotas(), cost)
+            .await;
+        let exhausted = match quota {
+            Ok(x) => x.filter(|x| x.exhausted),
+            Err(e) => {
+                error!("quota storage error: {}", e);
+                let error = ErrorObject::new(jsonrpc::INTERNAL_ERROR, "internal error");
+                return Admitted::Reply(errors(&payload, error));
+            }
+        };
+        if let Some(exhausted) = exhausted {
+            let window = exhausted.window.name();
+            info!("key = {} quota exceeded per {}", key, window);
+            state.metrics.quota_rejection(&app.slug, window);
+            for req in &permitted {
+                records.push(UsageRecord::call(&req.method, true, 0, 0));
+            }
+            usage::spawn_record(state.usage.clone(), app, caller.key_hash.clone(), records);
+            let message = if cost > exhausted.limit {
+                api::quota_overflow(cost, &exhausted)
+            } else {
+                format!("quota exceeded per {}", window)
+            };
+            let error = ErrorObject::new(jsonrpc::LIMIT_EXCEEDED, &message);
+            return Admitted::Reply(errors(&payload, error));
+        }
+    }
+    debug!(
+        "key = {} app = {} websocket calls = {} cost = {}",
+        key,
+        app.slug,
+        permitted.len(),
+        cost
+    );
+    Admitted::Calls {
+        batch: payload.is_batch(),
+        items,
+        records,
+    }
+}
+
+/// WebSocket client of the gateway
+struct Session<'a> {
+    state: &'a State,
+    caller: Caller,
+    url: String,
+    listener: Sender<Notice>,
+    // subscription ids given to the client
+    subscriptions: HashSet<String>,
+}
+
+impl<'a> Session<'a> {
+    async fn subscribe(&mut self, req: &Call) -> Response {
+        let params = req.params.clone().unwrap_or(Value::Null);
+        let app = &self.caller.route.app;
+        let connect_timeout = Duration::from_secs(app.proxy.timeouts.connect);
+        let subscription = self
+            .state
+            .subscriptions
+            .subscribe(
+                &app.slug,
+                &self.url,
+                connect_timeout,
+                params,
+                self.listener.clone(),
+            )
+            .await;
+        match subscription {
+            Ok(id) => {
+                self.subscriptions.insert(id.clone());
+                Response::result(req.id(), json!(id))
+            }
+            Err(e) => Response::error(req.id(), e),
+        }
+    }
+
+    // clients can only cancel their own subscriptions
+    async fn unsubscribe(&mut self, req: &Call) -> Response {
+        let id = req
+            .params
+            .as_ref()
+            .and_then(|x| x.get(0))
+            .and_then(|x| x.as_str())
+            .unwrap_or_default();
+        let removed = self.subscriptions.remove(id)
+            && self
+                .state
+                .subscriptions
+                .unsubscribe(&self.caller.route.app.slug, &self.url, id)
+                .await;
+        Response::result(req.id(), json!(removed))
+    }
+
+    // subscriptions are answered by the gateway, other calls are sent to the HTTP upstream
+    async fn calls(
+        &mut self,
+        batch: bool,
+        items: Vec<Item>,
+        mut records: Vec<UsageRecord>,
+    ) -> Option<String> {
+        let state = self.state;
+        let mut answered = Vec::with_capacity(items.len());
+        for item in items {
+            let req = match item {
+                Item::Forward(req) if req.id.is_some() => req,
+                x => {
+                    answered.push(x);
+                    continue;
+                }
+            };
+            let response = match req.method.as_str() {
+                "eth_subscribe" => self.subscribe(&req).await,
+                "eth_unsubscribe" => self.unsubscribe(&req).await,
+                _ => {
+                    answered.push(Item::Forward(req));
+                    continue;
+                }
+            };
+            records.push(UsageRecord::call(
+                &req.method,
+                response.error.is_some(),
+                0,
+                0,
+            ));
+            answered.push(Item::Answered(response));
+        }
+        let app = &self.caller.route.app;
+        let (answered, _) = api::pin_head(state, app, answered, &mut records);
+        let (answered, pinned) = api::pin_filters(state, app, answered, &mut records).await;
+        let forwarded: Vec<&Call> = answered
+            .iter()
+            .filter_map(|x| match x {
+                Item::Forward(req) => Some(req),
+                _ => None,
+            })
+            .collect();
+        let mut responses = vec![];
+        if !forwarded.is_empty() {
+            let body = match forwarded.as_slice() {
+                [req] if !batch => to_text(req),
+                list => to_text(&list),
+            };
+            let span = state
+                .tracer
+                .start(None, "jsonrpc.ws_message", otlp::KIND_SERVER);
+            let route = &self.caller.route;
+            let path = &self.caller.path;
+            let pin = pinned.as_deref();
+            let upstream = api::forward(state, route, path, &body, &forwarded, pin, &span);
+            responses = match upstream.await {
+                Some((url, mut res)) => {
+                    let max = self.caller.rpc_key.limits(app).max_response_size;
+                    match limits::read_body(&mut res, max).await {
+                        Ok(body) => {
+                            let responses = batch::parse_upstream(&body, &forwarded);
+                            if res.status().is_success() {
+                                let filters = &state.filters;
+                                filters.track(&app.slug, &url, &forwarded, &responses).await;
+                            }
+                            responses
+                        }
+                        Err(BodyError::Failed(e)) => {
+                            warn!("response of {} is broken: {}", url, e);
+                            batch::reject_calls(&forwarded, &api::response_failed())
+                        }
+                        Err(BodyError::Exceeded) => {
+                            let error = api::response_exceeded(max.unwrap_or_default());
+                            batch::reject_calls(&forwarded, &error)
+                        }
+                    }
+                }
+                None => {
+                    let error = match &pinned {
+                        Some(_) => {
+                            state.filters.forget(&app.slug, &forwarded).await;
+                            ErrorObject::new(
+                                jsonrpc::FILTER_NOT_FOUND,
+                                "filter not found: its upstream node is not available",
+                            )
+                        }
+                        None => ErrorObject::new(
+                            jsonrpc::UPSTREAM_UNAVAILABLE,
+                            "upstream is not available",
+                        ),
+                    };
+                    batch::reject_calls(&forwarded, &error)
+                }
+            };
+            records.extend(usage::batch_records(&forwarded, &responses));
+        }
+        let (storage, app) = (state.usage.clone(), &self.caller.route.app);
+        usage::spawn_record(storage, app, self.caller.key_hash.clone(), records);
+        let merged = batch::merge(answered, responses);
+        match merged.as_slice() {
+            [] => None,
+            [x] if !batch => Some(to_text(x)),
+            list => Some(to_text(&list)),
+        }
+    }
+}
+
+// subscription notifications are counted against the quota of the key,
+// returns the exhausted window if any
+async fn charge(state: &State, caller: &Caller, bytes_out: usize) -> Option<&'static str> {
+    let app = &caller.route.app;
+    let cost = app.method_cost(NOTIFICATION_METHOD);
+    let quota = state
+        .quotas
+        .consume(&app.slug, &caller.key_hash, &caller.rpc_key.quotas(), cost)
+        .await;
+    let record = UsageRecord::call(NOTIFICATION_METHOD, false, 0, bytes_out as u64);
+    match quota {
+        Ok(Some(x)) if x.exhausted => {
+            state.metrics.quota_rejection(&app.slug, x.window.name());
+            Some(x.window.name())
+        }
+        Ok(_) => {
+            usage::spawn_record(
+                state.usage.clone(),
+                app,
+                caller.key_hash.clone(),
+                vec![record],
+            );
+            None
+        }
+        // notifications are not lost because of the storage
+        Err(e) => {
+            error!("quota storage error: {}", e);
+            None
+        }
+    }
+}
+
+// reason to end the session when the key was revoked after the upgrade,
+// the session is kept while the key storage is not available
+async fn revoked(state: &State, caller: &Caller) -> Option<String> {
+    let app = &caller.route.app.slug;
+    let rpc_key = match state.rpckeys.get(app, &caller.key_hash).await {
+        Ok(x) => x,
+        Err(e) => {
+            error!("key storage error: {}", e);
+            return None;
+        }
+    };
+    let denied = api::check_key(state, app, &caller.key_hash, rpc_key).err()?;
+    Some(denied.error.message)
+}
+
+// the application was removed or serves websockets from another url now,
+// subscriptions of the session are not served anymore
+fn moved(state: &State, slug: &str, url: &str) -> bool {
+    let router = state.router.read().expect("lock error");
+    let app = router.apps().iter().find(|a| a.slug == slug);
+    app.and_then(|a| a.ws_url.as_deref()) != Some(url)
+}
+
+/// Upgrades the request to WebSocket once the key is checked
+pub async fn proxy_ws(mut req: Request<State>) -> tide::Result {
+    let parent = req
+        .header("traceparent")
+        .and_then(|x| SpanContext::from_traceparent(x.as_str()));
+    let mut span =
+        req.state()
+            .tracer
+            .start(parent.as_ref(), "jsonrpc.ws_connect", otlp::KIND_SERVER);
+    span.set_str("http.target", &redact::path(req.url().path()));
+    let mut labels = RequestLabels::default();
+    let caller = match api::authorize(&req, &mut labels, &span).await {
+        Ok(x) => x,
+        Err(denied) => {
+            return api::reply(denied.status, &Response::error(Id::Null, denied.error));
+        }
+    };
+    span.set_str("jsonrpc.app", &labels.app);
+    if caller.route.app.ws_url.is_none() {
+        info!("app = {} has no websocket upstream", caller.route.app.slug);
+        let error = ErrorObject::new(jsonrpc::UPSTREAM_UNAVAILABLE, "websocket is not available");
+        return api::reply(StatusCode::NotFound, &Response::error(Id::Null, error));
+    }
+    req.set_ext(caller);
+    WebSocket::new(session).call(req).await
+}
+
+// serves calls of the client and relays notifications of its subscriptions
+async fn session(req: Request<State>, client: WebSocketConnection) -> tide::Result<()> {
+    let caller = match req.ext::<Caller>() {
+        Some(x) => x.clone(),
+        None => return Ok(()),
+    };
+    let state = req.state();
+    let key = redact::key_hash(&caller.key_hash).to_owned();
+    let slug = caller.route.app.slug.clone();
+    let url = caller.route.app.ws_url.clone().unwrap_or_default();
+    let (listener, notices) = bounded::<Notice>(NOTICE_QUEUE);
+    let mut session = Session {
+        state,
+        url: url.clone(),
+        caller,
+        listener,
+        subscriptions: HashSet::new(),
+    };
+    info!("key = {} app = {} websocket connected", key, slug);
+
+    let close = {
+        let caller = session.caller.clone();
+        let inbound = async {
+            let mut client_rx = client.clone();
+            while let Some(msg) = client_rx.next().await {
+                let text = match msg {
+                    Ok(Message::Text(x)) => x,
+                    Ok(Message::Close(_)) | Err(_) => break,
+                    Ok(_) => continue,
+                };
+                let reply = match admit(state, &session.caller, &text).await {
+                    Admitted::Reply(x) => x,
+                    Admitted::Calls {
+                        batch,
+                        items,
+                        records,
+                    } => session.calls(batch, items, records).await,
+                };
+                if let Some(reply) = reply {
+                    if client.send_string(reply).await.is_err() {
+                        break;
+                    }
+                }
+            }
+            Close::new(CloseCode::Normal, "client closed")
+        };
+        let outbound = async {
+            while let Ok(notice) = notices.recv().await {
+                let text = notice.to_text();
+                if let Some(window) = charge(state, &caller, text.len()).await {
+                    let reason = format!("quota exceeded per {}", window);
+                    return Close::new(CloseCode::Policy, &reason);
+                }
+                if client.send_string(text).await.is_err() {
+                    break;
+                }
+            }
+            Close::new(CloseCode::Normal, "client closed")
+        };
+        // the key is checked again, same as every HTTP request does
+        let watch = async {
+            loop {
+                async_std::task::sleep(state.key_interval).await;
+                if let Some(reason) = revoked(state, &caller).await {
+                    return Close::new(CloseCode::Policy, &reason);
+                }
+                if moved(state, &slug, &url) {
+                    return Close::new(CloseCode::Restart, "upstream changed");
+                }
+            }
+        };
+        futures_util::pin_mut!(inbound, outbound, watch);
+        let relay = async { future::select(outbound, watch).await.factor_first().0 };
+        futures_util::pin_mut!(relay);
+        future::select(inbound, relay).await.factor_first().0
+    };
+    // subscriptions of the client are dropped with the connection
+    for id in &session.subscriptions {
+        state
+            .subscriptions
+            .unsubscribe(&slug, &session.url, id)
+            .await;
+    }
+    info!("key = {} websocket closed: {}", key, close.reason);
+    close_session(&client, close).await
+}
+
+async fn close_session(client: &WebSocketConnection, close: Close) -> tide::Result<()> {
+    let frame = CloseFrame {
+        code: close.code,
+        reason: close.reason.into(),
+    };
+    let _ = client.send(Message::Close(Some(frame))).await;
+    Ok(())
+}
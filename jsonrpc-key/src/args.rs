@@ -1,9 +1,12 @@
+use clap::arg_enum;
 use jsonrpc_proto::formatter::OutputFormat;
+use jsonrpc_proto::{parse_pepper, Limits, UsageWindow};
 use structopt::StructOpt;
 use tracing_subscriber::prelude::*;
 
 #[derive(StructOpt, Debug, Clone)]
 pub enum Command {
+    /// Generates a new key of the application
     Gen {
         #[structopt(short, long)]
         app: String,
@@ -25,6 +28,12 @@ pub enum Command {
         quota_month: Option<u64>,
         #[structopt(name = "per-year", long)]
         quota_year: Option<u64>,
+        #[structopt(name = "allow-method", long)]
+        allow_method: Vec<String>,
+        #[structopt(name = "deny-method", long)]
+        deny_method: Vec<String>,
+        #[structopt(flatten)]
+        limits: Limits,
     },
     Get {
         #[structopt(short, long)]
@@ -32,6 +41,7 @@ pub enum Command {
         #[structopt(short, long)]
         key: String,
     },
+    /// Changes the given settings of the key
     Update {
         #[structopt(short, long)]
         app: String,
@@ -57,6 +67,12 @@ pub enum Command {
         quota_month: Option<u64>,
         #[structopt(name = "per-year", long)]
         quota_year: Option<u64>,
+        #[structopt(name = "allow-method", long)]
+        allow_method: Vec<String>,
+        #[structopt(name = "deny-method", long)]
+        deny_method: Vec<String>,
+        #[structopt(flatten)]
+        limits: Limits,
     },
     List {
         #[structopt(short, long)]
@@ -64,6 +80,46 @@ pub enum Command {
         #[structopt(short, long)]
         tag: Vec<String>,
     },
+    Usage {
+        #[structopt(short, long)]
+        app: String,
+        #[structopt(short, long)]
+        key: String,
+        /// First day of the report as YYYY-MM-DD, start of the month by default
+        #[structopt(long)]
+        from: Option<String>,
+        /// Last day of the report as YYYY-MM-DD, today by default
+        #[structopt(long)]
+        to: Option<String>,
+        /// Counters to read: hour, day or month buckets that cover the days of the report
+        #[structopt(
+            long,
+            default_value = "day",
+            possible_values = &UsageWindow::variants(),
+            case_insensitive = true,
+        )]
+        window: UsageWindow,
+        #[structopt(
+            long,
+            default_value = "method",
+            possible_values = &UsageGroup::variants(),
+            case_insensitive = true,
+        )]
+        group_by: UsageGroup,
+    },
+    /// Rehashes keys stored in plain text by the earlier versions
+    Migrate {
+        #[structopt(short, long)]
+        app: Option<String>,
+    },
+}
+
+arg_enum! {
+    #[derive(Debug, Clone, Copy)]
+    pub enum UsageGroup {
+        Method,
+        Bucket,
+    }
 }
 
 #[derive(Debug, StructOpt, Clone)]
@@ -81,6 +137,14 @@ pub struct Args {
     pub redis_db: u32,
     #[structopt(long, env = "REDIS_TLS")]
     pub redis_tls: bool,
+    /// Secret for hashing the keys, must be the same as in the gateway
+    #[structopt(
+        long,
+        env = "KEY_PEPPER",
+        hide_env_values = true,
+        parse(try_from_str = parse_pepper)
+    )]
+    pub key_pepper: String,
 
     #[structopt(
         short,
@@ -111,6 +175,10 @@ pub fn parse() -> anyhow::Result<Args> {
         .init();
 
     let res = Args::from_args();
-    tracing::debug!("{:?}", res);
+    // secrets are not logged
+    let mut shown = res.clone();
+    shown.redis_password = "***".to_owned();
+    shown.key_pepper = "***".to_owned();
+    tracing::debug!("{:?}", shown);
     Ok(res)
 }
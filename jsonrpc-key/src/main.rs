@@ -1,7 +1,18 @@
 pub mod args;
+use chrono::{Datelike, NaiveDate, Utc};
 use jsonrpc_proto::formatter::Formatter;
-use jsonrpc_proto::redis::{AppStorage, RedisConnection, RpcKeyStorage};
-use jsonrpc_proto::{RpcKey, RpcKeyAction, RpcKeyResponse, RpcResponseStatus};
+use jsonrpc_proto::redis::{AppStorage, RedisConnection, RpcKeyStorage, UsageStorage};
+use jsonrpc_proto::{
+    KeyHasher, RpcKey, RpcKeyAction, RpcKeyResponse, RpcResponseStatus, UsageCounters, UsageRow,
+};
+use std::collections::BTreeMap;
+
+fn parse_day(value: Option<String>, default: NaiveDate) -> Option<NaiveDate> {
+    match value {
+        Some(x) => NaiveDate::parse_from_str(&x, "%Y-%m-%d").ok(),
+        None => Some(default),
+    }
+}
 
 fn main() -> anyhow::Result<()> {
     let args = match args::parse() {
@@ -9,6 +20,7 @@ fn main() -> anyhow::Result<()> {
         Err(e) => return Err(anyhow::Error::msg(format!("args parsing error {}", e))),
     };
     let fmt = Formatter::new(args.format);
+    let hasher = KeyHasher::new(&args.key_pepper);
     let conn = RedisConnection {
         host: args.redis_host,
         port: args.redis_port,
@@ -38,16 +50,20 @@ fn main() -> anyhow::Result<()> {
             quota_week,
             quota_month,
             quota_year,
+            allow_method,
+            deny_method,
+            limits,
         } => {
             let app_str = app.clone();
-            let a = match apps.get(&app) {
+            let a = match apps.get(&app)? {
                 Some(x) => x,
                 None => return fmt.fail("application not found"),
             };
             if !a.active {
                 return fmt.fail("application is not active");
             }
-            let doc = RpcKey::generate(
+            let (key, mut doc) = RpcKey::generate(
+                &hasher,
                 app,
                 tag,
                 expires,
@@ -59,29 +75,32 @@ fn main() -> anyhow::Result<()> {
                 quota_month,
                 quota_year,
             );
-            if let Err(e) = keys.set(&app_str, &doc.key_id, &doc) {
+            doc.methods.update(allow_method, deny_method);
+            doc.limits.update(limits);
+            if let Err(e) = keys.set(&app_str, &doc.key_hash, &doc) {
                 return fmt.wrap_error(e);
             }
-            return fmt.out(&RpcKeyResponse::Add {
+            fmt.out(&RpcKeyResponse::Add {
                 action: RpcKeyAction::Add,
                 status: RpcResponseStatus::OK,
-                key: doc.key_id,
+                key,
                 key_hash: doc.key_hash,
-            });
+            })
         }
         args::Command::Get { app, key } => {
-            if let None = apps.get(&app) {
+            if apps.get(&app)?.is_none() {
                 return fmt.fail("application not found");
             };
+            let key = keys.resolve(&app, &key, &hasher);
             let k = match keys.get(&app, &key) {
                 Some(x) => x,
                 None => return fmt.fail("key not found"),
             };
-            return fmt.out(&RpcKeyResponse::Get {
+            fmt.out(&RpcKeyResponse::Get {
                 action: RpcKeyAction::Add,
                 status: RpcResponseStatus::OK,
                 key: k,
-            });
+            })
         }
         args::Command::Update {
             app,
@@ -96,10 +115,14 @@ fn main() -> anyhow::Result<()> {
             quota_week,
             quota_month,
             quota_year,
+            allow_method,
+            deny_method,
+            limits,
         } => {
-            if let None = apps.get(&app) {
+            if apps.get(&app)?.is_none() {
                 return fmt.fail("application not found");
             };
+            let key = keys.resolve(&app, &key, &hasher);
             let mut doc = match keys.get(&app, &key) {
                 Some(x) => x,
                 None => return fmt.fail("key not found"),
@@ -131,6 +154,8 @@ fn main() -> anyhow::Result<()> {
             if let Some(quota_year) = quota_year {
                 doc.quota_year = Some(quota_year)
             }
+            doc.methods.update(allow_method, deny_method);
+            doc.limits.update(limits);
             for t in tag {
                 match t.get(..1) {
                     Some("-") => {
@@ -148,22 +173,99 @@ fn main() -> anyhow::Result<()> {
             if let Err(e) = keys.set(&app, &key, &doc) {
                 return fmt.wrap_error(e);
             }
-            let updated = keys.get(&app, &key).map(|x| x).unwrap();
-            return fmt.out(&RpcKeyResponse::Get {
+            let updated = keys.get(&app, &key).unwrap();
+            fmt.out(&RpcKeyResponse::Get {
                 action: RpcKeyAction::Add,
                 status: RpcResponseStatus::OK,
                 key: updated,
-            });
+            })
         }
         args::Command::List { app, .. } => {
-            if let None = apps.get(&app) {
+            if apps.get(&app)?.is_none() {
                 return fmt.fail("application not found");
             };
-            return fmt.out(&RpcKeyResponse::List {
+            fmt.out(&RpcKeyResponse::List {
                 action: RpcKeyAction::List,
                 status: RpcResponseStatus::OK,
                 keys: keys.scan(&app),
-            });
+            })
+        }
+        args::Command::Usage {
+            app,
+            key,
+            from,
+            to,
+            window,
+            group_by,
+        } => {
+            if apps.get(&app)?.is_none() {
+                return fmt.fail("application not found");
+            };
+            let today = Utc::today().naive_utc();
+            let (from, to) = match (
+                parse_day(from, today.with_day(1).unwrap()),
+                parse_day(to, today),
+            ) {
+                (Some(from), Some(to)) if from <= to => (from, to),
+                (Some(_), Some(_)) => return fmt.fail("from should not be after to"),
+                _ => return fmt.fail("dates should be YYYY-MM-DD"),
+            };
+            let key_hash = keys.resolve(&app, &key, &hasher);
+            let mut usage = match UsageStorage::from_redis(&conn) {
+                Ok(x) => x,
+                Err(e) => return fmt.wrap_error(e),
+            };
+            let mut groups: BTreeMap<String, UsageCounters> = BTreeMap::new();
+            let mut total = UsageCounters::default();
+            for bucket in window.buckets(from, to) {
+                let records = match usage.bucket(&app, &key_hash, window, &bucket) {
+                    Ok(x) => x,
+                    Err(e) => return fmt.wrap_error(e),
+                };
+                for r in records {
+                    let group = match group_by {
+                        args::UsageGroup::Method => r.method,
+                        args::UsageGroup::Bucket => bucket.clone(),
+                    };
+                    groups.entry(group).or_default().add(&r.counters);
+                    total.add(&r.counters);
+                }
+            }
+            fmt.out(&RpcKeyResponse::Usage {
+                action: RpcKeyAction::Usage,
+                status: RpcResponseStatus::OK,
+                from: from.to_string(),
+                to: to.to_string(),
+                window,
+                usage: groups
+                    .into_iter()
+                    .map(|(group, counters)| UsageRow { group, counters })
+                    .collect(),
+                total,
+            })
+        }
+        args::Command::Migrate { app } => {
+            let slugs = match app {
+                Some(x) => vec![x],
+                None => apps.scan()?,
+            };
+            let mut res = vec![];
+            for app in slugs {
+                if apps.get(&app)?.is_none() {
+                    return fmt.fail("application not found");
+                };
+                let migrated = match keys.migrate(&app, &hasher) {
+                    Ok(x) => x,
+                    Err(e) => return fmt.wrap_error(e),
+                };
+                res.push(RpcKeyResponse::Migrate {
+                    action: RpcKeyAction::Migrate,
+                    status: RpcResponseStatus::OK,
+                    app,
+                    keys: migrated,
+                });
+            }
+            fmt.out(&res)
         }
     }
 }
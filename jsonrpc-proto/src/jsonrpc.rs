@@ -0,0 +1,245 @@
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{Number, Value};
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+
+// gateway errors, from the server error range
+pub const LIMIT_EXCEEDED: i64 = -32005;
+pub const ACCESS_DENIED: i64 = -32009;
+pub const KEY_INACTIVE: i64 = -32010;
+pub const KEY_EXPIRED: i64 = -32011;
+pub const KEY_WRONG_APP: i64 = -32012;
+pub const APP_NOT_FOUND: i64 = -32013;
+pub const UPSTREAM_UNAVAILABLE: i64 = -32014;
+pub const METHOD_NOT_ALLOWED: i64 = -32015;
+pub const FILTER_NOT_FOUND: i64 = -32016;
+
+/// Request id, `Null` is a valid id that differs from the missing one.
+/// Numbers are kept as sent, including the large and fractional ones
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Id {
+    Number(Number),
+    String(String),
+    #[default]
+    Null,
+}
+
+// keeps `"id": null` as Some(Id::Null) while the missing id stays None
+fn deserialize_some<'de, D>(deserializer: D) -> Result<Option<Id>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Id::deserialize(deserializer).map(Some)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Request {
+    pub jsonrpc: String,
+    pub method: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub params: Option<Value>,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_some",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub id: Option<Id>,
+}
+
+impl Request {
+    /// Notifications have no id and expect no response
+    pub fn is_notification(&self) -> bool {
+        self.id.is_none()
+    }
+
+    pub fn id(&self) -> Id {
+        self.id.clone().unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ErrorObject {
+    pub code: i64,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+impl ErrorObject {
+    pub fn new(code: i64, message: &str) -> Self {
+        Self {
+            code,
+            message: message.to_owned(),
+            data: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Response {
+    pub jsonrpc: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorObject>,
+    pub id: Id,
+}
+
+impl Response {
+    pub fn result(id: Id, result: Value) -> Self {
+        Self {
+            jsonrpc: "2.0".to_owned(),
+            result: Some(result),
+            error: None,
+            id,
+        }
+    }
+
+    pub fn error(id: Id, error: ErrorObject) -> Self {
+        Self {
+            jsonrpc: "2.0".to_owned(),
+            result: None,
+            error: Some(error),
+            id,
+        }
+    }
+}
+
+/// Invalid payload, with the id to echo in the error response
+#[derive(Debug, Clone)]
+pub struct PayloadError {
+    pub id: Id,
+    pub error: ErrorObject,
+}
+
+impl From<PayloadError> for Response {
+    fn from(e: PayloadError) -> Self {
+        Response::error(e.id, e.error)
+    }
+}
+
+/// Single request or a batch.
+/// Invalid items of the batch are kept in place to be answered individually
+#[derive(Debug, Clone)]
+pub enum Payload {
+    Single(Request),
+    Batch(Vec<Result<Request, PayloadError>>),
+}
+
+impl Payload {
+    /// Parses and validates the request body.
+    /// On failure returns the error with the id of the request when it could be read
+    pub fn parse(body: &str) -> Result<Self, PayloadError> {
+        let value: Value = serde_json::from_str(body).map_err(|_| PayloadError {
+            id: Id::Null,
+            error: ErrorObject::new(PARSE_ERROR, "parse error"),
+        })?;
+        match value {
+            Value::Array(items) => {
+                if items.is_empty() {
+                    return Err(invalid_request(Id::Null));
+                }
+                Ok(Self::Batch(items.into_iter().map(parse_request).collect()))
+            }
+            v => parse_request(v).map(Self::Single),
+        }
+    }
+
+    /// Valid requests of the payload
+    pub fn requests(&self) -> Vec<&Request> {
+        match self {
+            Self::Single(x) => vec![x],
+            Self::Batch(items) => items.iter().filter_map(|x| x.as_ref().ok()).collect(),
+        }
+    }
+
+    pub fn is_batch(&self) -> bool {
+        matches!(self, Self::Batch(_))
+    }
+
+    /// Number of calls in the payload, including the invalid ones
+    pub fn size(&self) -> usize {
+        match self {
+            Self::Single(_) => 1,
+            Self::Batch(items) => items.len(),
+        }
+    }
+
+    /// Id to echo in errors about the whole payload, batches use null
+    pub fn id(&self) -> Id {
+        match self {
+            Self::Single(x) => x.id(),
+            Self::Batch(_) => Id::Null,
+        }
+    }
+}
+
+fn invalid_request(id: Id) -> PayloadError {
+    PayloadError {
+        id,
+        error: ErrorObject::new(INVALID_REQUEST, "invalid request"),
+    }
+}
+
+fn parse_request(value: Value) -> Result<Request, PayloadError> {
+    let id = value
+        .get("id")
+        .and_then(|x| serde_json::from_value::<Id>(x.clone()).ok())
+        .unwrap_or_default();
+    match serde_json::from_value::<Request>(value) {
+        Ok(req) if req.jsonrpc == "2.0" => Ok(req),
+        _ => Err(invalid_request(id)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn single(body: &str) -> Request {
+        match Payload::parse(body).expect("valid payload") {
+            Payload::Single(x) => x,
+            Payload::Batch(_) => panic!("single request expected"),
+        }
+    }
+
+    #[test]
+    fn numeric_ids_are_echoed_as_sent() {
+        for id in ["18446744073709551615", "-7", "1.5"] {
+            let body = format!(r#"{{"jsonrpc":"2.0","method":"eth_chainId","id":{}}}"#, id);
+            let res = Response::result(single(&body).id(), json!("0x1"));
+            let text = serde_json::to_string(&res).unwrap();
+            assert!(text.contains(&format!(r#""id":{}"#, id)), "{}", text);
+        }
+    }
+
+    #[test]
+    fn null_id_differs_from_missing() {
+        let req = single(r#"{"jsonrpc":"2.0","method":"eth_chainId","id":null}"#);
+        assert_eq!(req.id, Some(Id::Null));
+        let req = single(r#"{"jsonrpc":"2.0","method":"eth_chainId"}"#);
+        assert!(req.is_notification());
+    }
+
+    #[test]
+    fn invalid_batch_items_keep_their_ids() {
+        let body =
+            r#"[{"jsonrpc":"1.0","method":"x","id":"a"},{"jsonrpc":"2.0","method":"y","id":2}]"#;
+        match Payload::parse(body).unwrap() {
+            Payload::Batch(items) => {
+                assert_eq!(
+                    items[0].as_ref().unwrap_err().id,
+                    Id::String("a".to_owned())
+                );
+                assert_eq!(items[1].as_ref().unwrap().id, Some(Id::Number(2.into())));
+            }
+            Payload::Single(_) => panic!("batch expected"),
+        }
+    }
+}
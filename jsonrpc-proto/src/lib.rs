@@ -1,19 +1,291 @@
 pub mod formatter;
+pub mod jsonrpc;
 pub mod redis;
 
-use murmur3::murmur3_32;
-use std::io::Cursor;
+use clap::arg_enum;
 
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use hmac::{Hmac, Mac, NewMac};
 use rand::Rng;
 use serde::{Deserialize, Serialize};
+use sha2::Sha256;
 use slug::slugify;
+use std::collections::BTreeMap;
 use std::time::{SystemTime, UNIX_EPOCH};
+use structopt::StructOpt;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Upstream timeouts in seconds
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Timeouts {
+    pub connect: u64,
+    pub write: u64,
+    pub read: u64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: 5,
+            write: 5,
+            read: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ProxyEndpoint {
     pub path: String,
     pub url: String,
     pub strip: bool,
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    #[serde(default)]
+    pub timeouts: Timeouts,
+    /// Upstream accepts W3C `traceparent` header
+    #[serde(default)]
+    pub traceparent: bool,
+}
+
+/// Upstream node of the application.
+/// Nodes with lower priority value are used first, weight splits traffic inside one priority
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Upstream {
+    pub url: String,
+    pub weight: u32,
+    pub priority: u32,
+}
+
+impl Upstream {
+    pub fn new(url: String) -> Self {
+        Self {
+            url,
+            weight: 1,
+            priority: 0,
+        }
+    }
+}
+
+/// Label of the methods that are not known
+pub const OTHER_METHOD: &str = "other";
+
+/// Standard Ethereum JSON-RPC methods
+pub const KNOWN_METHODS: &[&str] = &[
+    "web3_clientVersion",
+    "web3_sha3",
+    "net_version",
+    "net_listening",
+    "net_peerCount",
+    "eth_protocolVersion",
+    "eth_syncing",
+    "eth_coinbase",
+    "eth_chainId",
+    "eth_mining",
+    "eth_hashrate",
+    "eth_gasPrice",
+    "eth_maxPriorityFeePerGas",
+    "eth_feeHistory",
+    "eth_accounts",
+    "eth_blockNumber",
+    "eth_getBalance",
+    "eth_getStorageAt",
+    "eth_getTransactionCount",
+    "eth_getBlockTransactionCountByHash",
+    "eth_getBlockTransactionCountByNumber",
+    "eth_getUncleCountByBlockHash",
+    "eth_getUncleCountByBlockNumber",
+    "eth_getCode",
+    "eth_sign",
+    "eth_signTransaction",
+    "eth_sendTransaction",
+    "eth_sendRawTransaction",
+    "eth_call",
+    "eth_estimateGas",
+    "eth_createAccessList",
+    "eth_getProof",
+    "eth_getBlockByHash",
+    "eth_getBlockByNumber",
+    "eth_getTransactionByHash",
+    "eth_getTransactionByBlockHashAndIndex",
+    "eth_getTransactionByBlockNumberAndIndex",
+    "eth_getTransactionReceipt",
+    "eth_getUncleByBlockHashAndIndex",
+    "eth_getUncleByBlockNumberAndIndex",
+    "eth_newFilter",
+    "eth_newBlockFilter",
+    "eth_newPendingTransactionFilter",
+    "eth_uninstallFilter",
+    "eth_getFilterChanges",
+    "eth_getFilterLogs",
+    "eth_getLogs",
+    "eth_getWork",
+    "eth_submitWork",
+    "eth_submitHashrate",
+    "eth_subscribe",
+    "eth_unsubscribe",
+    "eth_subscription",
+    "debug_traceTransaction",
+    "debug_traceCall",
+    "debug_traceBlockByHash",
+    "debug_traceBlockByNumber",
+    "trace_block",
+    "trace_call",
+    "trace_filter",
+    "trace_transaction",
+    "trace_replayTransaction",
+    "trace_replayBlockTransactions",
+    "txpool_content",
+    "txpool_inspect",
+    "txpool_status",
+];
+
+arg_enum! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum Balancing {
+        RoundRobin,
+        LeastLatency,
+    }
+}
+
+// arg_enum! does not accept #[default] on variants
+#[allow(clippy::derivable_impls)]
+impl Default for Balancing {
+    fn default() -> Self {
+        Self::RoundRobin
+    }
+}
+
+// simple glob match, `*` stands for any sequence of characters
+fn wildcard_match(pattern: &str, value: &str) -> bool {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    if parts.len() == 1 {
+        return pattern == value;
+    }
+    let (first, last) = (parts[0], parts[parts.len() - 1]);
+    if !value.starts_with(first) || !value.ends_with(last) || value.len() < first.len() + last.len()
+    {
+        return false;
+    }
+    // both ends were matched, the cuts fall on character boundaries
+    let mut rest = match value.get(first.len()..value.len() - last.len()) {
+        Some(x) => x,
+        None => return false,
+    };
+    for part in &parts[1..parts.len() - 1] {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+/// Method allow and deny patterns, such as `eth_*`.
+/// Deny wins, empty allow list lets everything else through
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MethodPolicy {
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+impl MethodPolicy {
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    pub fn permits(&self, method: &str) -> bool {
+        if self.deny.iter().any(|p| wildcard_match(p, method)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|p| wildcard_match(p, method))
+    }
+
+    /// Applies CLI arguments: patterns are added, `-pattern` removes it
+    /// and `!pattern` in the allow list is the same as denying it
+    pub fn update(&mut self, allow: Vec<String>, deny: Vec<String>) {
+        fn apply(list: &mut Vec<String>, pattern: String) {
+            match pattern.strip_prefix('-') {
+                Some(excluded) => list.retain(|x| x != excluded),
+                None => {
+                    if !list.contains(&pattern) {
+                        list.push(pattern)
+                    }
+                }
+            }
+        }
+        for p in allow {
+            match p.strip_prefix('!') {
+                Some(denied) => apply(&mut self.deny, denied.to_owned()),
+                None => apply(&mut self.allow, p),
+            }
+        }
+        for p in deny {
+            apply(&mut self.deny, p);
+        }
+    }
+}
+
+// Limits of the heavy calls, unset limits are not enforced.
+// Limits of the key win over the limits of the application.
+// Not a doc comment: flattened into the CLI commands, it would become their about text
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, StructOpt)]
+pub struct Limits {
+    /// Blocks in the range of `eth_getLogs`, `eth_newFilter` and `trace_filter`, 0 for no limit
+    #[structopt(long)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_block_range: Option<u64>,
+    /// Addresses in the filter of `eth_getLogs`, `eth_newFilter` and `trace_filter`, 0 for no limit
+    #[structopt(long)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_addresses: Option<usize>,
+    /// Topics in the filter of `eth_getLogs` and `eth_newFilter`, 0 for no limit
+    #[structopt(long)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_topics: Option<usize>,
+    /// Bytes of the upstream response, 0 for no limit
+    #[structopt(long)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_response_size: Option<u64>,
+}
+
+impl Limits {
+    /// Applies CLI arguments. 0 is kept: on the key it lifts the limit of the application too
+    pub fn update(&mut self, other: Limits) {
+        fn apply<T>(limit: &mut Option<T>, value: Option<T>) {
+            if value.is_some() {
+                *limit = value;
+            }
+        }
+        apply(&mut self.max_block_range, other.max_block_range);
+        apply(&mut self.max_addresses, other.max_addresses);
+        apply(&mut self.max_topics, other.max_topics);
+        apply(&mut self.max_response_size, other.max_response_size);
+    }
+
+    /// Limits that are not set are taken from the fallback
+    pub fn or(&self, fallback: &Limits) -> Limits {
+        Limits {
+            max_block_range: self.max_block_range.or(fallback.max_block_range),
+            max_addresses: self.max_addresses.or(fallback.max_addresses),
+            max_topics: self.max_topics.or(fallback.max_topics),
+            max_response_size: self.max_response_size.or(fallback.max_response_size),
+        }
+    }
+
+    /// Limits to enforce, 0 stands for no limit
+    pub fn enforced(&self) -> Limits {
+        fn limit<T: Default + PartialEq + Copy>(value: Option<T>) -> Option<T> {
+            value.filter(|x| *x != T::default())
+        }
+        Limits {
+            max_block_range: limit(self.max_block_range),
+            max_addresses: limit(self.max_addresses),
+            max_topics: limit(self.max_topics),
+            max_response_size: limit(self.max_response_size),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -22,30 +294,153 @@ pub struct Application {
     pub slug: String,
     pub proxy: ProxyEndpoint,
     pub active: bool,
+    #[serde(default)]
+    pub upstreams: Vec<Upstream>,
+    #[serde(default)]
+    pub balancing: Balancing,
+    /// Maximum calls in one batch, gateway default is used when not set
+    #[serde(default)]
+    pub max_batch: Option<usize>,
+    /// Default method policy for the keys that have none
+    #[serde(default)]
+    pub methods: MethodPolicy,
+    /// Compute units per method or pattern, methods that are not listed cost 1
+    #[serde(default)]
+    pub costs: BTreeMap<String, u64>,
+    /// Upstream WebSocket endpoint for subscriptions, such as `ws://node:8546`
+    #[serde(default)]
+    pub ws_url: Option<String>,
+    /// `latest` block of the calls is pinned to the head agreed by the nodes
+    #[serde(default)]
+    pub pin_head: bool,
+    #[serde(default)]
+    pub limits: Limits,
 }
 
 impl Application {
-    pub fn new(name: &str, slug: Option<String>, path: String, url: String, strip: bool) -> Self {
+    pub fn new(
+        name: &str,
+        slug: Option<String>,
+        path: String,
+        url: String,
+        strip: bool,
+        hosts: Vec<String>,
+    ) -> Self {
         Self {
             name: name.to_owned(),
             slug: match slug {
                 Some(x) => x,
                 None => slugify(name),
             },
-            proxy: ProxyEndpoint { path, url, strip },
+            proxy: ProxyEndpoint {
+                path,
+                url,
+                strip,
+                hosts,
+                timeouts: Timeouts::default(),
+                traceparent: false,
+            },
             active: true,
+            upstreams: vec![],
+            balancing: Balancing::default(),
+            max_batch: None,
+            methods: MethodPolicy::default(),
+            costs: BTreeMap::new(),
+            ws_url: None,
+            pin_head: false,
+            limits: Limits::default(),
         }
     }
+
+    /// Compute units of the method call. Exact name wins,
+    /// otherwise the longest matching pattern is used
+    pub fn method_cost(&self, method: &str) -> u64 {
+        if let Some(cost) = self.costs.get(method) {
+            return *cost;
+        }
+        self.costs
+            .iter()
+            .filter(|(pattern, _)| wildcard_match(pattern, method))
+            .max_by_key(|(pattern, _)| pattern.len())
+            .map(|(_, cost)| *cost)
+            .unwrap_or(1)
+    }
+
+    /// Name of the method for the usage and metrics. Clients may send any name,
+    /// so only the standard methods and the ones named by the application are kept
+    pub fn method_label<'a>(&self, method: &'a str) -> &'a str {
+        let policy = &self.methods;
+        let known = KNOWN_METHODS.contains(&method)
+            || self.costs.contains_key(method)
+            || policy
+                .allow
+                .iter()
+                .chain(policy.deny.iter())
+                .any(|x| x == method);
+        if known {
+            method
+        } else {
+            OTHER_METHOD
+        }
+    }
+
+    /// Upstream nodes of the application,
+    /// applications without the list are served by `proxy.url` alone
+    pub fn upstreams(&self) -> Vec<Upstream> {
+        if self.upstreams.is_empty() {
+            vec![Upstream::new(self.proxy.url.clone())]
+        } else {
+            self.upstreams.clone()
+        }
+    }
+}
+
+/// Validates the pepper given on the command line,
+/// digests of an empty pepper could be computed by anyone
+pub fn parse_pepper(s: &str) -> Result<String, String> {
+    if s.is_empty() {
+        return Err("key pepper must not be empty".to_owned());
+    }
+    Ok(s.to_owned())
 }
 
+/// Hashes RPC keys with HMAC-SHA256 and the server-side pepper,
+/// so the storage never keeps the keys themselves
+#[derive(Clone)]
+pub struct KeyHasher {
+    pepper: Vec<u8>,
+}
+
+impl KeyHasher {
+    pub fn new(pepper: &str) -> Self {
+        Self {
+            pepper: pepper.as_bytes().to_vec(),
+        }
+    }
+
+    /// Hex encoded digest of the key
+    pub fn digest(&self, key: &str) -> String {
+        let mut mac =
+            Hmac::<Sha256>::new_varkey(&self.pepper).expect("HMAC accepts keys of any size");
+        mac.update(key.as_bytes());
+        mac.finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+/// RPC key document, stored under `key_hash`.
+/// The key itself is only returned once, when it is generated
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RpcKey {
-    pub key_id: String,
     pub key_hash: String,
     pub app: String,
     pub tags: Vec<String>,
     pub expires: u64,
     pub active: bool,
+    // quotas are in compute units of the application cost table
     pub quota_second: Option<u64>,
     pub quota_minute: Option<u64>,
     pub quota_hour: Option<u64>,
@@ -53,10 +448,17 @@ pub struct RpcKey {
     pub quota_week: Option<u64>,
     pub quota_month: Option<u64>,
     pub quota_year: Option<u64>,
+    #[serde(default)]
+    pub methods: MethodPolicy,
+    #[serde(default)]
+    pub limits: Limits,
 }
 
 impl RpcKey {
+    /// Returns the new key and its document
+    #[allow(clippy::too_many_arguments)]
     pub fn generate(
+        hasher: &KeyHasher,
         app: String,
         tag: Vec<String>,
         expires: Option<u64>,
@@ -67,7 +469,7 @@ impl RpcKey {
         quota_week: Option<u64>,
         quota_month: Option<u64>,
         quota_year: Option<u64>,
-    ) -> Self {
+    ) -> (String, Self) {
         const CHARSET: &[u8] = b"abcdefghijkmnpqrstuvwxyz0123456789";
         let mut rng = rand::thread_rng();
         let key_id: String = (0..32)
@@ -76,10 +478,8 @@ impl RpcKey {
                 CHARSET[idx] as char
             })
             .collect();
-        let hash_bytes = murmur3_32(&mut Cursor::new(key_id.as_bytes()), 0).expect("hashing error");
-        let key_hash = format!("{:x}", hash_bytes);
-        Self {
-            key_id,
+        let key_hash = hasher.digest(&key_id);
+        let doc = Self {
             key_hash,
             app,
             tags: tag.clone(),
@@ -100,8 +500,192 @@ impl RpcKey {
             quota_month,
             quota_year,
             active: true,
+            methods: MethodPolicy::default(),
+            limits: Limits::default(),
+        };
+        (key_id, doc)
+    }
+}
+
+impl RpcKey {
+    /// Method policy of the key, falling back to the application default
+    pub fn method_policy<'a>(&'a self, app: &'a Application) -> &'a MethodPolicy {
+        if self.methods.is_empty() {
+            &app.methods
+        } else {
+            &self.methods
         }
     }
+
+    /// Limits of the key, completed by the limits of the application
+    pub fn limits(&self, app: &Application) -> Limits {
+        self.limits.or(&app.limits).enforced()
+    }
+
+    /// Returns the list of configured quota windows with their limits
+    pub fn quotas(&self) -> Vec<(QuotaWindow, u64)> {
+        vec![
+            (QuotaWindow::Second, self.quota_second),
+            (QuotaWindow::Minute, self.quota_minute),
+            (QuotaWindow::Hour, self.quota_hour),
+            (QuotaWindow::Day, self.quota_day),
+            (QuotaWindow::Week, self.quota_week),
+            (QuotaWindow::Month, self.quota_month),
+            (QuotaWindow::Year, self.quota_year),
+        ]
+        .into_iter()
+        .filter_map(|(w, limit)| limit.map(|x| (w, x)))
+        .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum QuotaWindow {
+    Second,
+    Minute,
+    Hour,
+    Day,
+    Week,
+    Month,
+    Year,
+}
+
+impl QuotaWindow {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Second => "second",
+            Self::Minute => "minute",
+            Self::Hour => "hour",
+            Self::Day => "day",
+            Self::Week => "week",
+            Self::Month => "month",
+            Self::Year => "year",
+        }
+    }
+
+    /// Length of the window in seconds.
+    /// Months are counted as 30 days and years as 365 days
+    pub fn seconds(&self) -> u64 {
+        match self {
+            Self::Second => 1,
+            Self::Minute => 60,
+            Self::Hour => 3600,
+            Self::Day => 86400,
+            Self::Week => 604800,
+            Self::Month => 2592000,
+            Self::Year => 31536000,
+        }
+    }
+}
+
+arg_enum! {
+    /// Calendar buckets of the usage counters
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub enum UsageWindow {
+        Hour,
+        Day,
+        Month,
+    }
+}
+
+impl UsageWindow {
+    pub const ALL: [UsageWindow; 3] = [Self::Hour, Self::Day, Self::Month];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Hour => "hour",
+            Self::Day => "day",
+            Self::Month => "month",
+        }
+    }
+
+    /// Bucket of the time, such as `2021061512`, `20210615` or `202106`
+    pub fn bucket(&self, at: DateTime<Utc>) -> String {
+        match self {
+            Self::Hour => at.format("%Y%m%d%H"),
+            Self::Day => at.format("%Y%m%d"),
+            Self::Month => at.format("%Y%m"),
+        }
+        .to_string()
+    }
+
+    /// Buckets that cover the days from `from` to `to`, both included
+    pub fn buckets(&self, from: NaiveDate, to: NaiveDate) -> Vec<String> {
+        let mut res: Vec<String> = vec![];
+        for day in from.iter_days().take_while(|x| *x <= to) {
+            let start = DateTime::<Utc>::from_utc(day.and_hms(0, 0, 0), Utc);
+            let hours = match self {
+                Self::Hour => 24,
+                _ => 1,
+            };
+            for hour in 0..hours {
+                let bucket = self.bucket(start + Duration::hours(hour));
+                if res.last() != Some(&bucket) {
+                    res.push(bucket);
+                }
+            }
+        }
+        res
+    }
+
+    /// How long the bucket is kept after the last update, months are kept forever
+    pub fn ttl(&self) -> Option<u64> {
+        match self {
+            Self::Hour => Some(QuotaWindow::Month.seconds() + QuotaWindow::Week.seconds()),
+            Self::Day => Some(QuotaWindow::Year.seconds() * 2),
+            Self::Month => None,
+        }
+    }
+}
+
+/// Usage counters of a key
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct UsageCounters {
+    pub calls: u64,
+    pub errors: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+impl UsageCounters {
+    pub fn add(&mut self, other: &UsageCounters) {
+        self.calls += other.calls;
+        self.errors += other.errors;
+        self.bytes_in += other.bytes_in;
+        self.bytes_out += other.bytes_out;
+    }
+}
+
+/// Usage of a single method
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UsageRecord {
+    pub method: String,
+    #[serde(flatten)]
+    pub counters: UsageCounters,
+}
+
+impl UsageRecord {
+    pub fn call(method: &str, error: bool, bytes_in: u64, bytes_out: u64) -> Self {
+        Self {
+            method: method.to_owned(),
+            counters: UsageCounters {
+                calls: 1,
+                errors: error as u64,
+                bytes_in,
+                bytes_out,
+            },
+        }
+    }
+}
+
+/// Row of the usage report, grouped by method or by bucket
+#[derive(Debug, Clone, Serialize)]
+pub struct UsageRow {
+    pub group: String,
+    #[serde(flatten)]
+    pub counters: UsageCounters,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -119,8 +703,12 @@ pub enum RpcKeyAction {
     Get,
     List,
     Update,
+    Usage,
+    Migrate,
 }
 
+// printed once by the CLI, boxing the key would not pay off
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone, Serialize)]
 pub enum RpcKeyResponse {
     Add {
@@ -139,4 +727,154 @@ pub enum RpcKeyResponse {
         action: RpcKeyAction,
         key: RpcKey,
     },
+    Usage {
+        status: RpcResponseStatus,
+        action: RpcKeyAction,
+        from: String,
+        to: String,
+        window: UsageWindow,
+        usage: Vec<UsageRow>,
+        total: UsageCounters,
+    },
+    Migrate {
+        status: RpcResponseStatus,
+        action: RpcKeyAction,
+        app: String,
+        keys: Vec<String>,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcard_prefix_suffix_and_middle() {
+        assert!(wildcard_match("eth_*", "eth_call"));
+        assert!(!wildcard_match("eth_*", "net_version"));
+        assert!(wildcard_match("*_subscribe", "eth_subscribe"));
+        assert!(!wildcard_match("*_subscribe", "eth_unsubscribe_x"));
+        assert!(wildcard_match("eth_*Filter*", "eth_newBlockFilter"));
+        assert!(wildcard_match("eth_*Filter*", "eth_getFilterChanges"));
+        assert!(!wildcard_match("eth_*Filter*", "eth_getLogs"));
+        assert!(!wildcard_match("a*a", "a"));
+    }
+
+    #[test]
+    fn wildcard_alone_and_exact() {
+        assert!(wildcard_match("*", ""));
+        assert!(wildcard_match("*", "anything"));
+        assert!(wildcard_match("eth_call", "eth_call"));
+        assert!(!wildcard_match("eth_call", "eth_callx"));
+    }
+
+    #[test]
+    fn wildcard_non_ascii() {
+        assert!(!wildcard_match("é*", "eé"));
+        assert!(!wildcard_match("*é", "éa"));
+        assert!(wildcard_match("ab*é", "abé"));
+        assert!(!wildcard_match("*ab", "éx"));
+        assert!(wildcard_match("ü*ß", "über_groß"));
+        assert!(wildcard_match("*л*", "eth_влад"));
+        assert!(!wildcard_match("x*", "日本"));
+    }
+
+    #[test]
+    fn unknown_methods_are_labeled_other() {
+        let mut app = Application::new(
+            "test",
+            None,
+            "/".to_owned(),
+            "http://node".to_owned(),
+            false,
+            vec![],
+        );
+        app.costs.insert("bor_getAuthor".to_owned(), 2);
+        app.costs.insert("debug_*".to_owned(), 10);
+        app.methods
+            .deny
+            .push("parity_pendingTransactions".to_owned());
+        assert_eq!(app.method_label("eth_call"), "eth_call");
+        assert_eq!(app.method_label("bor_getAuthor"), "bor_getAuthor");
+        assert_eq!(
+            app.method_label("parity_pendingTransactions"),
+            "parity_pendingTransactions"
+        );
+        assert_eq!(app.method_label("debug_whatever"), OTHER_METHOD);
+        assert_eq!(app.method_label("eth_call\n"), OTHER_METHOD);
+        assert_eq!(app.method_label("random_42"), OTHER_METHOD);
+    }
+
+    #[test]
+    fn usage_buckets_cover_the_days() {
+        let from = NaiveDate::from_ymd(2021, 6, 30);
+        let to = NaiveDate::from_ymd(2021, 7, 1);
+        let hours = UsageWindow::Hour.buckets(from, to);
+        assert_eq!(hours.len(), 48);
+        assert_eq!(hours[0], "2021063000");
+        assert_eq!(hours[47], "2021070123");
+        assert_eq!(UsageWindow::Day.buckets(from, to), ["20210630", "20210701"]);
+        assert_eq!(UsageWindow::Month.buckets(from, to), ["202106", "202107"]);
+        assert!(UsageWindow::Day.buckets(to, from).is_empty());
+    }
+
+    #[test]
+    fn empty_pepper_is_rejected() {
+        assert!(parse_pepper("").is_err());
+        assert_eq!(parse_pepper("s3cret"), Ok("s3cret".to_owned()));
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let mut policy = MethodPolicy::default();
+        policy.update(vec!["eth_*".to_owned(), "!eth_send*".to_owned()], vec![]);
+        assert!(policy.permits("eth_call"));
+        assert!(!policy.permits("eth_sendRawTransaction"));
+        assert!(!policy.permits("debug_traceTransaction"));
+    }
+
+    #[test]
+    fn zero_limit_of_the_key_lifts_the_app_limit() {
+        let mut app = Application::new("main", None, "/".to_owned(), String::new(), false, vec![]);
+        app.limits.update(Limits {
+            max_block_range: Some(1000),
+            max_topics: Some(4),
+            ..Limits::default()
+        });
+        let hasher = KeyHasher::new("pepper");
+        let no_quota = || None;
+        let (_, mut key) = RpcKey::generate(
+            &hasher,
+            "main".to_owned(),
+            vec![],
+            None,
+            no_quota(),
+            no_quota(),
+            no_quota(),
+            no_quota(),
+            no_quota(),
+            no_quota(),
+            no_quota(),
+        );
+        assert_eq!(key.limits(&app).max_block_range, Some(1000));
+
+        key.limits.update(Limits {
+            max_block_range: Some(0),
+            max_addresses: Some(10),
+            ..Limits::default()
+        });
+        let limits = key.limits(&app);
+        assert_eq!(limits.max_block_range, None);
+        assert_eq!(limits.max_addresses, Some(10));
+        assert_eq!(limits.max_topics, Some(4));
+
+        // arguments that are not given keep the limits
+        key.limits.update(Limits::default());
+        assert_eq!(key.limits.max_block_range, Some(0));
+        app.limits.update(Limits {
+            max_topics: Some(0),
+            ..Limits::default()
+        });
+        assert_eq!(key.limits(&app).max_topics, None);
+    }
 }
@@ -1,6 +1,14 @@
-use crate::{Application, RpcKey};
+use crate::{Application, KeyHasher, QuotaWindow, RpcKey, UsageCounters, UsageRecord, UsageWindow};
+use chrono::Utc;
+use deadpool::managed::{Manager, Pool, RecycleResult};
 use serde::{de, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::error;
 
+#[derive(Clone)]
 pub struct RedisConnection {
     pub host: String,
     pub port: u32,
@@ -10,19 +18,94 @@ pub struct RedisConnection {
     pub use_tls: bool,
 }
 
+/// Channel where the applications storage announces updated application slugs
+pub const APP_CHANGES_CHANNEL: &str = "app_changes";
+
+const QUOTA_PREFIX: &str = "rq_";
+const USAGE_PREFIX: &str = "ru_";
+const CACHE_PREFIX: &str = "rc_";
+const FILTER_PREFIX: &str = "rf_";
+
+fn client(info: &RedisConnection) -> redis::Client {
+    let uri_scheme = if info.use_tls { "rediss" } else { "redis" };
+    redis::Client::open(format!(
+        "{}://{}:{}@{}:{}/{}",
+        uri_scheme, info.username, info.password, info.host, info.port, info.db
+    ))
+    .unwrap()
+}
+
+fn connect(info: &RedisConnection) -> anyhow::Result<redis::Connection> {
+    Ok(client(info).get_connection()?)
+}
+
+pub struct RedisManager {
+    client: redis::Client,
+}
+
+#[async_trait::async_trait]
+impl Manager for RedisManager {
+    type Type = redis::aio::Connection;
+    type Error = redis::RedisError;
+
+    async fn create(&self) -> Result<Self::Type, Self::Error> {
+        self.client.get_async_std_connection().await
+    }
+
+    async fn recycle(&self, con: &mut Self::Type) -> RecycleResult<Self::Error> {
+        redis::cmd("PING").query_async::<_, ()>(con).await?;
+        Ok(())
+    }
+}
+
+/// Pool of async connections, cheap to clone and shared by the async storages
+#[derive(Clone)]
+pub struct RedisPool {
+    pool: Pool<RedisManager>,
+}
+
+impl RedisPool {
+    pub async fn from_redis(info: &RedisConnection, size: usize) -> anyhow::Result<Self> {
+        let manager = RedisManager {
+            client: client(info),
+        };
+        let pool: Pool<RedisManager> = Pool::builder(manager).max_size(size).build()?;
+        let mut con = pool.get().await?; // connect and ping to check we are connected
+        redis::cmd("PING").query_async::<_, ()>(&mut *con).await?;
+        Ok(Self { pool })
+    }
+
+    /// Missing and unreadable documents are `None`, pool and Redis errors are returned
+    pub async fn get<T>(&self, key: &str) -> anyhow::Result<Option<T>>
+    where
+        T: de::DeserializeOwned,
+    {
+        let mut con = self.pool.get().await?;
+        let rval = redis::cmd("GET")
+            .arg(key)
+            .query_async::<_, Option<String>>(&mut *con)
+            .await?;
+        let rval = match rval {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        match serde_json::from_str::<T>(&rval) {
+            Ok(v) => Ok(Some(v)),
+            Err(e) => {
+                error!("document parsing error: {} in key {}", e, key);
+                Ok(None)
+            }
+        }
+    }
+}
+
 struct RedisStorage {
     con: redis::Connection,
 }
 
-impl<'de> RedisStorage {
+impl RedisStorage {
     pub fn from_redis(info: &RedisConnection) -> anyhow::Result<Self> {
-        let uri_scheme = if info.use_tls { "rediss" } else { "redis" };
-        let client = redis::Client::open(format!(
-            "{}://{}:{}@{}:{}/{}",
-            uri_scheme, info.username, info.password, info.host, info.port, info.db
-        ))
-        .unwrap();
-        let mut con = client.get_connection()?;
+        let mut con = connect(info)?;
         let _: () = redis::cmd("PING").query(&mut con).unwrap(); // ping to check we are connected
         Ok(Self { con })
     }
@@ -32,7 +115,18 @@ impl<'de> RedisStorage {
         T: Serialize,
     {
         let val = serde_json::to_string(v)?;
-        redis::cmd("SET").arg(key).arg(val).query(&mut self.con)?;
+        redis::cmd("SET")
+            .arg(key)
+            .arg(val)
+            .query::<()>(&mut self.con)?;
+        Ok(())
+    }
+
+    pub fn publish(&mut self, channel: &str, message: &str) -> anyhow::Result<()> {
+        redis::cmd("PUBLISH")
+            .arg(channel)
+            .arg(message)
+            .query::<()>(&mut self.con)?;
         Ok(())
     }
 
@@ -40,42 +134,83 @@ impl<'de> RedisStorage {
     where
         T: de::DeserializeOwned,
     {
-        match redis::cmd("GET").arg(key).query::<String>(&mut self.con) {
-            Ok(rval) => match serde_json::from_str::<T>(&rval) {
-                Ok(v) => Some(v),
-                Err(e) => {
-                    println!("PARSING ERR: {} in key {}", e, key);
-                    None
-                }
-            },
-            Err(_) => None,
+        self.try_get(key).unwrap_or_default()
+    }
+
+    /// Reads the document, storage errors are returned
+    /// and the document that does not parse is reported as missing
+    pub fn try_get<T>(&mut self, key: &str) -> anyhow::Result<Option<T>>
+    where
+        T: de::DeserializeOwned,
+    {
+        let rval = redis::cmd("GET")
+            .arg(key)
+            .query::<Option<String>>(&mut self.con)?;
+        let rval = match rval {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        match serde_json::from_str::<T>(&rval) {
+            Ok(v) => Ok(Some(v)),
+            Err(e) => {
+                println!("PARSING ERR: {} in key {}", e, key);
+                Ok(None)
+            }
         }
     }
 
     pub fn scan(&mut self, prefix: &str) -> Vec<String> {
-        match redis::cmd("SCAN")
-            .cursor_arg(0)
-            .arg("MATCH")
-            .arg(format!("{}*", prefix))
-            .arg("COUNT")
-            .arg(1000000)
-            .clone()
-            .iter::<String>(&mut self.con)
-        {
-            Ok(arr) => {
-                let mut res: Vec<String> = vec![];
-                for x in arr {
-                    let k = x.chars().skip(prefix.len()).collect();
-                    res.push(k);
-                }
-                res
-            }
+        match self.try_scan(prefix) {
+            Ok(x) => x,
             Err(e) => {
                 println!("SCAN2 ERR: {}", e);
                 vec![]
             }
         }
     }
+
+    /// Keys with the prefix, without it. Fails instead of returning a partial list
+    pub fn try_scan(&mut self, prefix: &str) -> anyhow::Result<Vec<String>> {
+        let mut res = vec![];
+        let mut cursor = 0u64;
+        loop {
+            let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(format!("{}*", prefix))
+                .arg("COUNT")
+                .arg(1000000)
+                .query(&mut self.con)?;
+            res.extend(
+                keys.into_iter()
+                    .map(|x| x.chars().skip(prefix.len()).collect()),
+            );
+            if next == 0 {
+                return Ok(res);
+            }
+            cursor = next;
+        }
+    }
+
+    pub fn del(&mut self, key: &str) -> anyhow::Result<()> {
+        redis::cmd("DEL").arg(key).query::<()>(&mut self.con)?;
+        Ok(())
+    }
+
+    /// Renames every key with the prefix to the same key with the new prefix
+    pub fn rename_prefix(&mut self, from: &str, to: &str) -> anyhow::Result<()> {
+        for rest in self.scan(from) {
+            redis::cmd("RENAME")
+                .arg(format!("{}{}", from, rest))
+                .arg(format!("{}{}", to, rest))
+                .query::<()>(&mut self.con)?;
+        }
+        Ok(())
+    }
+
+    pub fn hgetall(&mut self, key: &str) -> anyhow::Result<HashMap<String, u64>> {
+        Ok(redis::cmd("HGETALL").arg(key).query(&mut self.con)?)
+    }
 }
 
 pub struct AppStorage {
@@ -94,13 +229,43 @@ impl AppStorage {
         format!("{}{}", self.prefix, key)
     }
     pub fn set(&mut self, key: &str, v: &Application) -> anyhow::Result<()> {
-        self.kv.set(&self.realkey(key), v)
+        self.kv.set(&self.realkey(key), v)?;
+        self.kv.publish(APP_CHANGES_CHANNEL, key)
+    }
+    pub fn get(&mut self, key: &str) -> anyhow::Result<Option<Application>> {
+        self.kv.try_get(&self.realkey(key))
     }
-    pub fn get(&mut self, key: &str) -> Option<Application> {
-        self.kv.get(&self.realkey(key))
+    pub fn scan(&mut self) -> anyhow::Result<Vec<String>> {
+        self.kv.try_scan(&self.prefix)
+    }
+}
+
+/// Subscription to the applications changes, published on every `AppStorage::set`
+pub struct AppChanges {
+    con: redis::Connection,
+}
+
+impl AppChanges {
+    pub fn from_redis(info: &RedisConnection) -> anyhow::Result<Self> {
+        Ok(Self {
+            con: connect(info)?,
+        })
     }
-    pub fn scan(&mut self) -> Vec<String> {
-        self.kv.scan(&self.prefix)
+    /// Blocks and calls `f` with the slug of every updated application
+    /// until the connection is lost or a callback fails.
+    /// `ready` is called once the subscription is active
+    pub fn listen<R, F>(&mut self, ready: R, mut f: F) -> anyhow::Result<()>
+    where
+        R: FnOnce() -> anyhow::Result<()>,
+        F: FnMut(String) -> anyhow::Result<()>,
+    {
+        let mut pubsub = self.con.as_pubsub();
+        pubsub.subscribe(APP_CHANGES_CHANNEL)?;
+        ready()?;
+        loop {
+            let msg = pubsub.get_message()?;
+            f(msg.get_payload()?)?;
+        }
     }
 }
 
@@ -125,8 +290,446 @@ impl RpcKeyStorage {
     pub fn get(&mut self, app: &str, key: &str) -> Option<RpcKey> {
         self.kv.get(&self.realkey(app, key))
     }
+    /// Names of the keys of the application. Slugs may start with the slug of another
+    /// application and its separator, so the stored key tells which application it belongs to
     pub fn scan(&mut self, app: &str) -> Vec<String> {
         let p = format!("{}a{}_", self.prefix, app);
-        self.kv.scan(&p)
+        self.kv
+            .scan(&p)
+            .into_iter()
+            .filter(|name| self.get(app, name).is_some_and(|doc| doc.app == app))
+            .collect()
+    }
+    /// Name of the key given either as the key itself or as its digest.
+    /// Only a stored document named after its own hash was given by the digest,
+    /// a key that merely looks like one is hashed
+    pub fn resolve(&mut self, app: &str, key: &str, hasher: &KeyHasher) -> String {
+        match self.get(app, key) {
+            Some(doc) if doc.key_hash == key => key.to_owned(),
+            _ => hasher.digest(key),
+        }
+    }
+    /// Moves the keys of the application stored under the key itself to their digests,
+    /// together with their quota and usage counters. Returns digests of the moved keys
+    pub fn migrate(&mut self, app: &str, hasher: &KeyHasher) -> anyhow::Result<Vec<String>> {
+        let mut res = vec![];
+        for name in self.scan(app) {
+            // migrated documents are named after their hash
+            let mut doc = match self.get(app, &name) {
+                Some(x) if x.key_hash != name => x,
+                _ => continue,
+            };
+            doc.key_hash = hasher.digest(&name);
+            self.set(app, &doc.key_hash, &doc)?;
+            for prefix in [QUOTA_PREFIX, USAGE_PREFIX] {
+                self.kv.rename_prefix(
+                    &format!("{}a{}_{}_", prefix, app, name),
+                    &format!("{}a{}_{}_", prefix, app, doc.key_hash),
+                )?;
+            }
+            self.kv.del(&self.realkey(app, &name))?;
+            res.push(doc.key_hash);
+        }
+        Ok(res)
+    }
+}
+
+/// Read-only access to the RPC keys for the gateway, using the connection pool
+#[derive(Clone)]
+pub struct AsyncRpcKeyStorage {
+    prefix: String,
+    kv: RedisPool,
+}
+
+impl AsyncRpcKeyStorage {
+    pub fn new(kv: RedisPool) -> Self {
+        Self {
+            prefix: "rk_".to_owned(),
+            kv,
+        }
+    }
+    fn realkey(&self, app: &str, key: &str) -> String {
+        format!("{}a{}_{}", self.prefix, app, key)
+    }
+    pub async fn get(&self, app: &str, key: &str) -> anyhow::Result<Option<RpcKey>> {
+        self.kv.get(&self.realkey(app, key)).await
+    }
+}
+
+// checks every window first and only then increments all of them,
+// so a rejected request is not counted against the budget.
+// returns 1-based index of the exhausted window or 0 when allowed,
+// followed by the units used in every window
+const QUOTA_SCRIPT: &str = r"
+local cost = tonumber(ARGV[1])
+local used = {}
+for i = 1, #KEYS do
+    used[i] = tonumber(redis.call('GET', KEYS[i]) or '0')
+end
+for i = 1, #KEYS do
+    if used[i] + cost > tonumber(ARGV[i * 2]) then
+        return {i, unpack(used)}
+    end
+end
+for i = 1, #KEYS do
+    used[i] = redis.call('INCRBY', KEYS[i], cost)
+    redis.call('EXPIRE', KEYS[i], ARGV[i * 2 + 1])
+end
+return {0, unpack(used)}
+";
+
+/// Budget of the tightest quota window after the request
+#[derive(Debug, Clone)]
+pub struct QuotaUsage {
+    pub window: QuotaWindow,
+    pub limit: u64,
+    pub remaining: u64,
+    /// Seconds until the window is reset
+    pub reset: u64,
+    /// The request was rejected because of this window
+    pub exhausted: bool,
+}
+
+/// Fixed window compute unit counters, shared between all gateway replicas
+#[derive(Clone)]
+pub struct QuotaStorage {
+    prefix: String,
+    kv: RedisPool,
+    script: redis::Script,
+}
+
+impl QuotaStorage {
+    pub fn new(kv: RedisPool) -> Self {
+        Self {
+            prefix: QUOTA_PREFIX.to_owned(),
+            kv,
+            script: redis::Script::new(QUOTA_SCRIPT),
+        }
+    }
+    fn realkey(&self, app: &str, key: &str, window: &QuotaWindow, bucket: u64) -> String {
+        format!(
+            "{}a{}_{}_{}_{}",
+            self.prefix,
+            app,
+            key,
+            window.name(),
+            bucket
+        )
+    }
+    /// Deducts `cost` from every window of the key if all of them have enough budget left.
+    /// Returns the exhausted window when the request is rejected,
+    /// otherwise the window with the least budget left. None for the keys without quotas
+    pub async fn consume(
+        &self,
+        app: &str,
+        key: &str,
+        limits: &[(QuotaWindow, u64)],
+        cost: u64,
+    ) -> anyhow::Result<Option<QuotaUsage>> {
+        if limits.is_empty() {
+            return Ok(None);
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs();
+        let mut invocation = self.script.prepare_invoke();
+        invocation.arg(cost);
+        for (window, limit) in limits {
+            let bucket = now / window.seconds();
+            invocation
+                .key(self.realkey(app, key, window, bucket))
+                .arg(*limit)
+                .arg(window.seconds());
+        }
+        let mut con = self.kv.pool.get().await?;
+        let res: Vec<u64> = invocation.invoke_async(&mut *con).await?;
+        let mut usage = windows(limits, res.iter().skip(1).copied(), now);
+        let exhausted = res.first().copied().unwrap_or(0) as usize;
+        Ok(match exhausted {
+            0 => usage.min_by_key(|x| (x.remaining, x.reset)),
+            i => usage.nth(i - 1).map(|x| QuotaUsage {
+                exhausted: true,
+                ..x
+            }),
+        })
+    }
+
+    /// Budget of the tightest window, the counters are only read
+    pub async fn peek(
+        &self,
+        app: &str,
+        key: &str,
+        limits: &[(QuotaWindow, u64)],
+    ) -> anyhow::Result<Option<QuotaUsage>> {
+        if limits.is_empty() {
+            return Ok(None);
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs();
+        let keys: Vec<String> = limits
+            .iter()
+            .map(|(window, _)| self.realkey(app, key, window, now / window.seconds()))
+            .collect();
+        let mut con = self.kv.pool.get().await?;
+        let used: Vec<Option<u64>> = redis::cmd("MGET").arg(keys).query_async(&mut *con).await?;
+        let used = used.into_iter().map(Option::unwrap_or_default);
+        Ok(windows(limits, used, now).min_by_key(|x| (x.remaining, x.reset)))
+    }
+}
+
+// budget left in every window once `used` units of it are spent
+fn windows<'a>(
+    limits: &'a [(QuotaWindow, u64)],
+    used: impl Iterator<Item = u64> + 'a,
+    now: u64,
+) -> impl Iterator<Item = QuotaUsage> + 'a {
+    limits
+        .iter()
+        .zip(used)
+        .map(move |((window, limit), used)| QuotaUsage {
+            window: *window,
+            limit: *limit,
+            remaining: limit.saturating_sub(used),
+            reset: window.seconds() - now % window.seconds(),
+            exhausted: false,
+        })
+}
+
+fn usage_key(prefix: &str, app: &str, key: &str, window: UsageWindow, bucket: &str) -> String {
+    format!("{}a{}_{}_{}_{}", prefix, app, key, window.name(), bucket)
+}
+
+// usage hashes keep the counters of every method as `{method}:{counter}` fields
+fn usage_records(fields: HashMap<String, u64>) -> Vec<UsageRecord> {
+    let mut methods: BTreeMap<String, UsageCounters> = BTreeMap::new();
+    for (field, value) in fields {
+        let (method, counter) = match field.rsplit_once(':') {
+            Some(x) => x,
+            None => continue,
+        };
+        let counters = methods.entry(method.to_owned()).or_default();
+        match counter {
+            "calls" => counters.calls = value,
+            "errors" => counters.errors = value,
+            "bytes_in" => counters.bytes_in = value,
+            "bytes_out" => counters.bytes_out = value,
+            _ => {}
+        }
+    }
+    methods
+        .into_iter()
+        .map(|(method, counters)| UsageRecord { method, counters })
+        .collect()
+}
+
+/// Per key usage counters for the reports
+pub struct UsageStorage {
+    prefix: String,
+    kv: RedisStorage,
+}
+
+impl UsageStorage {
+    pub fn from_redis(info: &RedisConnection) -> anyhow::Result<Self> {
+        Ok(Self {
+            prefix: USAGE_PREFIX.to_owned(),
+            kv: RedisStorage::from_redis(info)?,
+        })
+    }
+    /// Usage of the key per method in the bucket of the window, see `UsageWindow::buckets`
+    pub fn bucket(
+        &mut self,
+        app: &str,
+        key: &str,
+        window: UsageWindow,
+        bucket: &str,
+    ) -> anyhow::Result<Vec<UsageRecord>> {
+        let realkey = usage_key(&self.prefix, app, key, window, bucket);
+        Ok(usage_records(self.kv.hgetall(&realkey)?))
+    }
+}
+
+/// Usage counters updated by the gateway, using the connection pool
+#[derive(Clone)]
+pub struct AsyncUsageStorage {
+    prefix: String,
+    kv: RedisPool,
+}
+
+impl AsyncUsageStorage {
+    pub fn new(kv: RedisPool) -> Self {
+        Self {
+            prefix: USAGE_PREFIX.to_owned(),
+            kv,
+        }
+    }
+    /// Adds the usage to the hour, day and month buckets of the key
+    pub async fn record(
+        &self,
+        app: &str,
+        key: &str,
+        records: &[UsageRecord],
+    ) -> anyhow::Result<()> {
+        let now = Utc::now();
+        let mut pipe = redis::pipe();
+        for window in UsageWindow::ALL.iter() {
+            let realkey = usage_key(&self.prefix, app, key, *window, &window.bucket(now));
+            for r in records {
+                let c = &r.counters;
+                for (counter, value) in [
+                    ("calls", c.calls),
+                    ("errors", c.errors),
+                    ("bytes_in", c.bytes_in),
+                    ("bytes_out", c.bytes_out),
+                ] {
+                    if value > 0 {
+                        let field = format!("{}:{}", r.method, counter);
+                        pipe.cmd("HINCRBY")
+                            .arg(&realkey)
+                            .arg(field)
+                            .arg(value)
+                            .ignore();
+                    }
+                }
+            }
+            if let Some(ttl) = window.ttl() {
+                pipe.cmd("EXPIRE").arg(&realkey).arg(ttl).ignore();
+            }
+        }
+        let mut con = self.kv.pool.get().await?;
+        pipe.query_async::<_, ()>(&mut *con).await?;
+        Ok(())
+    }
+}
+
+/// Results of JSON-RPC calls shared by the gateway instances,
+/// stored under the digest of the method and its params
+#[derive(Clone)]
+pub struct CacheStorage {
+    prefix: String,
+    kv: RedisPool,
+}
+
+impl CacheStorage {
+    pub fn new(kv: RedisPool) -> Self {
+        Self {
+            prefix: CACHE_PREFIX.to_owned(),
+            kv,
+        }
+    }
+    fn realkey(&self, app: &str, key: &str) -> String {
+        let digest: String = Sha256::digest(key.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        format!("{}a{}_{}", self.prefix, app, digest)
+    }
+    pub async fn get(&self, app: &str, key: &str) -> anyhow::Result<Option<Value>> {
+        self.kv.get(&self.realkey(app, key)).await
+    }
+    /// Stores the result, without expiration when `ttl` is not set
+    pub async fn set(
+        &self,
+        app: &str,
+        key: &str,
+        value: &Value,
+        ttl: Option<u64>,
+    ) -> anyhow::Result<()> {
+        let mut cmd = redis::cmd("SET");
+        cmd.arg(self.realkey(app, key)).arg(value.to_string());
+        if let Some(ttl) = ttl {
+            cmd.arg("EX").arg(ttl);
+        }
+        let mut con = self.kv.pool.get().await?;
+        cmd.query_async::<_, ()>(&mut *con).await?;
+        Ok(())
+    }
+    /// Removes every result of the application, keys of other apps
+    /// sharing the prefix such as `{app}_x` are kept
+    pub async fn clear(&self, app: &str) -> anyhow::Result<usize> {
+        let prefix = format!("{}a{}_", self.prefix, app);
+        let mut con = self.kv.pool.get().await?;
+        let mut keys = vec![];
+        {
+            let mut iter = redis::cmd("SCAN")
+                .cursor_arg(0)
+                .arg("MATCH")
+                .arg(format!("{}*", prefix))
+                .arg("COUNT")
+                .arg(1000)
+                .clone()
+                .iter_async::<String>(&mut *con)
+                .await?;
+            while let Some(key) = iter.next_item().await {
+                let digest = key.strip_prefix(&prefix).unwrap_or_default();
+                if digest.len() == 64 && digest.bytes().all(|b| b.is_ascii_hexdigit()) {
+                    keys.push(key);
+                }
+            }
+        }
+        for chunk in keys.chunks(1000) {
+            redis::cmd("DEL")
+                .arg(chunk)
+                .query_async::<_, ()>(&mut *con)
+                .await?;
+        }
+        Ok(keys.len())
+    }
+}
+
+/// Upstream nodes of the filters, shared by the gateway replicas.
+/// Filters expire when they are not used, as the nodes drop them too
+#[derive(Clone)]
+pub struct FilterStorage {
+    prefix: String,
+    kv: RedisPool,
+}
+
+impl FilterStorage {
+    pub fn new(kv: RedisPool) -> Self {
+        Self {
+            prefix: FILTER_PREFIX.to_owned(),
+            kv,
+        }
+    }
+    // filter ids are hex quantities, case is not significant
+    fn realkey(&self, app: &str, id: &str) -> String {
+        format!("{}a{}_{}", self.prefix, app, id.to_lowercase())
+    }
+    /// Node of the filter, the filter is kept for another `idle` seconds
+    pub async fn get(&self, app: &str, id: &str, idle: u64) -> anyhow::Result<Option<String>> {
+        let key = self.realkey(app, id);
+        let mut con = self.kv.pool.get().await?;
+        let (upstream,): (Option<String>,) = redis::pipe()
+            .cmd("GET")
+            .arg(&key)
+            .cmd("EXPIRE")
+            .arg(&key)
+            .arg(idle)
+            .ignore()
+            .query_async(&mut *con)
+            .await?;
+        Ok(upstream)
+    }
+    pub async fn set(&self, app: &str, id: &str, upstream: &str, idle: u64) -> anyhow::Result<()> {
+        let mut con = self.kv.pool.get().await?;
+        redis::cmd("SET")
+            .arg(self.realkey(app, id))
+            .arg(upstream)
+            .arg("EX")
+            .arg(idle)
+            .query_async::<_, ()>(&mut *con)
+            .await?;
+        Ok(())
+    }
+    pub async fn remove(&self, app: &str, id: &str) -> anyhow::Result<()> {
+        let mut con = self.kv.pool.get().await?;
+        redis::cmd("DEL")
+            .arg(self.realkey(app, id))
+            .query_async::<_, ()>(&mut *con)
+            .await?;
+        Ok(())
     }
 }
@@ -24,6 +24,14 @@ app_publish() {
     docker save jsonrpc-gw | bzip2 | ssh -o StrictHostKeyChecking=no -o UserKnownHostsFile=/dev/null $SSH_HOST 'bunzip2 | docker load'
 }
 
+# one gateway serves every active application, routed by Host header or path prefix,
+# so every host gets the same image
+app_deploy() {
+    for SSH_HOST in "$@"; do
+        app_publish $SSH_HOST
+    done
+}
+
 app_install() {
     cp ./target/release/jsonrpc-app ~/.cargo/bin/jsonrpc-app
     cp ./target/release/jsonrpc-key ~/.cargo/bin/jsonrpc-key
@@ -34,5 +42,4 @@ app_install() {
 [[ "$1" == "build" ]] && { shift; app_build; }
 [[ "$1" == "install" ]] && { shift; app_install; }
 [[ "$1" == "publish" ]] && { shift; app_publish $@; }
-[[ "$1" == "mainnet" ]] && { shift; app_build && app_publish root@mainnet.enormous.cloud; }
-[[ "$1" == "xdai" ]] && { shift; app_build && app_publish root@xdai.enormous.cloud; }
+[[ "$1" == "deploy" ]] && { shift; app_build && app_deploy $@; }